@@ -1,5 +1,10 @@
 use std::fs;
-use std::collections::HashMap;
+use std::fmt;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 // ============= ENUMS =============
 
@@ -17,6 +22,7 @@ enum ConfigType {
         host: String,
         port: u16,
         username: String,
+        password: Option<Secret>,
         max_connections: u32,
     },
     Server {
@@ -30,6 +36,119 @@ enum ConfigType {
         debug_mode: bool,
         log_level: LogLevel,
     },
+    /// A type defined only by a schema file, kept as validated text.
+    Custom {
+        kind: String,
+        fields: BTreeMap<String, String>,
+        secrets: BTreeMap<String, Secret>,
+    },
+}
+
+impl ConfigType {
+    /// Every field as text, starting with `type`, in the order files list
+    /// them. Secrets are redacted.
+    fn to_fields(&self) -> Vec<(String, String)> {
+        self.fields_with(|secret| secret.to_string())
+    }
+    
+    /// Like `to_fields`, but with secrets as written in the file: their
+    /// `${...}` reference, or the literal value when there is none.
+    fn to_stored_fields(&self) -> Vec<(String, String)> {
+        self.fields_with(|secret| secret.stored_form().to_string())
+    }
+    
+    fn fields_with(&self, show_secret: impl Fn(&Secret) -> String) -> Vec<(String, String)> {
+        let fields = match self {
+            ConfigType::Database { host, port, username, password, max_connections } => {
+                let mut fields = vec![
+                    ("type", String::from("database")),
+                    ("host", host.clone()),
+                    ("port", port.to_string()),
+                    ("username", username.clone()),
+                ];
+                if let Some(password) = password {
+                    fields.push(("password", show_secret(password)));
+                }
+                fields.push(("max_connections", max_connections.to_string()));
+                fields
+            }
+            
+            ConfigType::Server { bind_address, port, ssl_enabled } => vec![
+                ("type", String::from("server")),
+                ("bind_address", bind_address.clone()),
+                ("port", port.to_string()),
+                ("ssl_enabled", ssl_enabled.to_string()),
+            ],
+            
+            ConfigType::Application { name, version, debug_mode, log_level } => {
+                let level_str = match log_level {
+                    LogLevel::Debug => "debug",
+                    LogLevel::Info => "info",
+                    LogLevel::Warning => "warning",
+                    LogLevel::Error => "error",
+                };
+                vec![
+                    ("type", String::from("application")),
+                    ("name", name.clone()),
+                    ("version", version.clone()),
+                    ("debug_mode", debug_mode.to_string()),
+                    ("log_level", level_str.to_string()),
+                ]
+            }
+            
+            ConfigType::Custom { kind, fields, secrets } => {
+                let mut all = fields.clone();
+                all.extend(secrets.iter().map(|(key, secret)| (key.clone(), show_secret(secret))));
+                return std::iter::once((String::from("type"), kind.clone()))
+                    .chain(all)
+                    .collect();
+            }
+        };
+        fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect()
+    }
+    
+    /// The `type` value this config was read from.
+    fn kind(&self) -> &str {
+        match self {
+            ConfigType::Database { .. } => "database",
+            ConfigType::Server { .. } => "server",
+            ConfigType::Application { .. } => "application",
+            ConfigType::Custom { kind, .. } => kind,
+        }
+    }
+    
+    /// Field-by-field changes from `self` to `other`, in field order.
+    /// Secrets are compared as stored but reported redacted.
+    fn diff(&self, other: &ConfigType) -> Vec<FieldChange> {
+        let old = self.to_stored_fields();
+        let new = other.to_stored_fields();
+        let (old_shown, new_shown) = (self.to_fields(), other.to_fields());
+        let shown = |fields: &[(String, String)], key: &str| {
+            fields.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone())
+        };
+        let mut changes = Vec::new();
+        
+        for (key, old_value) in &old {
+            let new_value = new.iter().find(|(k, _)| k == key).map(|(_, v)| v);
+            if new_value != Some(old_value) {
+                changes.push(FieldChange {
+                    field: key.clone(),
+                    old: shown(&old_shown, key),
+                    new: shown(&new_shown, key),
+                });
+            }
+        }
+        for (key, _) in &new {
+            if !old.iter().any(|(k, _)| k == key) {
+                changes.push(FieldChange {
+                    field: key.clone(),
+                    old: None,
+                    new: shown(&new_shown, key),
+                });
+            }
+        }
+        changes
+    }
 }
 
 // ============= STRUCTS =============
@@ -45,6 +164,93 @@ struct ConfigMetadata {
     is_valid: bool,
 }
 
+/// Every section of a multi-section file, in file order.
+struct ConfigSet {
+    filename: String,
+    sections: Vec<ConfigSection>,
+}
+
+/// One field that differs between two configs; `None` means absent.
+#[derive(Debug, Clone, PartialEq)]
+struct FieldChange {
+    field: String,
+    old: Option<String>,
+    new: Option<String>,
+}
+
+type ChangeCallback = Box<dyn Fn(&[FieldChange], &Config) + Send>;
+
+/// Polls a config file and keeps a validated copy of it live.
+struct ConfigWatcher {
+    filename: String,
+    live: Arc<RwLock<Arc<Config>>>,
+    /// Modification time and size at the last poll, so a change is noticed
+    /// even when the clock granularity hides it in the mtime alone.
+    last_seen: Option<(SystemTime, u64)>,
+    callbacks: Vec<ChangeCallback>,
+}
+
+#[derive(Debug, PartialEq)]
+enum ReloadOutcome {
+    Unchanged,
+    Reloaded(Vec<FieldChange>),
+}
+
+/// A watcher polling on its own thread; dropping it stops the thread.
+struct WatcherHandle {
+    live: Arc<RwLock<Arc<Config>>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+/// Where a layered value came from, lowest precedence first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum ConfigLayer {
+    Default,
+    File,
+    Environment,
+    CommandLine,
+}
+
+#[derive(Debug, Clone)]
+struct Provenance {
+    layer: ConfigLayer,
+    /// The file, variable or flag that supplied the value.
+    source: String,
+}
+
+/// A config merged from every layer, with where each field came from.
+struct LayeredConfig {
+    config: Config,
+    provenance: BTreeMap<String, (String, Provenance)>,
+}
+
+/// A section's name and its raw `key -> value` fields, before type conversion.
+type RawSection = (String, HashMap<String, String>);
+
+/// A credential resolved at load time. It prints as `[redacted]` in every
+/// `Display` and `Debug` form; only `expose` returns the value.
+#[derive(Clone)]
+struct Secret {
+    value: String,
+    /// The `${env:..}` or `${file:..}` text it was resolved from, if any.
+    reference: Option<String>,
+}
+
+/// What `Config::migrate_file` did; `backup` is `None` when nothing changed.
+struct MigrationReport {
+    from: u32,
+    to: u32,
+    applied: Vec<&'static str>,
+    backup: Option<String>,
+}
+
+/// One `[kind]` or `[kind.name]` section; the kind picks the `ConfigType`.
+struct ConfigSection {
+    name: String,
+    config_type: ConfigType,
+}
+
 // ============= ERROR TYPES =============
 
 #[derive(Debug)]
@@ -56,6 +262,8 @@ enum ConfigError {
     ValidationFailed(Vec<String>),
     SaveFailed { filename: String, reason: String },
     ParseError(String),
+    MigrationFailed { version: u32, reason: String },
+    SecretUnavailable { reference: String, reason: String },
 }
 
 impl std::fmt::Display for ConfigError {
@@ -76,9 +284,9 @@ impl std::fmt::Display for ConfigError {
                        value, field, expected)
             }
             ConfigError::ValidationFailed(errors) => {
-                write!(f, "Validation failed with {} errors:\n", errors.len())?;
+                writeln!(f, "Validation failed with {} errors:", errors.len())?;
                 for error in errors {
-                    write!(f, "  - {}\n", error)?;
+                    writeln!(f, "  - {}", error)?;
                 }
                 Ok(())
             }
@@ -88,371 +296,2224 @@ impl std::fmt::Display for ConfigError {
             ConfigError::ParseError(msg) => {
                 write!(f, "Parse error: {}", msg)
             }
+            ConfigError::MigrationFailed { version, reason } => {
+                write!(f, "Migration to schema version {} failed: {}", version, reason)
+            }
+            ConfigError::SecretUnavailable { reference, reason } => {
+                write!(f, "Cannot resolve secret {}: {}", reference, reason)
+            }
         }
     }
 }
 
-// =============  CONFIG IMPLEMENTATION =============
+// ============= JSON PARSER =============
 
-impl Config {
-    fn load_from_file(filename: &str) -> Result<Config, ConfigError> {
-        let contents = fs::read_to_string(filename)
-            .map_err(|_| ConfigError::FileNotFound(filename.to_string()))?;
+#[derive(Debug, Clone, PartialEq)]
+enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl fmt::Display for JsonValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JsonValue::Null => write!(f, "null"),
+            JsonValue::Bool(b) => write!(f, "{}", b),
+            // Whole numbers print without a fraction so "5432" stays a valid port.
+            JsonValue::Number(n) if n.fract() == 0.0 && n.abs() < 9e15 => write!(f, "{}", *n as i64),
+            JsonValue::Number(n) => write!(f, "{}", n),
+            JsonValue::String(s) => write!(f, "{}", s),
+            JsonValue::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            JsonValue::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", key, value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+impl JsonValue {
+    /// Flattens an object into the `key -> text` map the `parse_*_config`
+    /// functions expect. Nested objects become dotted keys and `null`
+    /// members are left out, as if they were missing.
+    fn flatten_into(&self, prefix: &str, map: &mut HashMap<String, String>) {
+        match self {
+            JsonValue::Object(members) => {
+                for (key, value) in members {
+                    let path = if prefix.is_empty() {
+                        key.clone()
+                    } else {
+                        format!("{}.{}", prefix, key)
+                    };
+                    value.flatten_into(&path, map);
+                }
+            }
+            JsonValue::Null => {}
+            other => {
+                map.insert(prefix.to_string(), other.to_string());
+            }
+        }
+    }
+    
+    /// Like `flatten_into`, but keeps member order and value types.
+    fn flatten_ordered(&self, prefix: &str, out: &mut Vec<(String, JsonValue)>) {
+        match self {
+            JsonValue::Object(members) => {
+                for (key, value) in members {
+                    let path = if prefix.is_empty() {
+                        key.clone()
+                    } else {
+                        format!("{}.{}", prefix, key)
+                    };
+                    value.flatten_ordered(&path, out);
+                }
+            }
+            JsonValue::Null => {}
+            other => out.push((prefix.to_string(), other.clone())),
+        }
+    }
+    
+    /// Inverse of flattening: sets the dotted `path` inside this object,
+    /// creating nested objects on the way.
+    fn insert_path(&mut self, path: &str, value: JsonValue) {
+        let JsonValue::Object(members) = self else {
+            return;
+        };
+        let (key, rest) = match path.split_once('.') {
+            Some((key, rest)) => (key, Some(rest)),
+            None => (path, None),
+        };
+        let Some(rest) = rest else {
+            members.push((key.to_string(), value));
+            return;
+        };
+        if !members.iter().any(|(name, member)| name == key && matches!(member, JsonValue::Object(_))) {
+            members.push((key.to_string(), JsonValue::Object(Vec::new())));
+        }
+        if let Some((_, nested)) = members.iter_mut().rev().find(|(name, member)| name == key && matches!(member, JsonValue::Object(_))) {
+            nested.insert_path(rest, value);
+        }
+    }
+    
+    /// Serializes as JSON text, one object member per line.
+    fn write_json(&self, out: &mut String, indent: usize) {
+        match self {
+            JsonValue::String(s) => {
+                out.push('"');
+                for ch in s.chars() {
+                    match ch {
+                        '"' => out.push_str("\\\""),
+                        '\\' => out.push_str("\\\\"),
+                        '\n' => out.push_str("\\n"),
+                        '\r' => out.push_str("\\r"),
+                        '\t' => out.push_str("\\t"),
+                        c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+                        c => out.push(c),
+                    }
+                }
+                out.push('"');
+            }
+            JsonValue::Array(items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    item.write_json(out, indent);
+                }
+                out.push(']');
+            }
+            JsonValue::Object(members) if members.is_empty() => out.push_str("{}"),
+            JsonValue::Object(members) => {
+                out.push_str("{\n");
+                for (i, (key, value)) in members.iter().enumerate() {
+                    out.push_str(&"  ".repeat(indent + 1));
+                    JsonValue::String(key.clone()).write_json(out, indent + 1);
+                    out.push_str(": ");
+                    value.write_json(out, indent + 1);
+                    out.push_str(if i + 1 < members.len() { ",\n" } else { "\n" });
+                }
+                out.push_str(&"  ".repeat(indent));
+                out.push('}');
+            }
+            scalar => out.push_str(&scalar.to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct JsonError {
+    line: usize,
+    column: usize,
+    message: String,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
+
+struct JsonParser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn parse(input: &'a str) -> Result<JsonValue, JsonError> {
+        let mut parser = JsonParser {
+            chars: input.chars().peekable(),
+            line: 1,
+            column: 1,
+        };
+
+        let value = parser.parse_value()?;
+        parser.skip_whitespace();
+        match parser.chars.peek() {
+            None => Ok(value),
+            Some(&ch) => Err(parser.error(format!("unexpected '{}' after the end of the document", ch))),
+        }
+    }
+
+    fn error(&self, message: impl Into<String>) -> JsonError {
+        JsonError {
+            line: self.line,
+            column: self.column,
+            message: message.into(),
+        }
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let ch = self.chars.next()?;
+        if ch == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(ch)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(' ' | '\t' | '\n' | '\r') = self.chars.peek() {
+            self.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char, context: &str) -> Result<(), JsonError> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some(&ch) if ch == expected => {
+                self.next();
+                Ok(())
+            }
+            Some(&ch) => Err(self.error(format!("expected '{}' {}, found '{}'", expected, context, ch))),
+            None => Err(self.error(format!("expected '{}' {}, found end of input", expected, context))),
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<JsonValue, JsonError> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some('{') => self.parse_object(),
+            Some('[') => self.parse_array(),
+            Some('"') => self.parse_string().map(JsonValue::String),
+            Some('-' | '0'..='9') => self.parse_number(),
+            Some('t') => self.parse_literal("true", JsonValue::Bool(true)),
+            Some('f') => self.parse_literal("false", JsonValue::Bool(false)),
+            Some('n') => self.parse_literal("null", JsonValue::Null),
+            Some(&ch) => Err(self.error(format!("unexpected character '{}'", ch))),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<JsonValue, JsonError> {
+        self.next();
+        let mut members: Vec<(String, JsonValue)> = Vec::new();
+
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&'}') {
+            self.next();
+            return Ok(JsonValue::Object(members));
+        }
+
+        loop {
+            self.skip_whitespace();
+            if self.chars.peek() != Some(&'"') {
+                return Err(self.error("expected a string key"));
+            }
+            let key = self.parse_string()?;
+            self.expect(':', "after an object key")?;
+            let value = self.parse_value()?;
+
+            // A repeated key overrides the earlier one.
+            members.retain(|(existing, _)| *existing != key);
+            members.push((key, value));
+
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(JsonValue::Object(members)),
+                Some(ch) => return Err(self.error(format!("expected ',' or '}}' after an object member, found '{}'", ch))),
+                None => return Err(self.error("unterminated object")),
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<JsonValue, JsonError> {
+        self.next();
+        let mut items = Vec::new();
+
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&']') {
+            self.next();
+            return Ok(JsonValue::Array(items));
+        }
+
+        loop {
+            items.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(JsonValue::Array(items)),
+                Some(ch) => return Err(self.error(format!("expected ',' or ']' after an array element, found '{}'", ch))),
+                None => return Err(self.error("unterminated array")),
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, JsonError> {
+        self.next();
+        let mut value = String::new();
+
+        loop {
+            match self.next() {
+                Some('"') => return Ok(value),
+                Some('\\') => {
+                    let escaped = match self.next() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => self.parse_unicode_escape()?,
+                        Some(ch) => return Err(self.error(format!("invalid escape sequence '\\{}'", ch))),
+                        None => return Err(self.error("unterminated string")),
+                    };
+                    value.push(escaped);
+                }
+                Some(ch) if (ch as u32) < 0x20 => {
+                    return Err(self.error("control characters must be escaped in strings"));
+                }
+                Some(ch) => value.push(ch),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, JsonError> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self.next()
+                .and_then(|ch| ch.to_digit(16))
+                .ok_or_else(|| self.error("expected four hex digits after '\\u'"))?;
+            code = code * 16 + digit;
+        }
+        Ok(code)
+    }
+
+    fn parse_unicode_escape(&mut self) -> Result<char, JsonError> {
+        let high = self.parse_hex4()?;
+        if !(0xD800..0xDC00).contains(&high) {
+            return char::from_u32(high).ok_or_else(|| self.error("invalid unicode escape"));
+        }
+
+        // Characters outside the BMP are written as a surrogate pair.
+        if self.next() != Some('\\') || self.next() != Some('u') {
+            return Err(self.error("unpaired surrogate in unicode escape"));
+        }
+        let low = self.parse_hex4()?;
+        if !(0xDC00..0xE000).contains(&low) {
+            return Err(self.error("unpaired surrogate in unicode escape"));
+        }
+        char::from_u32(0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00))
+            .ok_or_else(|| self.error("invalid unicode escape"))
+    }
+
+    fn parse_number(&mut self) -> Result<JsonValue, JsonError> {
+        let (line, column) = (self.line, self.column);
+        let mut text = String::new();
+        while let Some(&ch) = self.chars.peek() {
+            if ch.is_ascii_digit() || matches!(ch, '-' | '+' | '.' | 'e' | 'E') {
+                text.push(ch);
+                self.next();
+            } else {
+                break;
+            }
+        }
+
+        let digits = text.strip_prefix('-').unwrap_or(&text);
+        let leading_zero = digits.len() > 1 && digits.starts_with('0') && digits.as_bytes()[1].is_ascii_digit();
+        let starts_with_digit = digits.starts_with(|ch: char| ch.is_ascii_digit());
+        match text.parse::<f64>() {
+            Ok(n) if starts_with_digit && !leading_zero && !text.ends_with('.') && n.is_finite() => {
+                Ok(JsonValue::Number(n))
+            }
+            _ => Err(JsonError {
+                line,
+                column,
+                message: format!("invalid number '{}'", text),
+            }),
+        }
+    }
+
+    fn parse_literal(&mut self, word: &str, value: JsonValue) -> Result<JsonValue, JsonError> {
+        let (line, column) = (self.line, self.column);
+        for expected in word.chars() {
+            if self.next() != Some(expected) {
+                return Err(JsonError {
+                    line,
+                    column,
+                    message: format!("invalid literal, expected '{}'", word),
+                });
+            }
+        }
+        Ok(value)
+    }
+}
+
+// ============= PATTERNS =============
+
+/// A small backtracking regular expression, enough for schema `pattern`
+/// rules: literals, `.`, classes, `\d \w \s`, groups with `|`, anchors and
+/// the `* + ? {n,m}` quantifiers. Matches anywhere unless anchored.
+///
+/// The pattern is compiled to a list of instructions, and matching never
+/// revisits an (instruction, position) state that already failed, so the
+/// cost is bounded by program size times text length even for nested
+/// quantifiers like `(a*)*`.
+#[derive(Debug, Clone)]
+struct Pattern {
+    source: String,
+    program: Vec<PatternInst>,
+}
+
+#[derive(Debug, Clone)]
+enum PatternNode {
+    Char(char),
+    Any,
+    Class { ranges: Vec<(char, char)>, negated: bool },
+    Start,
+    End,
+    Group(Vec<Vec<PatternNode>>),
+    Repeat { node: Box<PatternNode>, min: usize, max: Option<usize> },
+}
+
+#[derive(Debug, Clone)]
+enum PatternInst {
+    /// A single character test or an anchor.
+    Test(PatternNode),
+    /// Try the first target, then the second.
+    Split(usize, usize),
+    Jump(usize),
+    Match,
+}
+
+/// Keeps `{n,m}` expansion from turning a short pattern into a huge program.
+const MAX_PATTERN_PROGRAM: usize = 10_000;
+
+impl Pattern {
+    fn new(source: &str) -> Result<Pattern, String> {
+        let chars: Vec<char> = source.chars().collect();
+        let mut pos = 0;
+        let alternatives = Self::parse_alternatives(&chars, &mut pos)?;
+        if pos < chars.len() {
+            return Err(format!("unmatched ')' at position {}", pos));
+        }
         
-        let config_type = Self::parse_json(&contents)?;
-        Self::validate_config(&config_type)?;
+        let mut program = Vec::new();
+        Self::compile_alternatives(&alternatives, &mut program)?;
+        program.push(PatternInst::Match);
         
-        Ok(Config {
-            config_type,
-            metadata: ConfigMetadata {
-                filename: filename.to_string(),
-                last_modified: None,
-                is_valid: true,
-            },
+        Ok(Pattern {
+            source: source.to_string(),
+            program,
         })
     }
     
-    fn parse_json(json: &str) -> Result<ConfigType, ConfigError> {
-        let mut map: HashMap<String, String> = HashMap::new();
+    fn is_match(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        let width = text.len() + 1;
+        // A state that failed once fails again from any start position.
+        let mut visited = vec![false; self.program.len() * width];
+        let mut pending: Vec<(usize, usize)> = (0..=text.len()).rev().map(|start| (0, start)).collect();
         
-        for line in json.lines() {
-            let line = line.trim();
-            if line.is_empty() || line.starts_with('#') {
+        while let Some((pc, pos)) = pending.pop() {
+            if std::mem::replace(&mut visited[pc * width + pos], true) {
                 continue;
             }
-            
-            let parts: Vec<&str> = line.splitn(2, ':').collect();
-            if parts.len() != 2 {
-                return Err(ConfigError::ParseError(
-                    format!("Invalid line format: {}", line)
-                ));
+            match &self.program[pc] {
+                PatternInst::Match => return true,
+                PatternInst::Jump(target) => pending.push((*target, pos)),
+                PatternInst::Split(first, second) => {
+                    pending.push((*second, pos));
+                    pending.push((*first, pos));
+                }
+                PatternInst::Test(PatternNode::Start) => {
+                    if pos == 0 {
+                        pending.push((pc + 1, pos));
+                    }
+                }
+                PatternInst::Test(PatternNode::End) => {
+                    if pos == text.len() {
+                        pending.push((pc + 1, pos));
+                    }
+                }
+                PatternInst::Test(node) => {
+                    if pos < text.len() && Self::matches_char(node, text[pos]) {
+                        pending.push((pc + 1, pos + 1));
+                    }
+                }
             }
-            
-            map.insert(
-                parts[0].trim().to_string(),
-                parts[1].trim().to_string(),
-            );
         }
+        false
+    }
+    
+    fn parse_alternatives(chars: &[char], pos: &mut usize) -> Result<Vec<Vec<PatternNode>>, String> {
+        let mut alternatives = vec![Vec::new()];
         
-        let config_type = map.get("type")
-            .ok_or_else(|| ConfigError::MissingField {
-                config_type: String::from("unknown"),
-                field: String::from("type"),
-            })?;
-        
-        match config_type.as_str() {
-            "database" => Self::parse_database_config(&map),
-            "server" => Self::parse_server_config(&map),
-            "application" => Self::parse_application_config(&map),
-            other => Err(ConfigError::InvalidValue {
-                field: String::from("type"),
-                value: other.to_string(),
-                expected: String::from("database, server, or application"),
-            }),
+        while let Some(&ch) = chars.get(*pos) {
+            match ch {
+                ')' => break,
+                '|' => {
+                    *pos += 1;
+                    alternatives.push(Vec::new());
+                }
+                _ => {
+                    let atom = Self::parse_atom(chars, pos)?;
+                    let node = Self::parse_quantifier(chars, pos, atom)?;
+                    alternatives.last_mut().expect("at least one alternative").push(node);
+                }
+            }
         }
+        Ok(alternatives)
     }
     
-    fn parse_database_config(map: &HashMap<String, String>) -> Result<ConfigType, ConfigError> {
-        let host = map.get("host")
-            .ok_or_else(|| ConfigError::MissingField {
-                config_type: String::from("database"),
-                field: String::from("host"),
-            })?
-            .clone();
-        
-        let port_str = map.get("port")
-            .ok_or_else(|| ConfigError::MissingField {
-                config_type: String::from("database"),
-                field: String::from("port"),
-            })?;
+    fn parse_atom(chars: &[char], pos: &mut usize) -> Result<PatternNode, String> {
+        let ch = chars[*pos];
+        *pos += 1;
         
-        let port = port_str.parse::<u16>()
-            .map_err(|_| ConfigError::InvalidValue {
-                field: String::from("port"),
+        Ok(match ch {
+            '.' => PatternNode::Any,
+            '^' => PatternNode::Start,
+            '$' => PatternNode::End,
+            '(' => {
+                let alternatives = Self::parse_alternatives(chars, pos)?;
+                if chars.get(*pos) != Some(&')') {
+                    return Err(String::from("unclosed group"));
+                }
+                *pos += 1;
+                PatternNode::Group(alternatives)
+            }
+            '[' => Self::parse_class(chars, pos)?,
+            '\\' => Self::parse_escape(chars, pos)?,
+            '*' | '+' | '?' | '{' => return Err(format!("nothing to repeat before '{}'", ch)),
+            other => PatternNode::Char(other),
+        })
+    }
+    
+    fn parse_escape(chars: &[char], pos: &mut usize) -> Result<PatternNode, String> {
+        let ch = *chars.get(*pos).ok_or("trailing backslash")?;
+        *pos += 1;
+        
+        let class = |ranges: &[(char, char)]| PatternNode::Class { ranges: ranges.to_vec(), negated: false };
+        Ok(match ch {
+            'd' => class(&[('0', '9')]),
+            'w' => class(&[('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')]),
+            's' => class(&[(' ', ' '), ('\t', '\t'), ('\n', '\n'), ('\r', '\r')]),
+            other => PatternNode::Char(other),
+        })
+    }
+    
+    fn parse_class(chars: &[char], pos: &mut usize) -> Result<PatternNode, String> {
+        let negated = chars.get(*pos) == Some(&'^');
+        if negated {
+            *pos += 1;
+        }
+        
+        let mut ranges = Vec::new();
+        loop {
+            let ch = *chars.get(*pos).ok_or("unclosed character class")?;
+            *pos += 1;
+            match ch {
+                ']' if !ranges.is_empty() => break,
+                '\\' => match Self::parse_escape(chars, pos)? {
+                    PatternNode::Class { ranges: escaped, .. } => ranges.extend(escaped),
+                    PatternNode::Char(c) => ranges.push((c, c)),
+                    _ => unreachable!(),
+                },
+                start if chars.get(*pos) == Some(&'-') && chars.get(*pos + 1).is_some_and(|&c| c != ']') => {
+                    let end = chars[*pos + 1];
+                    *pos += 2;
+                    if end < start {
+                        return Err(format!("invalid range {}-{}", start, end));
+                    }
+                    ranges.push((start, end));
+                }
+                other => ranges.push((other, other)),
+            }
+        }
+        Ok(PatternNode::Class { ranges, negated })
+    }
+    
+    fn parse_quantifier(chars: &[char], pos: &mut usize, node: PatternNode) -> Result<PatternNode, String> {
+        let (min, max) = match chars.get(*pos) {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                let close = chars[*pos..].iter().position(|&c| c == '}').ok_or("unclosed '{'")? + *pos;
+                let inner: String = chars[*pos + 1..close].iter().collect();
+                let number = |text: &str| text.trim().parse::<usize>().map_err(|_| format!("invalid repetition {{{}}}", inner));
+                let bounds = match inner.split_once(',') {
+                    None => {
+                        let n = number(&inner)?;
+                        (n, Some(n))
+                    }
+                    Some((min, max)) if max.trim().is_empty() => (number(min)?, None),
+                    Some((min, max)) => (number(min)?, Some(number(max)?)),
+                };
+                *pos = close;
+                bounds
+            }
+            _ => return Ok(node),
+        };
+        *pos += 1;
+        
+        Ok(PatternNode::Repeat { node: Box::new(node), min, max })
+    }
+    
+    fn compile_alternatives(alternatives: &[Vec<PatternNode>], program: &mut Vec<PatternInst>) -> Result<(), String> {
+        let mut exits = Vec::new();
+        for (i, alternative) in alternatives.iter().enumerate() {
+            let split = program.len();
+            let last = i + 1 == alternatives.len();
+            if !last {
+                program.push(PatternInst::Split(split + 1, 0));
+            }
+            for node in alternative {
+                Self::compile_node(node, program)?;
+            }
+            if !last {
+                exits.push(program.len());
+                program.push(PatternInst::Jump(0));
+                program[split] = PatternInst::Split(split + 1, program.len());
+            }
+        }
+        for exit in exits {
+            program[exit] = PatternInst::Jump(program.len());
+        }
+        Ok(())
+    }
+    
+    fn compile_node(node: &PatternNode, program: &mut Vec<PatternInst>) -> Result<(), String> {
+        if program.len() > MAX_PATTERN_PROGRAM {
+            return Err(String::from("pattern is too large"));
+        }
+        
+        match node {
+            PatternNode::Group(alternatives) => Self::compile_alternatives(alternatives, program)?,
+            PatternNode::Repeat { node, min, max } => {
+                for _ in 0..*min {
+                    Self::compile_node(node, program)?;
+                }
+                match max {
+                    // Greedy: each split prefers another repetition over stopping.
+                    None => {
+                        let split = program.len();
+                        program.push(PatternInst::Split(split + 1, 0));
+                        Self::compile_node(node, program)?;
+                        program.push(PatternInst::Jump(split));
+                        program[split] = PatternInst::Split(split + 1, program.len());
+                    }
+                    Some(max) => {
+                        let mut splits = Vec::new();
+                        for _ in *min..*max {
+                            splits.push(program.len());
+                            program.push(PatternInst::Split(0, 0));
+                            Self::compile_node(node, program)?;
+                        }
+                        for split in splits {
+                            program[split] = PatternInst::Split(split + 1, program.len());
+                        }
+                    }
+                }
+            }
+            single => program.push(PatternInst::Test(single.clone())),
+        }
+        Ok(())
+    }
+    
+    fn matches_char(node: &PatternNode, ch: char) -> bool {
+        match node {
+            PatternNode::Char(expected) => *expected == ch,
+            PatternNode::Any => ch != '\n',
+            PatternNode::Class { ranges, negated } => {
+                ranges.iter().any(|&(start, end)| start <= ch && ch <= end) != *negated
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "/{}/", self.source)
+    }
+}
+
+// ============= SCHEMA VALIDATION =============
+
+/// Rules for the built-in config types. A schema file uses the same format
+/// and can add new types or replace these.
+const BUILTIN_SCHEMA: &str = r#"{
+    "database": {
+        "fields": {
+            "host": {"type": "string", "required": true, "min_length": 1, "default": "localhost"},
+            "port": {"type": "integer", "required": true, "min": 1, "max": 65535, "default": 5432},
+            "username": {"type": "string", "required": true, "min_length": 1},
+            "password": {"type": "secret", "min_length": 1},
+            "max_connections": {"type": "integer", "required": true, "min": 1, "max": 10000, "default": 100}
+        }
+    },
+    "server": {
+        "fields": {
+            "bind_address": {"type": "string", "required": true, "pattern": "^(localhost|.*\\..*)$", "default": "0.0.0.0"},
+            "port": {"type": "integer", "required": true, "min": 1, "max": 65535, "default": 8080},
+            "ssl_enabled": {"type": "bool", "required": true, "default": false}
+        }
+    },
+    "application": {
+        "fields": {
+            "name": {"type": "string", "required": true, "min_length": 1},
+            "version": {"type": "string", "required": true, "pattern": "^[^.]+\\.[^.]+\\.[^.]+$", "default": "0.1.0"},
+            "debug_mode": {"type": "bool", "required": true, "default": false},
+            "log_level": {"type": "string", "required": true, "enum": ["debug", "info", "warning", "error"], "default": "info"}
+        }
+    }
+}"#;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FieldKind {
+    String,
+    Integer,
+    Number,
+    Bool,
+    /// A string redacted on output, possibly a `${env:..}` or `${file:..}` reference.
+    Secret,
+}
+
+#[derive(Debug, Clone)]
+struct FieldSchema {
+    name: String,
+    kind: FieldKind,
+    required: bool,
+    default: Option<String>,
+    min: Option<f64>,
+    max: Option<f64>,
+    min_length: Option<usize>,
+    pattern: Option<Pattern>,
+    allowed: Option<Vec<String>>,
+}
+
+/// A cross-field rule: when `when` has the value `equals`, every field in
+/// `requires` must be present and non-empty.
+#[derive(Debug, Clone)]
+struct FieldRule {
+    when: String,
+    equals: String,
+    requires: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+struct TypeSchema {
+    fields: Vec<FieldSchema>,
+    rules: Vec<FieldRule>,
+}
+
+#[derive(Debug, Clone)]
+struct Schema {
+    types: BTreeMap<String, TypeSchema>,
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "1" => Some(true),
+        "false" | "no" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+impl Schema {
+    /// The schema for the built-in config types, parsed once.
+    fn builtin() -> &'static Schema {
+        static BUILTIN: OnceLock<Schema> = OnceLock::new();
+        BUILTIN.get_or_init(|| {
+            Schema::from_json(BUILTIN_SCHEMA, "<builtin schema>").expect("built-in schema is valid")
+        })
+    }
+    
+    fn load_from_file(filename: &str) -> Result<Schema, ConfigError> {
+        let contents = fs::read_to_string(filename)
+            .map_err(|_| ConfigError::FileNotFound(filename.to_string()))?;
+        
+        Self::from_json(&contents, filename)
+    }
+    
+    fn from_json(json: &str, filename: &str) -> Result<Schema, ConfigError> {
+        let invalid = |reason: String| ConfigError::InvalidJson {
+            filename: filename.to_string(),
+            reason,
+        };
+        
+        let JsonValue::Object(type_members) = JsonParser::parse(json).map_err(|e| invalid(e.to_string()))? else {
+            return Err(invalid(String::from("a schema must be an object of config types")));
+        };
+        
+        let mut types = BTreeMap::new();
+        for (kind, definition) in type_members {
+            let type_schema = Self::parse_type(&kind, &definition).map_err(|reason| invalid(format!("{}: {}", kind, reason)))?;
+            types.insert(kind, type_schema);
+        }
+        Ok(Schema { types })
+    }
+    
+    fn parse_type(kind: &str, definition: &JsonValue) -> Result<TypeSchema, String> {
+        let JsonValue::Object(members) = definition else {
+            return Err(format!("the definition of '{}' must be an object", kind));
+        };
+        
+        let mut fields = Vec::new();
+        let mut rules = Vec::new();
+        for (key, value) in members {
+            match (key.as_str(), value) {
+                ("fields", JsonValue::Object(field_members)) => {
+                    for (name, field) in field_members {
+                        fields.push(Self::parse_field(name, field).map_err(|e| format!("{}: {}", name, e))?);
+                    }
+                }
+                ("rules", JsonValue::Array(items)) => {
+                    for item in items {
+                        rules.push(Self::parse_rule(item)?);
+                    }
+                }
+                _ => return Err(format!("unexpected '{}'", key)),
+            }
+        }
+        
+        for rule in &rules {
+            for name in std::iter::once(&rule.when).chain(&rule.requires) {
+                if !fields.iter().any(|field| field.name == *name) {
+                    return Err(format!("rule refers to unknown field '{}'", name));
+                }
+            }
+        }
+        Ok(TypeSchema { fields, rules })
+    }
+    
+    fn parse_field(name: &str, definition: &JsonValue) -> Result<FieldSchema, String> {
+        let JsonValue::Object(members) = definition else {
+            return Err(String::from("a field definition must be an object"));
+        };
+        
+        let mut field = FieldSchema {
+            name: name.to_string(),
+            kind: FieldKind::String,
+            required: false,
+            default: None,
+            min: None,
+            max: None,
+            min_length: None,
+            pattern: None,
+            allowed: None,
+        };
+        
+        for (key, value) in members {
+            match (key.as_str(), value) {
+                ("type", JsonValue::String(kind)) => {
+                    field.kind = match kind.as_str() {
+                        "string" => FieldKind::String,
+                        "integer" => FieldKind::Integer,
+                        "number" => FieldKind::Number,
+                        "bool" => FieldKind::Bool,
+                        "secret" => FieldKind::Secret,
+                        other => return Err(format!("unknown type '{}'", other)),
+                    };
+                }
+                ("required", JsonValue::Bool(required)) => field.required = *required,
+                ("default", JsonValue::Null) => field.default = None,
+                ("default", scalar) if !matches!(scalar, JsonValue::Array(_) | JsonValue::Object(_)) => {
+                    field.default = Some(scalar.to_string());
+                }
+                ("min", JsonValue::Number(n)) => field.min = Some(*n),
+                ("max", JsonValue::Number(n)) => field.max = Some(*n),
+                ("min_length", JsonValue::Number(n)) if *n >= 0.0 => field.min_length = Some(*n as usize),
+                ("pattern", JsonValue::String(source)) => {
+                    field.pattern = Some(Pattern::new(source).map_err(|e| format!("invalid pattern: {}", e))?);
+                }
+                ("enum", JsonValue::Array(values)) => {
+                    field.allowed = Some(values.iter().map(|value| value.to_string()).collect());
+                }
+                _ => return Err(format!("invalid '{}': {}", key, value)),
+            }
+        }
+        
+        // A default must itself pass the field's rules.
+        if let Some(default) = &field.default {
+            let mut violations = Vec::new();
+            Self::check_value(&field, default, "default", &mut violations);
+            if let Some(violation) = violations.into_iter().next() {
+                return Err(violation);
+            }
+        }
+        Ok(field)
+    }
+    
+    fn parse_rule(definition: &JsonValue) -> Result<FieldRule, String> {
+        let JsonValue::Object(members) = definition else {
+            return Err(String::from("a rule must be an object"));
+        };
+        
+        let member = |name: &str| members.iter().find(|(key, _)| key == name).map(|(_, value)| value);
+        match (member("when"), member("equals"), member("requires")) {
+            (Some(JsonValue::String(when)), Some(equals), Some(JsonValue::Array(requires))) => Ok(FieldRule {
+                when: when.clone(),
+                equals: equals.to_string(),
+                requires: requires.iter().map(|value| value.to_string()).collect(),
+            }),
+            _ => Err(String::from("a rule needs \"when\", \"equals\" and a \"requires\" list")),
+        }
+    }
+    
+    /// Adds the types of `other`, replacing any of the same name.
+    fn extend(&mut self, other: Schema) {
+        self.types.extend(other.types);
+    }
+    
+    fn type_schema(&self, kind: &str) -> Result<&TypeSchema, ConfigError> {
+        self.types.get(kind).ok_or_else(|| ConfigError::InvalidValue {
+            field: String::from("type"),
+            value: kind.to_string(),
+            expected: self.types.keys().cloned().collect::<Vec<_>>().join(", "),
+        })
+    }
+    
+    fn defaults(&self, kind: &str) -> Vec<(String, String)> {
+        self.types.get(kind)
+            .map(|schema| {
+                schema.fields.iter()
+                    .filter_map(|field| Some((field.name.clone(), field.default.clone()?)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+    
+    fn field_kind(&self, kind: &str, name: &str) -> Option<FieldKind> {
+        self.types.get(kind)?.fields.iter()
+            .find(|field| field.name == name)
+            .map(|field| field.kind)
+    }
+    
+    fn field_names(&self, kind: &str) -> Vec<String> {
+        self.types.get(kind)
+            .map(|schema| schema.fields.iter().map(|field| field.name.clone()).collect())
+            .unwrap_or_default()
+    }
+    
+    /// Checks raw fields against the schema for their `type`, filling in
+    /// defaults and keeping only the fields the schema describes. Every
+    /// violation is reported, prefixed with its field path under `path`
+    /// (such as `database.primary.port`).
+    fn validate(&self, fields: &HashMap<String, String>, path: &str) -> Result<HashMap<String, String>, ConfigError> {
+        let kind = fields.get("type").ok_or_else(|| ConfigError::MissingField {
+            config_type: String::from("unknown"),
+            field: String::from("type"),
+        })?;
+        let schema = self.type_schema(kind)?;
+        
+        let mut values = fields.clone();
+        let mut violations = Vec::new();
+        
+        for field in &schema.fields {
+            let field_path = format!("{}.{}", path, field.name);
+            if !values.contains_key(&field.name) {
+                match &field.default {
+                    Some(default) => {
+                        values.insert(field.name.clone(), default.clone());
+                    }
+                    None if field.required => violations.push(format!("{}: required field is missing", field_path)),
+                    None => {}
+                }
+                continue;
+            }
+            
+            Self::check_value(field, &values[&field.name], &field_path, &mut violations);
+            // Later rules compare against the canonical spelling of booleans.
+            if field.kind == FieldKind::Bool && let Some(b) = parse_bool(&values[&field.name]) {
+                values.insert(field.name.clone(), b.to_string());
+            }
+        }
+        
+        for rule in &schema.rules {
+            let triggered = values.get(&rule.when).is_some_and(|value| value.eq_ignore_ascii_case(&rule.equals));
+            if !triggered {
+                continue;
+            }
+            for required in &rule.requires {
+                if values.get(required).is_none_or(|value| value.trim().is_empty()) {
+                    violations.push(format!(
+                        "{}.{}: required when {} is {}",
+                        path, required, rule.when, rule.equals
+                    ));
+                }
+            }
+        }
+        
+        if violations.is_empty() {
+            // Fields the schema does not describe are dropped.
+            values.retain(|key, _| key == "type" || schema.fields.iter().any(|field| field.name == *key));
+            Ok(values)
+        } else {
+            Err(ConfigError::ValidationFailed(violations))
+        }
+    }
+    
+    fn check_value(field: &FieldSchema, value: &str, path: &str, violations: &mut Vec<String>) {
+        let number = match field.kind {
+            FieldKind::String => None,
+            FieldKind::Integer => match value.trim().parse::<i64>() {
+                Ok(n) => Some(n as f64),
+                Err(_) => {
+                    violations.push(format!("{}: expected an integer, found '{}'", path, value));
+                    return;
+                }
+            },
+            FieldKind::Number => match value.trim().parse::<f64>() {
+                Ok(n) => Some(n),
+                Err(_) => {
+                    violations.push(format!("{}: expected a number, found '{}'", path, value));
+                    return;
+                }
+            },
+            FieldKind::Bool => {
+                if parse_bool(value).is_none() {
+                    violations.push(format!("{}: expected true or false, found '{}'", path, value));
+                }
+                return;
+            }
+            // Never echo a secret, and only literal values have a length.
+            FieldKind::Secret => {
+                match Secret::parse_reference(value) {
+                    Some(Err(())) => violations.push(format!(
+                        "{}: invalid secret reference, expected ${{env:NAME}} or ${{file:PATH}}", path
+                    )),
+                    Some(Ok(_)) => {}
+                    None if field.min_length.is_some_and(|min| value.chars().count() < min) => {
+                        violations.push(format!("{}: secret is too short", path));
+                    }
+                    None => {}
+                }
+                return;
+            }
+        };
+        
+        if let Some(n) = number {
+            if let Some(min) = field.min.filter(|min| n < *min) {
+                violations.push(format!("{}: {} is below the minimum of {}", path, value, min));
+            }
+            if let Some(max) = field.max.filter(|max| n > *max) {
+                violations.push(format!("{}: {} is above the maximum of {}", path, value, max));
+            }
+        }
+        
+        if let Some(min_length) = field.min_length {
+            let length = value.chars().count();
+            if min_length == 1 && length == 0 {
+                violations.push(format!("{}: must not be empty", path));
+            } else if length < min_length {
+                violations.push(format!("{}: must be at least {} characters", path, min_length));
+            }
+        }
+        
+        if let Some(pattern) = &field.pattern && !pattern.is_match(value) {
+            violations.push(format!("{}: '{}' does not match the pattern {}", path, value, pattern));
+        }
+        
+        if let Some(allowed) = &field.allowed && !allowed.iter().any(|option| option.eq_ignore_ascii_case(value)) {
+            violations.push(format!("{}: '{}' is not one of: {}", path, value, allowed.join(", ")));
+        }
+    }
+}
+
+// ============= SECRETS =============
+
+impl Secret {
+    const REDACTED: &'static str = "[redacted]";
+    
+    /// Resolves `${env:NAME}` from the environment and `${file:PATH}` from a
+    /// file (without its trailing newline); anything else is a literal.
+    fn resolve(raw: &str) -> Result<Secret, ConfigError> {
+        let unavailable = |reason: String| ConfigError::SecretUnavailable {
+            reference: raw.trim().to_string(),
+            reason,
+        };
+        
+        let value = match Self::parse_reference(raw) {
+            None => {
+                return Ok(Secret {
+                    value: raw.to_string(),
+                    reference: None,
+                });
+            }
+            Some(Err(())) => return Err(unavailable(String::from("expected ${env:NAME} or ${file:PATH}"))),
+            Some(Ok(("env", name))) => std::env::var(name)
+                .map_err(|_| unavailable(String::from("the environment variable is not set")))?,
+            Some(Ok((_, path))) => fs::read_to_string(path)
+                .map_err(|e| unavailable(e.to_string()))?
+                .trim_end_matches(['\n', '\r'])
+                .to_string(),
+        };
+        
+        Ok(Secret {
+            value,
+            reference: Some(raw.trim().to_string()),
+        })
+    }
+    
+    /// `None` for a literal, otherwise the scheme and target of a `${...}`
+    /// reference, or `Err` when it starts like one but is malformed.
+    fn parse_reference(raw: &str) -> Option<Result<(&str, &str), ()>> {
+        let inner = raw.trim().strip_prefix("${")?;
+        Some(
+            inner.strip_suffix('}')
+                .and_then(|inner| inner.split_once(':'))
+                .filter(|(scheme, target)| matches!(*scheme, "env" | "file") && !target.is_empty())
+                .ok_or(()),
+        )
+    }
+    
+    /// The secret in the clear. Callers opt in explicitly; nothing else
+    /// prints it.
+    fn expose(&self) -> &str {
+        &self.value
+    }
+    
+    /// What a saved file should hold: the reference, or the literal value.
+    fn stored_form(&self) -> &str {
+        self.reference.as_deref().unwrap_or(&self.value)
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", Self::REDACTED)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret({})", Self::REDACTED)
+    }
+}
+
+// =============  CONFIG IMPLEMENTATION =============
+
+impl Config {
+    fn load_from_file(filename: &str) -> Result<Config, ConfigError> {
+        Self::load_with_schema(filename, Schema::builtin())
+    }
+    
+    /// Loads a config whose type may be one that only `schema` defines.
+    fn load_with_schema(filename: &str, schema: &Schema) -> Result<Config, ConfigError> {
+        let fields = Self::read_fields(filename)?;
+        let config_type = Self::from_fields(&fields, schema)?;
+        
+        let last_modified = fs::metadata(filename)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .map(format_timestamp);
+        
+        Ok(Config {
+            config_type,
+            metadata: ConfigMetadata {
+                filename: filename.to_string(),
+                last_modified,
+                is_valid: true,
+            },
+        })
+    }
+    
+    /// Reads a config file into its raw `key -> value` fields, upgraded to
+    /// the current schema version but before type conversion or validation.
+    fn read_fields(filename: &str) -> Result<HashMap<String, String>, ConfigError> {
+        let contents = fs::read_to_string(filename)
+            .map_err(|_| ConfigError::FileNotFound(filename.to_string()))?;
+        
+        let mut fields = Self::parse_raw_fields(&contents, filename)?;
+        Migration::upgrade(&mut fields)?;
+        Ok(fields)
+    }
+    
+    /// Parses a JSON document, or a legacy `key: value` file when the
+    /// contents do not start with `{`.
+    fn parse_json(json: &str, filename: &str) -> Result<ConfigType, ConfigError> {
+        Self::from_fields(&Self::parse_raw_fields(json, filename)?, Schema::builtin())
+    }
+    
+    /// Validates raw fields against `schema`, naming violations after the
+    /// config's type, then converts them.
+    fn from_fields(fields: &HashMap<String, String>, schema: &Schema) -> Result<ConfigType, ConfigError> {
+        let path = fields.get("type").map(String::as_str).unwrap_or("config");
+        Self::parse_fields(&schema.validate(fields, path)?, schema)
+    }
+    
+    fn parse_raw_fields(contents: &str, filename: &str) -> Result<HashMap<String, String>, ConfigError> {
+        if contents.trim_start().starts_with('{') {
+            Self::parse_json_fields(contents, filename)
+        } else {
+            Self::parse_legacy_fields(contents)
+        }
+    }
+
+    fn parse_json_fields(json: &str, filename: &str) -> Result<HashMap<String, String>, ConfigError> {
+        let invalid = |reason: String| ConfigError::InvalidJson {
+            filename: filename.to_string(),
+            reason,
+        };
+
+        let document = JsonParser::parse(json).map_err(|e| invalid(e.to_string()))?;
+        let mut map = HashMap::new();
+        document.flatten_into("", &mut map);
+        Ok(map)
+    }
+
+    fn parse_legacy_fields(contents: &str) -> Result<HashMap<String, String>, ConfigError> {
+        let mut map: HashMap<String, String> = HashMap::new();
+        
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            
+            let parts: Vec<&str> = line.splitn(2, ':').collect();
+            if parts.len() != 2 {
+                return Err(ConfigError::ParseError(
+                    format!("Invalid line format: {}", line)
+                ));
+            }
+            
+            map.insert(
+                parts[0].trim().to_string(),
+                parts[1].trim().to_string(),
+            );
+        }
+        
+        Ok(map)
+    }
+    
+    /// Converts fields that already passed schema validation. Types without
+    /// a dedicated variant become `ConfigType::Custom`.
+    fn parse_fields(map: &HashMap<String, String>, schema: &Schema) -> Result<ConfigType, ConfigError> {
+        let config_type = map.get("type")
+            .ok_or_else(|| ConfigError::MissingField {
+                config_type: String::from("unknown"),
+                field: String::from("type"),
+            })?;
+        
+        match config_type.as_str() {
+            "database" => Self::parse_database_config(map),
+            "server" => Self::parse_server_config(map),
+            "application" => Self::parse_application_config(map),
+            other => {
+                let mut fields = BTreeMap::new();
+                let mut secrets = BTreeMap::new();
+                for (key, value) in map.iter().filter(|(key, _)| *key != "type") {
+                    if schema.field_kind(other, key) == Some(FieldKind::Secret) {
+                        secrets.insert(key.clone(), Secret::resolve(value)?);
+                    } else {
+                        fields.insert(key.clone(), value.clone());
+                    }
+                }
+                Ok(ConfigType::Custom {
+                    kind: other.to_string(),
+                    fields,
+                    secrets,
+                })
+            }
+        }
+    }
+    
+    fn parse_database_config(map: &HashMap<String, String>) -> Result<ConfigType, ConfigError> {
+        let host = map.get("host")
+            .ok_or_else(|| ConfigError::MissingField {
+                config_type: String::from("database"),
+                field: String::from("host"),
+            })?
+            .clone();
+        
+        let port_str = map.get("port")
+            .ok_or_else(|| ConfigError::MissingField {
+                config_type: String::from("database"),
+                field: String::from("port"),
+            })?;
+        
+        let port = port_str.parse::<u16>()
+            .map_err(|_| ConfigError::InvalidValue {
+                field: String::from("port"),
                 value: port_str.clone(),
                 expected: String::from("valid port number (1-65535)"),
             })?;
         
-        let username = map.get("username")
-            .ok_or_else(|| ConfigError::MissingField {
-                config_type: String::from("database"),
-                field: String::from("username"),
-            })?
-            .clone();
+        let username = map.get("username")
+            .ok_or_else(|| ConfigError::MissingField {
+                config_type: String::from("database"),
+                field: String::from("username"),
+            })?
+            .clone();
+        
+        let password = map.get("password")
+            .map(|reference| Secret::resolve(reference))
+            .transpose()?;
+        
+        let max_connections_str = map.get("max_connections")
+            .ok_or_else(|| ConfigError::MissingField {
+                config_type: String::from("database"),
+                field: String::from("max_connections"),
+            })?;
+        
+        let max_connections = max_connections_str.parse::<u32>()
+            .map_err(|_| ConfigError::InvalidValue {
+                field: String::from("max_connections"),
+                value: max_connections_str.clone(),
+                expected: String::from("positive integer"),
+            })?;
+        
+        Ok(ConfigType::Database {
+            host,
+            port,
+            username,
+            password,
+            max_connections,
+        })
+    }
+    
+    fn parse_server_config(map: &HashMap<String, String>) -> Result<ConfigType, ConfigError> {
+        let bind_address = map.get("bind_address")
+            .ok_or_else(|| ConfigError::MissingField {
+                config_type: String::from("server"),
+                field: String::from("bind_address"),
+            })?
+            .clone();
+        
+        let port_str = map.get("port")
+            .ok_or_else(|| ConfigError::MissingField {
+                config_type: String::from("server"),
+                field: String::from("port"),
+            })?;
+        
+        let port = port_str.parse::<u16>()
+            .map_err(|_| ConfigError::InvalidValue {
+                field: String::from("port"),
+                value: port_str.clone(),
+                expected: String::from("valid port number (1-65535)"),
+            })?;
+        
+        let ssl_str = map.get("ssl_enabled")
+            .ok_or_else(|| ConfigError::MissingField {
+                config_type: String::from("server"),
+                field: String::from("ssl_enabled"),
+            })?;
+        
+        let ssl_enabled = match ssl_str.to_lowercase().as_str() {
+            "true" | "yes" | "1" => true,
+            "false" | "no" | "0" => false,
+            _ => return Err(ConfigError::InvalidValue {
+                field: String::from("ssl_enabled"),
+                value: ssl_str.clone(),
+                expected: String::from("true or false"),
+            }),
+        };
+        
+        Ok(ConfigType::Server {
+            bind_address,
+            port,
+            ssl_enabled,
+        })
+    }
+    
+    fn parse_application_config(map: &HashMap<String, String>) -> Result<ConfigType, ConfigError> {
+        let name = map.get("name")
+            .ok_or_else(|| ConfigError::MissingField {
+                config_type: String::from("application"),
+                field: String::from("name"),
+            })?
+            .clone();
+        
+        let version = map.get("version")
+            .ok_or_else(|| ConfigError::MissingField {
+                config_type: String::from("application"),
+                field: String::from("version"),
+            })?
+            .clone();
+        
+        let debug_str = map.get("debug_mode")
+            .ok_or_else(|| ConfigError::MissingField {
+                config_type: String::from("application"),
+                field: String::from("debug_mode"),
+            })?;
+        
+        let debug_mode = match debug_str.to_lowercase().as_str() {
+            "true" | "yes" | "1" => true,
+            "false" | "no" | "0" => false,
+            _ => return Err(ConfigError::InvalidValue {
+                field: String::from("debug_mode"),
+                value: debug_str.clone(),
+                expected: String::from("true or false"),
+            }),
+        };
+        
+        let log_level_str = map.get("log_level")
+            .ok_or_else(|| ConfigError::MissingField {
+                config_type: String::from("application"),
+                field: String::from("log_level"),
+            })?;
+        
+        let log_level = match log_level_str.to_lowercase().as_str() {
+            "debug" => LogLevel::Debug,
+            "info" => LogLevel::Info,
+            "warning" => LogLevel::Warning,
+            "error" => LogLevel::Error,
+            _ => return Err(ConfigError::InvalidValue {
+                field: String::from("log_level"),
+                value: log_level_str.clone(),
+                expected: String::from("debug, info, warning, or error"),
+            }),
+        };
+        
+        Ok(ConfigType::Application {
+            name,
+            version,
+            debug_mode,
+            log_level,
+        })
+    }
+    
+    /// Checks an already-built config against `schema`.
+    fn validate_config(config_type: &ConfigType, schema: &Schema) -> Result<(), ConfigError> {
+        let fields: HashMap<String, String> = config_type.to_stored_fields().into_iter().collect();
+        schema.validate(&fields, config_type.kind())?;
+        Ok(())
+    }
+    
+    /// Writes the config with secrets as their references, or as literal
+    /// values where the file held no reference.
+    fn save_to_file(&self, filename: &str) -> Result<(), ConfigError> {
+        let mut content = format!("{}: {}\n", SCHEMA_VERSION_FIELD, CURRENT_SCHEMA_VERSION);
+        for (key, value) in self.config_type.to_stored_fields() {
+            content.push_str(&format!("{}: {}\n", key, value));
+        }
+        
+        fs::write(filename, content)
+            .map_err(|e| ConfigError::SaveFailed {
+                filename: filename.to_string(),
+                reason: e.to_string(),
+            })?;
+        
+        println!("✓ Configuration saved to: {}", filename);
+        Ok(())
+    }
+    
+    fn display(&self) {
+        println!("\n=== Configuration ===");
+        println!("File: {}", self.metadata.filename);
+        println!("Valid: {}", self.metadata.is_valid);
+        if let Some(modified) = &self.metadata.last_modified {
+            println!("Last Modified: {}", modified);
+        }
+        println!();
+        
+        match &self.config_type {
+            ConfigType::Database { host, port, username, password, max_connections } => {
+                println!("Type: Database");
+                println!("  Host: {}", host);
+                println!("  Port: {}", port);
+                println!("  Username: {}", username);
+                if let Some(password) = password {
+                    println!("  Password: {}", password);
+                }
+                println!("  Max Connections: {}", max_connections);
+            }
+            
+            ConfigType::Server { bind_address, port, ssl_enabled } => {
+                println!("Type: Server");
+                println!("  Bind Address: {}", bind_address);
+                println!("  Port: {}", port);
+                println!("  SSL Enabled: {}", ssl_enabled);
+            }
+            
+            ConfigType::Application { name, version, debug_mode, log_level } => {
+                println!("Type: Application");
+                println!("  Name: {}", name);
+                println!("  Version: {}", version);
+                println!("  Debug Mode: {}", debug_mode);
+                println!("  Log Level: {:?}", log_level);
+            }
+            
+            ConfigType::Custom { kind, .. } => {
+                println!("Type: {}", kind);
+                for (key, value) in self.config_type.to_fields().into_iter().skip(1) {
+                    println!("  {}: {}", key, value);
+                }
+            }
+        }
+        println!("====================\n");
+    }
+}
+
+fn format_timestamp(time: SystemTime) -> String {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => format!("{} (Unix time)", elapsed.as_secs()),
+        Err(_) => String::from("before 1970"),
+    }
+}
+
+impl fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.old, &self.new) {
+            (Some(old), Some(new)) => write!(f, "~ {}: {} -> {}", self.field, old, new),
+            (None, Some(new)) => write!(f, "+ {}: {}", self.field, new),
+            (Some(old), None) => write!(f, "- {}: {}", self.field, old),
+            (None, None) => write!(f, "  {}", self.field),
+        }
+    }
+}
+
+impl fmt::Display for Config {
+    /// Writes the `key: value` form that `save_to_file` stores, but with
+    /// secrets redacted.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}: {}", SCHEMA_VERSION_FIELD, CURRENT_SCHEMA_VERSION)?;
+        for (key, value) in self.config_type.to_fields() {
+            writeln!(f, "{}: {}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+// ============= CONFIG SETS =============
+
+impl ConfigSet {
+    fn load_from_file(filename: &str) -> Result<ConfigSet, ConfigError> {
+        let contents = fs::read_to_string(filename)
+            .map_err(|_| ConfigError::FileNotFound(filename.to_string()))?;
+        
+        Self::parse(&contents, filename)
+    }
+    
+    fn parse(contents: &str, filename: &str) -> Result<ConfigSet, ConfigError> {
+        Self::parse_with_schema(contents, filename, Schema::builtin())
+    }
+    
+    /// Parses and validates every section, reporting all failing sections
+    /// together rather than stopping at the first. Violations are named by
+    /// section, as in `database.replica.port`.
+    fn parse_with_schema(contents: &str, filename: &str, schema: &Schema) -> Result<ConfigSet, ConfigError> {
+        let raw_sections = if contents.trim_start().starts_with('{') {
+            Self::split_json_sections(contents, filename)?
+        } else {
+            Self::split_sections(contents)?
+        };
         
-        let max_connections_str = map.get("max_connections")
-            .ok_or_else(|| ConfigError::MissingField {
-                config_type: String::from("database"),
-                field: String::from("max_connections"),
-            })?;
+        let mut sections = Vec::new();
+        let mut errors = Vec::new();
+        let mut seen = HashSet::new();
         
-        let max_connections = max_connections_str.parse::<u32>()
-            .map_err(|_| ConfigError::InvalidValue {
-                field: String::from("max_connections"),
-                value: max_connections_str.clone(),
-                expected: String::from("positive integer"),
-            })?;
+        for (name, fields) in raw_sections {
+            if !seen.insert(name.clone()) {
+                errors.push(format!("{}: section is defined more than once", name));
+                continue;
+            }
+            
+            match Self::parse_section(&name, fields, schema) {
+                Ok(config_type) => sections.push(ConfigSection { name, config_type }),
+                Err(ConfigError::ValidationFailed(violations)) => errors.extend(violations),
+                Err(e) => errors.push(format!("{}: {}", name, e)),
+            }
+        }
         
-        Ok(ConfigType::Database {
-            host,
-            port,
-            username,
-            max_connections,
+        if !errors.is_empty() {
+            return Err(ConfigError::ValidationFailed(errors));
+        }
+        if sections.is_empty() {
+            return Err(ConfigError::ParseError(format!("{} contains no sections", filename)));
+        }
+        
+        Ok(ConfigSet {
+            filename: filename.to_string(),
+            sections,
         })
     }
     
-    fn parse_server_config(map: &HashMap<String, String>) -> Result<ConfigType, ConfigError> {
-        let bind_address = map.get("bind_address")
-            .ok_or_else(|| ConfigError::MissingField {
-                config_type: String::from("server"),
-                field: String::from("bind_address"),
-            })?
-            .clone();
+    fn parse_section(name: &str, mut fields: HashMap<String, String>, schema: &Schema) -> Result<ConfigType, ConfigError> {
+        // The header decides the type: `[database.replica]` is a database.
+        let kind = name.split('.').next().unwrap_or(name);
+        fields.insert(String::from("type"), kind.to_string());
+        Config::parse_fields(&schema.validate(&fields, name)?, schema)
+    }
+    
+    fn split_sections(contents: &str) -> Result<Vec<RawSection>, ConfigError> {
+        let mut sections: Vec<(String, String)> = Vec::new();
         
-        let port_str = map.get("port")
-            .ok_or_else(|| ConfigError::MissingField {
-                config_type: String::from("server"),
-                field: String::from("port"),
-            })?;
+        for line in contents.lines() {
+            let trimmed = line.trim();
+            if let Some(header) = trimmed.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+                let name = header.trim();
+                if name.is_empty() {
+                    return Err(ConfigError::ParseError(String::from("Empty section header: []")));
+                }
+                sections.push((name.to_string(), String::new()));
+                continue;
+            }
+            
+            match sections.last_mut() {
+                Some((_, body)) => {
+                    body.push_str(line);
+                    body.push('\n');
+                }
+                None if trimmed.is_empty() || trimmed.starts_with('#') => {}
+                None => {
+                    return Err(ConfigError::ParseError(
+                        format!("Line outside of any section: {}", trimmed)
+                    ));
+                }
+            }
+        }
         
-        let port = port_str.parse::<u16>()
-            .map_err(|_| ConfigError::InvalidValue {
-                field: String::from("port"),
-                value: port_str.clone(),
-                expected: String::from("valid port number (1-65535)"),
-            })?;
+        sections.into_iter()
+            .map(|(name, body)| Ok((name, Config::parse_legacy_fields(&body)?)))
+            .collect()
+    }
+    
+    /// JSON files hold one object per section: `{"database.primary": {...}}`.
+    fn split_json_sections(contents: &str, filename: &str) -> Result<Vec<RawSection>, ConfigError> {
+        let invalid = |reason: String| ConfigError::InvalidJson {
+            filename: filename.to_string(),
+            reason,
+        };
         
-        let ssl_str = map.get("ssl_enabled")
+        let members = match JsonParser::parse(contents).map_err(|e| invalid(e.to_string()))? {
+            JsonValue::Object(members) => members,
+            _ => return Err(invalid(String::from("expected an object of sections"))),
+        };
+        
+        let mut sections = Vec::new();
+        for (name, value) in members {
+            if !matches!(value, JsonValue::Object(_)) {
+                return Err(invalid(format!("section '{}' must be an object", name)));
+            }
+            let mut fields = HashMap::new();
+            value.flatten_into("", &mut fields);
+            sections.push((name, fields));
+        }
+        Ok(sections)
+    }
+    
+    fn get(&self, name: &str) -> Option<&ConfigType> {
+        self.sections.iter()
+            .find(|section| section.name == name)
+            .map(|section| &section.config_type)
+    }
+    
+    fn display(&self) {
+        println!("\n=== Configuration Set ===");
+        println!("File: {}", self.filename);
+        println!("Sections: {}", self.sections.len());
+        
+        for section in &self.sections {
+            println!("\n[{}]", section.name);
+            println!("  {:?}", section.config_type);
+        }
+        println!("=========================\n");
+    }
+}
+
+// ============= LAYERED LOADING =============
+
+const ENV_PREFIX: &str = "APP_";
+
+impl fmt::Display for ConfigLayer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ConfigLayer::Default => "default",
+            ConfigLayer::File => "file",
+            ConfigLayer::Environment => "environment",
+            ConfigLayer::CommandLine => "command line",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl LayeredConfig {
+    /// Merges built-in defaults < `filename` < `APP_*` variables from `env`
+    /// < `--set key=value` flags from `args`, then converts and validates
+    /// the result like `Config::load_from_file` does.
+    fn load(
+        filename: Option<&str>,
+        env: impl IntoIterator<Item = (String, String)>,
+        args: &[String],
+    ) -> Result<LayeredConfig, ConfigError> {
+        let mut merged: HashMap<String, (String, Provenance)> = HashMap::new();
+        let mut apply = |layer: ConfigLayer, key: String, value: String, source: String| {
+            merged.insert(key, (value, Provenance { layer, source }));
+        };
+        
+        if let Some(filename) = filename {
+            for (key, value) in Config::read_fields(filename)? {
+                apply(ConfigLayer::File, key, value, filename.to_string());
+            }
+        }
+        
+        let mut env: Vec<(String, String)> = env.into_iter()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX) && name.len() > ENV_PREFIX.len())
+            .collect();
+        env.sort();
+        for (name, value) in env {
+            let key = name[ENV_PREFIX.len()..].to_lowercase();
+            apply(ConfigLayer::Environment, key, value, name);
+        }
+        
+        for (key, value) in Self::parse_overrides(args)? {
+            let source = format!("--set {}={}", key, value);
+            apply(ConfigLayer::CommandLine, key, value, source);
+        }
+        
+        // Defaults depend on the type, so they go in last, under everything else.
+        let kind = merged.get("type")
+            .map(|(kind, _)| kind.clone())
             .ok_or_else(|| ConfigError::MissingField {
-                config_type: String::from("server"),
-                field: String::from("ssl_enabled"),
+                config_type: String::from("unknown"),
+                field: String::from("type"),
             })?;
+        let schema = Schema::builtin();
+        for (key, value) in schema.defaults(&kind) {
+            merged.entry(key).or_insert_with(|| {
+                let provenance = Provenance {
+                    layer: ConfigLayer::Default,
+                    source: String::from("built-in default"),
+                };
+                (value, provenance)
+            });
+        }
         
-        let ssl_enabled = match ssl_str.to_lowercase().as_str() {
-            "true" | "yes" | "1" => true,
-            "false" | "no" | "0" => false,
-            _ => return Err(ConfigError::InvalidValue {
-                field: String::from("ssl_enabled"),
-                value: ssl_str.clone(),
-                expected: String::from("true or false"),
-            }),
-        };
+        let fields: HashMap<String, String> = merged.iter()
+            .map(|(key, (value, _))| (key.clone(), value.clone()))
+            .collect();
+        let config_type = Config::from_fields(&fields, schema)?;
         
-        Ok(ConfigType::Server {
-            bind_address,
-            port,
-            ssl_enabled,
+        // Only report fields the chosen type actually uses, and never a secret's value.
+        let used = schema.field_names(&kind);
+        let provenance = merged.into_iter()
+            .filter(|(key, _)| key == "type" || used.contains(key))
+            .map(|(key, (value, mut provenance))| {
+                if schema.field_kind(&kind, &key) != Some(FieldKind::Secret) {
+                    return (key, (value, provenance));
+                }
+                if provenance.layer == ConfigLayer::CommandLine {
+                    provenance.source = format!("--set {}={}", key, Secret::REDACTED);
+                }
+                (key, (Secret::REDACTED.to_string(), provenance))
+            })
+            .collect();
+        
+        Ok(LayeredConfig {
+            config: Config {
+                config_type,
+                metadata: ConfigMetadata {
+                    filename: filename.unwrap_or("<layered>").to_string(),
+                    last_modified: None,
+                    is_valid: true,
+                },
+            },
+            provenance,
         })
     }
     
-    fn parse_application_config(map: &HashMap<String, String>) -> Result<ConfigType, ConfigError> {
-        let name = map.get("name")
-            .ok_or_else(|| ConfigError::MissingField {
-                config_type: String::from("application"),
-                field: String::from("name"),
-            })?
-            .clone();
+    /// Collects `--set key=value` (or `--set=key=value`) flags; other
+    /// arguments are left for the caller.
+    fn parse_overrides(args: &[String]) -> Result<Vec<(String, String)>, ConfigError> {
+        let mut overrides = Vec::new();
+        let mut args = args.iter();
         
-        let version = map.get("version")
-            .ok_or_else(|| ConfigError::MissingField {
-                config_type: String::from("application"),
-                field: String::from("version"),
-            })?
-            .clone();
+        while let Some(arg) = args.next() {
+            let assignment = if arg == "--set" {
+                args.next().ok_or_else(|| ConfigError::ParseError(String::from("--set requires key=value")))?
+            } else if let Some(assignment) = arg.strip_prefix("--set=") {
+                assignment
+            } else {
+                continue;
+            };
+            
+            match assignment.split_once('=') {
+                Some((key, value)) if !key.trim().is_empty() => {
+                    overrides.push((key.trim().to_string(), value.trim().to_string()));
+                }
+                _ => {
+                    return Err(ConfigError::ParseError(
+                        format!("Invalid override '{}', expected key=value", assignment)
+                    ));
+                }
+            }
+        }
         
-        let debug_str = map.get("debug_mode")
-            .ok_or_else(|| ConfigError::MissingField {
-                config_type: String::from("application"),
-                field: String::from("debug_mode"),
-            })?;
+        Ok(overrides)
+    }
+    
+    fn provenance_report(&self) -> String {
+        let width = self.provenance.keys().map(|key| key.len()).max().unwrap_or(0);
+        let mut report = String::from("=== Configuration Provenance ===\n");
         
-        let debug_mode = match debug_str.to_lowercase().as_str() {
-            "true" | "yes" | "1" => true,
-            "false" | "no" | "0" => false,
-            _ => return Err(ConfigError::InvalidValue {
-                field: String::from("debug_mode"),
-                value: debug_str.clone(),
-                expected: String::from("true or false"),
-            }),
+        for (key, (value, provenance)) in &self.provenance {
+            report.push_str(&format!(
+                "  {:<width$} = {:<20} ({}: {})\n",
+                key, value, provenance.layer, provenance.source,
+                width = width
+            ));
+        }
+        report
+    }
+}
+
+// ============= HOT RELOAD =============
+
+impl ConfigWatcher {
+    fn new(filename: &str) -> Result<ConfigWatcher, ConfigError> {
+        let last_seen = Self::file_signature(filename);
+        let config = Config::load_from_file(filename)?;
+        
+        Ok(ConfigWatcher {
+            filename: filename.to_string(),
+            live: Arc::new(RwLock::new(Arc::new(config))),
+            last_seen,
+            callbacks: Vec::new(),
+        })
+    }
+    
+    fn file_signature(filename: &str) -> Option<(SystemTime, u64)> {
+        let metadata = fs::metadata(filename).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    }
+    
+    /// The config currently in effect. Readers keep the snapshot they got
+    /// even if a reload swaps in a newer one.
+    fn current(&self) -> Arc<Config> {
+        Arc::clone(&self.live.read().unwrap_or_else(|e| e.into_inner()))
+    }
+    
+    /// Registers a callback run after each successful reload that changed
+    /// at least one field.
+    fn on_change(&mut self, callback: impl Fn(&[FieldChange], &Config) + Send + 'static) {
+        self.callbacks.push(Box::new(callback));
+    }
+    
+    /// Reloads the file if it changed since the last poll. A file that fails
+    /// to parse or validate leaves the current config in place; the error is
+    /// returned once, and the file is retried after its next change.
+    fn poll(&mut self) -> Result<ReloadOutcome, ConfigError> {
+        let signature = Self::file_signature(&self.filename);
+        if signature == self.last_seen {
+            return Ok(ReloadOutcome::Unchanged);
+        }
+        self.last_seen = signature;
+        
+        let config = Config::load_from_file(&self.filename)?;
+        let changes = self.current().config_type.diff(&config.config_type);
+        if changes.is_empty() {
+            return Ok(ReloadOutcome::Unchanged);
+        }
+        
+        let config = Arc::new(config);
+        *self.live.write().unwrap_or_else(|e| e.into_inner()) = Arc::clone(&config);
+        for callback in &self.callbacks {
+            callback(&changes, &config);
+        }
+        Ok(ReloadOutcome::Reloaded(changes))
+    }
+    
+    /// Polls every `interval` on a background thread until the handle is dropped.
+    fn spawn(mut self, interval: Duration) -> WatcherHandle {
+        let live = Arc::clone(&self.live);
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        
+        let thread = thread::spawn(move || {
+            while !thread_stop.load(Ordering::Relaxed) {
+                if let Err(e) = self.poll() {
+                    eprintln!("✗ Keeping previous configuration for {}: {}", self.filename, e);
+                }
+                thread::park_timeout(interval);
+            }
+        });
+        
+        WatcherHandle {
+            live,
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl WatcherHandle {
+    fn current(&self) -> Arc<Config> {
+        Arc::clone(&self.live.read().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+impl Drop for WatcherHandle {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+// ============= MIGRATIONS =============
+
+/// The version `save_to_file` stamps on every file it writes. Files from
+/// before versioning have no `schema_version` and count as version 1.
+const CURRENT_SCHEMA_VERSION: u32 = 3;
+
+const SCHEMA_VERSION_FIELD: &str = "schema_version";
+
+/// One edit to the raw fields of every config of `config_type`.
+enum MigrationStep {
+    Rename {
+        config_type: &'static str,
+        from: &'static str,
+        to: &'static str,
+    },
+    /// Splits `from` at the last `separator` into two fields.
+    Split {
+        config_type: &'static str,
+        from: &'static str,
+        separator: char,
+        into: (&'static str, &'static str),
+    },
+}
+
+/// The steps that bring a file from `version - 1` up to `version`.
+struct Migration {
+    version: u32,
+    description: &'static str,
+    steps: &'static [MigrationStep],
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 2,
+        description: "split server 'listen' into 'bind_address' and 'port'",
+        steps: &[MigrationStep::Split {
+            config_type: "server",
+            from: "listen",
+            separator: ':',
+            into: ("bind_address", "port"),
+        }],
+    },
+    Migration {
+        version: 3,
+        description: "rename database 'max_conn' to 'max_connections'",
+        steps: &[MigrationStep::Rename {
+            config_type: "database",
+            from: "max_conn",
+            to: "max_connections",
+        }],
+    },
+];
+
+impl MigrationStep {
+    /// Applies the step, doing nothing when the old field is absent.
+    fn apply(&self, fields: &mut HashMap<String, String>) -> Result<(), String> {
+        let (config_type, from) = match self {
+            MigrationStep::Rename { config_type, from, .. } => (config_type, from),
+            MigrationStep::Split { config_type, from, .. } => (config_type, from),
+        };
+        if fields.get("type").map(String::as_str) != Some(*config_type) {
+            return Ok(());
+        }
+        let Some(value) = fields.remove(*from) else {
+            return Ok(());
         };
         
-        let log_level_str = map.get("log_level")
-            .ok_or_else(|| ConfigError::MissingField {
-                config_type: String::from("application"),
-                field: String::from("log_level"),
-            })?;
+        match self {
+            MigrationStep::Rename { to, .. } => {
+                Self::insert_new(fields, to, value)?;
+            }
+            MigrationStep::Split { separator, into: (first, second), .. } => {
+                let (left, right) = value.rsplit_once(*separator).ok_or_else(|| {
+                    format!("cannot split {} '{}': expected a '{}'", from, value, separator)
+                })?;
+                Self::insert_new(fields, first, left.trim().to_string())?;
+                Self::insert_new(fields, second, right.trim().to_string())?;
+            }
+        }
+        Ok(())
+    }
+    
+    fn insert_new(fields: &mut HashMap<String, String>, key: &str, value: String) -> Result<(), String> {
+        if fields.contains_key(key) {
+            return Err(format!("'{}' would overwrite an existing field", key));
+        }
+        fields.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+impl Migration {
+    /// Reads and removes the `schema_version` field.
+    fn version_of(fields: &mut HashMap<String, String>) -> Result<u32, ConfigError> {
+        let Some(value) = fields.remove(SCHEMA_VERSION_FIELD) else {
+            return Ok(1);
+        };
         
-        let log_level = match log_level_str.to_lowercase().as_str() {
-            "debug" => LogLevel::Debug,
-            "info" => LogLevel::Info,
-            "warning" => LogLevel::Warning,
-            "error" => LogLevel::Error,
-            _ => return Err(ConfigError::InvalidValue {
-                field: String::from("log_level"),
-                value: log_level_str.clone(),
-                expected: String::from("debug, info, warning, or error"),
+        match value.trim().parse::<u32>() {
+            Ok(version) if (1..=CURRENT_SCHEMA_VERSION).contains(&version) => Ok(version),
+            Ok(version) => Err(ConfigError::MigrationFailed {
+                version,
+                reason: format!("this build only understands versions up to {}", CURRENT_SCHEMA_VERSION),
+            }),
+            Err(_) => Err(ConfigError::InvalidValue {
+                field: SCHEMA_VERSION_FIELD.to_string(),
+                value,
+                expected: String::from("a positive integer"),
             }),
+        }
+    }
+    
+    /// Brings raw fields up to the current version, returning the version
+    /// they started at and the migrations that were applied.
+    fn upgrade(fields: &mut HashMap<String, String>) -> Result<(u32, Vec<&'static str>), ConfigError> {
+        let from = Self::version_of(fields)?;
+        let mut applied = Vec::new();
+        
+        for migration in MIGRATIONS.iter().filter(|migration| migration.version > from) {
+            for step in migration.steps {
+                step.apply(fields).map_err(|reason| ConfigError::MigrationFailed {
+                    version: migration.version,
+                    reason,
+                })?;
+            }
+            applied.push(migration.description);
+        }
+        Ok((from, applied))
+    }
+}
+
+impl Config {
+    /// Upgrades `filename` to the current schema version in place, keeping
+    /// its format and any fields the schema does not describe. The old file
+    /// is first copied to `<filename>.v<N>.bak`; nothing is written if the
+    /// file is already current or fails to migrate.
+    fn migrate_file(filename: &str) -> Result<MigrationReport, ConfigError> {
+        let contents = fs::read_to_string(filename)
+            .map_err(|_| ConfigError::FileNotFound(filename.to_string()))?;
+        let mut fields = Self::parse_raw_fields(&contents, filename)?;
+        let (from, applied) = Migration::upgrade(&mut fields)?;
+        
+        let mut report = MigrationReport {
+            from,
+            to: CURRENT_SCHEMA_VERSION,
+            applied,
+            backup: None,
+        };
+        if from == CURRENT_SCHEMA_VERSION {
+            return Ok(report);
+        }
+        
+        // Only check the built-in types; the file keeps exactly its own fields.
+        if let Some(kind) = fields.get("type") && Schema::builtin().types.contains_key(kind) {
+            Schema::builtin().validate(&fields, kind)?;
+        }
+        let migrated = Self::render_raw_fields(&contents, &fields);
+        
+        // Never overwrite an earlier backup: it may be the only copy of the original.
+        let backup = format!("{}.v{}.bak", filename, from);
+        let backup_failed = |e: std::io::Error| ConfigError::SaveFailed {
+            filename: backup.clone(),
+            reason: if e.kind() == std::io::ErrorKind::AlreadyExists {
+                String::from("backup already exists; move it aside before migrating again")
+            } else {
+                e.to_string()
+            },
         };
+        fs::File::create_new(&backup)
+            .and_then(|mut file| std::io::Write::write_all(&mut file, contents.as_bytes()))
+            .map_err(backup_failed)?;
+        fs::write(filename, migrated).map_err(|e| ConfigError::SaveFailed {
+            filename: filename.to_string(),
+            reason: e.to_string(),
+        })?;
         
-        Ok(ConfigType::Application {
-            name,
-            version,
-            debug_mode,
-            log_level,
-        })
+        report.backup = Some(backup);
+        Ok(report)
     }
     
-    fn validate_config(config_type: &ConfigType) -> Result<(), ConfigError> {
-        let mut errors = Vec::new();
-        
-        match config_type {
-            ConfigType::Database { host, port, username, max_connections } => {
-                if host.is_empty() {
-                    errors.push(String::from("Host cannot be empty"));
-                }
-                
-                if *port == 0 {
-                    errors.push(String::from("Port must be between 1 and 65535"));
-                }
-                
-                if username.is_empty() {
-                    errors.push(String::from("Username cannot be empty"));
-                }
-                
-                if *max_connections == 0 {
-                    errors.push(String::from("Max connections must be at least 1"));
-                }
-                
-                if *max_connections > 10000 {
-                    errors.push(String::from("Max connections seems unreasonably high (>10000)"));
-                }
+    /// Writes migrated raw fields in the format of the original `contents`.
+    /// Fields keep their original order, and in JSON their original type;
+    /// fields a migration introduced go last, typed by the built-in schema.
+    fn render_raw_fields(contents: &str, fields: &HashMap<String, String>) -> String {
+        let json = contents.trim_start().starts_with('{');
+        let mut original = Vec::new();
+        if json {
+            if let Ok(document) = JsonParser::parse(contents) {
+                document.flatten_ordered("", &mut original);
             }
-            
-            ConfigType::Server { bind_address, port, .. } => {
-                if bind_address.is_empty() {
-                    errors.push(String::from("Bind address cannot be empty"));
-                }
-                
-                if !bind_address.contains('.') && bind_address != "localhost" {
-                    errors.push(format!("'{}' doesn't look like a valid address", bind_address));
-                }
-                
-                if *port == 0 {
-                    errors.push(String::from("Port must be between 1 and 65535"));
+        } else {
+            for line in contents.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+                if let Some((key, value)) = line.split_once(':') {
+                    original.push((key.trim().to_string(), JsonValue::String(value.trim().to_string())));
                 }
             }
-            
-            ConfigType::Application { name, version, .. } => {
-                if name.is_empty() {
-                    errors.push(String::from("Application name cannot be empty"));
-                }
-                
-                if version.is_empty() {
-                    errors.push(String::from("Version cannot be empty"));
-                }
-                
-                let parts: Vec<&str> = version.split('.').collect();
-                if parts.len() != 3 {
-                    errors.push(format!("Version '{}' should be in format x.y.z", version));
-                }
+        }
+        
+        let mut keys: Vec<&String> = Vec::new();
+        for (key, _) in &original {
+            if let Some((key, _)) = fields.get_key_value(key) && !keys.contains(&key) {
+                keys.push(key);
             }
         }
+        let mut added: Vec<&String> = fields.keys().filter(|key| !keys.contains(key)).collect();
+        added.sort();
+        keys.extend(added);
         
-        if errors.is_empty() {
-            Ok(())
-        } else {
-            Err(ConfigError::ValidationFailed(errors))
+        if !json {
+            let mut content = format!("{}: {}\n", SCHEMA_VERSION_FIELD, CURRENT_SCHEMA_VERSION);
+            for key in keys {
+                content.push_str(&format!("{}: {}\n", key, fields[key]));
+            }
+            return content;
         }
-    }
-    
-    fn save_to_file(&self, filename: &str) -> Result<(), ConfigError> {
-        let content = self.to_string();
         
-        fs::write(filename, content)
-            .map_err(|e| ConfigError::SaveFailed {
-                filename: filename.to_string(),
-                reason: e.to_string(),
-            })?;
+        let kind = fields.get("type").map(String::as_str).unwrap_or_default();
+        let mut document = JsonValue::Object(vec![(
+            SCHEMA_VERSION_FIELD.to_string(),
+            JsonValue::Number(CURRENT_SCHEMA_VERSION as f64),
+        )]);
+        for key in keys {
+            let value = &fields[key];
+            let unchanged = original.iter()
+                .find(|(name, original)| name == key && original.to_string() == *value)
+                .map(|(_, original)| original.clone());
+            let typed = unchanged.or_else(|| match Schema::builtin().field_kind(kind, key) {
+                Some(FieldKind::Integer | FieldKind::Number) => value.parse().ok().map(JsonValue::Number),
+                Some(FieldKind::Bool) => parse_bool(value).map(JsonValue::Bool),
+                _ => None,
+            });
+            document.insert_path(key, typed.unwrap_or_else(|| JsonValue::String(value.clone())));
+        }
         
-        println!("✓ Configuration saved to: {}", filename);
-        Ok(())
+        let mut content = String::new();
+        document.write_json(&mut content, 0);
+        content.push('\n');
+        content
     }
     
-    fn to_string(&self) -> String {
-        let mut output = String::new();
-        
-        match &self.config_type {
-            ConfigType::Database { host, port, username, max_connections } => {
-                output.push_str("type: database\n");
-                output.push_str(&format!("host: {}\n", host));
-                output.push_str(&format!("port: {}\n", port));
-                output.push_str(&format!("username: {}\n", username));
-                output.push_str(&format!("max_connections: {}\n", max_connections));
-            }
-            
-            ConfigType::Server { bind_address, port, ssl_enabled } => {
-                output.push_str("type: server\n");
-                output.push_str(&format!("bind_address: {}\n", bind_address));
-                output.push_str(&format!("port: {}\n", port));
-                output.push_str(&format!("ssl_enabled: {}\n", ssl_enabled));
-            }
-            
-            ConfigType::Application { name, version, debug_mode, log_level } => {
-                output.push_str("type: application\n");
-                output.push_str(&format!("name: {}\n", name));
-                output.push_str(&format!("version: {}\n", version));
-                output.push_str(&format!("debug_mode: {}\n", debug_mode));
-                
-                let level_str = match log_level {
-                    LogLevel::Debug => "debug",
-                    LogLevel::Info => "info",
-                    LogLevel::Warning => "warning",
-                    LogLevel::Error => "error",
-                };
-                output.push_str(&format!("log_level: {}\n", level_str));
-            }
+    /// Field-by-field changes from `self` to `other`.
+    fn diff(&self, other: &Config) -> Vec<FieldChange> {
+        self.config_type.diff(&other.config_type)
+    }
+}
+
+impl fmt::Display for MigrationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.backup.is_none() {
+            return write!(f, "already at schema version {}", self.to);
         }
         
-        output
+        writeln!(f, "migrated from schema version {} to {}:", self.from, self.to)?;
+        for description in &self.applied {
+            writeln!(f, "  - {}", description)?;
+        }
+        write!(f, "backup written to {}", self.backup.as_deref().unwrap_or_default())
     }
+}
+
+// ============= COMMANDS =============
+
+/// Runs `diff <old> <new>` or `migrate <file>...`; returns `None` when
+/// `args` name no command so `main` runs the examples instead.
+fn run_command(args: &[String]) -> Option<Result<(), ConfigError>> {
+    let (command, operands) = args.split_first()?;
     
-    fn display(&self) {
-        println!("\n=== Configuration ===");
-        println!("File: {}", self.metadata.filename);
-        println!("Valid: {}", self.metadata.is_valid);
-        println!();
-        
-        match &self.config_type {
-            ConfigType::Database { host, port, username, max_connections } => {
-                println!("Type: Database");
-                println!("  Host: {}", host);
-                println!("  Port: {}", port);
-                println!("  Username: {}", username);
-                println!("  Max Connections: {}", max_connections);
-            }
-            
-            ConfigType::Server { bind_address, port, ssl_enabled } => {
-                println!("Type: Server");
-                println!("  Bind Address: {}", bind_address);
-                println!("  Port: {}", port);
-                println!("  SSL Enabled: {}", ssl_enabled);
+    let result = match (command.as_str(), operands) {
+        ("diff", [old, new]) => Config::load_from_file(old).and_then(|old_config| {
+            let changes = old_config.diff(&Config::load_from_file(new)?);
+            if changes.is_empty() {
+                println!("No differences between {} and {}", old, new);
             }
-            
-            ConfigType::Application { name, version, debug_mode, log_level } => {
-                println!("Type: Application");
-                println!("  Name: {}", name);
-                println!("  Version: {}", version);
-                println!("  Debug Mode: {}", debug_mode);
-                println!("  Log Level: {:?}", log_level);
+            for change in changes {
+                println!("{}", change);
             }
-        }
-        println!("====================\n");
-    }
+            Ok(())
+        }),
+        ("diff", _) => Err(ConfigError::ParseError(String::from("usage: diff <old-file> <new-file>"))),
+        ("migrate", []) => Err(ConfigError::ParseError(String::from("usage: migrate <file>..."))),
+        ("migrate", files) => files.iter().try_for_each(|file| {
+            let report = Config::migrate_file(file)?;
+            println!("{}: {}", file, report);
+            Ok(())
+        }),
+        _ => return None,
+    };
+    Some(result)
 }
 
 // =============  MAIN FUNCTION =============
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(result) = run_command(&args) {
+        if let Err(e) = result {
+            eprintln!("✗ {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    
     println!("=== JSON Config Manager ===\n");
     
     // Create and save a database configuration
@@ -463,6 +2524,7 @@ fn main() {
             host: String::from("localhost"),
             port: 5432,
             username: String::from("admin"),
+            password: None,
             max_connections: 100,
         },
         metadata: ConfigMetadata {
@@ -543,13 +2605,687 @@ fn main() {
         host: String::from(""),
         port: 0,
         username: String::from(""),
+        password: None,
         max_connections: 0,
     };
     
-    match Config::validate_config(&invalid_config) {
+    match Config::validate_config(&invalid_config, Schema::builtin()) {
         Ok(()) => println!("Config is valid"),
         Err(e) => println!("Validation errors:\n{}", e),
     }
     
+    // Loading JSON, with error positions for malformed documents
+    println!("\n--- Example 6: JSON Configuration ---\n");
+    
+    let json = r#"{
+    "type": "server",
+    "bind_address": "127.0.0.1",
+    "port": 8443,
+    "ssl_enabled": true
+}"#;
+    
+    match Config::parse_json(json, "server.json") {
+        Ok(config_type) => println!("✓ Parsed JSON config: {:?}", config_type),
+        Err(e) => println!("✗ Error parsing JSON: {}", e),
+    }
+    
+    match Config::parse_json("{\n  \"type\": \"server\",\n  \"port\": 80,,\n}", "broken.json") {
+        Ok(config_type) => println!("Unexpectedly parsed: {:?}", config_type),
+        Err(e) => println!("✗ Error parsing JSON: {}", e),
+    }
+    
+    // Several sections in one file, with every section's errors reported together
+    println!("\n--- Example 7: Multi-Section Configuration ---\n");
+    
+    let deployment = "\
+[database.primary]
+host: db1.internal
+port: 5432
+username: admin
+max_connections: 200
+
+[database.replica]
+host: db2.internal
+port: 5432
+username: reader
+max_connections: 100
+
+[server]
+bind_address: 0.0.0.0
+port: 8080
+ssl_enabled: true
+";
+    
+    let deployment_path = std::env::temp_dir().join(format!("config_manager_deployment_{}.conf", std::process::id()));
+    let deployment_name = deployment_path.to_string_lossy().into_owned();
+    let loaded = fs::write(&deployment_path, deployment)
+        .map_err(|e| ConfigError::SaveFailed {
+            filename: deployment_name.clone(),
+            reason: e.to_string(),
+        })
+        .and_then(|_| ConfigSet::load_from_file(&deployment_name));
+    
+    match loaded {
+        Ok(set) => {
+            set.display();
+            if let Some(replica) = set.get("database.replica") {
+                println!("Replica: {:?}", replica);
+            }
+        }
+        Err(e) => println!("✗ Error loading config set: {}", e),
+    }
+    let _ = fs::remove_file(&deployment_path);
+    
+    let broken = "[database.primary]\nhost:\nport: 5432\nusername: admin\nmax_connections: 0\n\n[server]\nport: 8080\n";
+    match ConfigSet::parse(broken, "broken.conf") {
+        Ok(_) => println!("Config set is valid"),
+        Err(e) => println!("✗ {}", e),
+    }
+    
+    // Layering: defaults < file < APP_* environment < --set flags
+    println!("\n--- Example 8: Layered Configuration ---\n");
+    
+    match LayeredConfig::load(Some("server.conf"), std::env::vars(), &args) {
+        Ok(layered) => {
+            layered.config.display();
+            print!("{}", layered.provenance_report());
+        }
+        Err(e) => println!("✗ Error loading layered config: {}", e),
+    }
+    
+    // Hot reload: edits are picked up by polling, invalid edits are ignored
+    println!("\n--- Example 9: Hot Reload ---\n");
+    
+    // Watch a scratch copy so the demo edits never touch server.conf itself.
+    let watched = std::env::temp_dir().join(format!("config_manager_demo_{}.conf", std::process::id()));
+    let watched_name = watched.to_string_lossy().into_owned();
+    let watcher = fs::copy("server.conf", &watched)
+        .map_err(|_| ConfigError::FileNotFound(String::from("server.conf")))
+        .and_then(|_| ConfigWatcher::new(&watched_name));
+    match watcher {
+        Ok(mut watcher) => {
+            watcher.on_change(|changes, config| {
+                println!("↻ Reloaded {}:", config.metadata.filename);
+                for change in changes {
+                    println!("  {}", change);
+                }
+            });
+            
+            let original = watcher.current().to_string();
+            let edited = original.replace("port: 8080", "port: 9090");
+            for contents in [edited.as_str(), "type: server\nport: 0\n", original.as_str()] {
+                if let Err(e) = fs::write(&watched, contents) {
+                    println!("Failed to edit {}: {}", watched_name, e);
+                    break;
+                }
+                match watcher.poll() {
+                    Ok(ReloadOutcome::Unchanged) => println!("No changes detected"),
+                    Ok(ReloadOutcome::Reloaded(_)) => {}
+                    Err(e) => println!("✗ Reload rejected, keeping previous config: {}", e),
+                }
+            }
+            
+            // The same watcher, polling in the background
+            let handle = watcher.spawn(Duration::from_millis(50));
+            thread::sleep(Duration::from_millis(100));
+            println!("Live config:\n{}", handle.current());
+        }
+        Err(e) => println!("✗ Cannot watch server.conf: {}", e),
+    }
+    let _ = fs::remove_file(&watched);
+    
+    // Schema files: new config types without new Rust code
+    println!("\n--- Example 10: Schema Validation ---\n");
+    
+    match Schema::load_from_file("schema.json") {
+        Ok(cache_schema) => {
+            let mut schema = Schema::builtin().clone();
+            schema.extend(cache_schema);
+            
+            let cache_path = std::env::temp_dir().join(format!("config_manager_cache_{}.conf", std::process::id()));
+            let cache_name = cache_path.to_string_lossy().into_owned();
+            let loaded = fs::write(&cache_path, "type: cache\nhost: cache.internal\neviction: lfu\n")
+                .map_err(|e| ConfigError::SaveFailed {
+                    filename: cache_name.clone(),
+                    reason: e.to_string(),
+                })
+                .and_then(|_| Config::load_with_schema(&cache_name, &schema));
+            match loaded {
+                Ok(config) => config.display(),
+                Err(e) => println!("✗ Error loading {}: {}", cache_name, e),
+            }
+            let _ = fs::remove_file(&cache_path);
+            
+            let broken = "[cache.sessions]\nport: 70000\neviction: random\ntls_enabled: true\n\n[server]\nbind_address: nowhere\n";
+            match ConfigSet::parse_with_schema(broken, "broken.conf", &schema) {
+                Ok(_) => println!("Config set is valid"),
+                Err(e) => println!("✗ {}", e),
+            }
+        }
+        Err(e) => println!("✗ Cannot load schema.json: {}", e),
+    }
+    
+    // Upgrading a file written before schema versions existed
+    println!("\n--- Example 11: Diff and Migration ---\n");
+    
+    // A directory per run, so the backup left by one run never blocks the next.
+    let migration_dir = std::env::temp_dir().join(format!("config_manager_migration_{}", std::process::id()));
+    let legacy_name = migration_dir.join("legacy_server.conf").to_string_lossy().into_owned();
+    let migrated = fs::create_dir_all(&migration_dir)
+        .and_then(|_| fs::write(&legacy_name, "type: server\nlisten: 10.0.0.5:9090\nssl_enabled: true\n"))
+        .map_err(|e| ConfigError::SaveFailed {
+            filename: legacy_name.clone(),
+            reason: e.to_string(),
+        })
+        .and_then(|_| Config::migrate_file(&legacy_name));
+    match migrated {
+        Ok(report) => println!("{}", report),
+        Err(e) => println!("✗ Migration failed: {}", e),
+    }
+    
+    if let Err(e) = run_command(&[String::from("diff"), String::from("server.conf"), legacy_name]).unwrap_or(Ok(())) {
+        println!("✗ Diff failed: {}", e);
+    }
+    let _ = fs::remove_dir_all(&migration_dir);
+    
+    // Credentials come from references and never print in the clear
+    println!("\n--- Example 12: Secrets ---\n");
+    
+    // Kept out of the working directory so no password file is left behind.
+    let secret_path = std::env::temp_dir().join(format!("config_manager_db_password_{}.secret", std::process::id()));
+    let secure_path = std::env::temp_dir().join(format!("config_manager_secure_database_{}.conf", std::process::id()));
+    let secure_name = secure_path.to_string_lossy().into_owned();
+    let secure_config = format!(
+        "type: database\nhost: db.internal\nusername: app\npassword: ${{file:{}}}\n",
+        secret_path.display(),
+    );
+    let loaded = fs::write(&secret_path, "s3cr3t-passw0rd\n")
+        .and_then(|_| fs::write(&secure_path, secure_config))
+        .map_err(|e| ConfigError::SaveFailed {
+            filename: secure_name.clone(),
+            reason: e.to_string(),
+        })
+        .and_then(|_| Config::load_from_file(&secure_name));
+    match loaded {
+        Ok(config) => {
+            config.display();
+            println!("Debug: {:?}", config.config_type);
+            if let ConfigType::Database { password: Some(password), .. } = &config.config_type {
+                println!("Revealed on request: {} characters", password.expose().len());
+            }
+        }
+        Err(e) => println!("✗ Error loading {}: {}", secure_name, e),
+    }
+    let _ = fs::remove_file(&secure_path);
+    let _ = fs::remove_file(&secret_path);
+    
+    let missing = "type: database\nusername: app\npassword: ${env:CONFIG_MANAGER_UNSET_PASSWORD}\n";
+    match Config::parse_json(missing, "missing.conf") {
+        Ok(_) => println!("Unexpectedly resolved the secret"),
+        Err(e) => println!("✗ {}", e),
+    }
+    
     println!("\n=== All Examples Complete! ===");
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json_error(json: &str) -> String {
+        match Config::parse_json(json, "test.json") {
+            Err(ConfigError::InvalidJson { reason, .. }) => reason,
+            other => panic!("expected InvalidJson, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn parses_json_values() {
+        let value = JsonParser::parse(r#" {"a": [1, -2.5e1, true, null], "b": {"c": "x\"\\\/\n\u00e9\ud83d\ude00"}} "#).unwrap();
+        assert_eq!(
+            value,
+            JsonValue::Object(vec![
+                ("a".to_string(), JsonValue::Array(vec![
+                    JsonValue::Number(1.0),
+                    JsonValue::Number(-25.0),
+                    JsonValue::Bool(true),
+                    JsonValue::Null,
+                ])),
+                ("b".to_string(), JsonValue::Object(vec![
+                    ("c".to_string(), JsonValue::String("x\"\\/\né😀".to_string())),
+                ])),
+            ])
+        );
+
+        let mut map = HashMap::new();
+        value.flatten_into("", &mut map);
+        assert_eq!(map.get("a").map(String::as_str), Some("[1, -25, true, null]"));
+        assert_eq!(map.get("b.c").map(String::as_str), Some("x\"\\/\né😀"));
+    }
+
+    #[test]
+    fn loads_json_and_legacy_configs() {
+        let json = r#"{ "type": "database", "host": "db.local", "port": 5432, "username": "admin", "max_connections": 50 }"#;
+        let legacy = "type: database\nhost: db.local\nport: 5432\nusername: admin\nmax_connections: 50\n";
+        for contents in [json, legacy] {
+            match Config::parse_json(contents, "db.json").unwrap() {
+                ConfigType::Database { host, port, max_connections, .. } => {
+                    assert_eq!((host.as_str(), port, max_connections), ("db.local", 5432, 50));
+                }
+                other => panic!("expected a database config, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn loads_multi_section_files() {
+        let text = "# deployment\n[database.primary]\nhost: a\nport: 1\nusername: u\nmax_connections: 5\n\n[application]\nname: app\nversion: 1.2.3\ndebug_mode: no\nlog_level: info\n";
+        let json = r#"{
+            "database.primary": {"host": "a", "port": 1, "username": "u", "max_connections": 5},
+            "application": {"name": "app", "version": "1.2.3", "debug_mode": false, "log_level": "info"}
+        }"#;
+        
+        for contents in [text, json] {
+            let set = ConfigSet::parse(contents, "deploy.conf").unwrap();
+            let names: Vec<&str> = set.sections.iter().map(|section| section.name.as_str()).collect();
+            assert_eq!(names, ["database.primary", "application"]);
+            assert!(matches!(set.get("database.primary"), Some(ConfigType::Database { port: 1, .. })));
+            assert!(matches!(set.get("application"), Some(ConfigType::Application { debug_mode: false, .. })));
+            assert!(set.get("server").is_none());
+        }
+    }
+    
+    #[test]
+    fn aggregates_section_errors() {
+        let contents = "[database.primary]\nhost:\nport: 5432\nmax_connections: 0\n\n[server]\nssl_enabled: maybe\n\n[unknown]\nsize: 1\n\n[server]\n";
+        match ConfigSet::parse(contents, "broken.conf") {
+            Err(ConfigError::ValidationFailed(errors)) => assert_eq!(errors, [
+                "database.primary.host: must not be empty",
+                "database.primary.username: required field is missing",
+                "database.primary.max_connections: 0 is below the minimum of 1",
+                "server.ssl_enabled: expected true or false, found 'maybe'",
+                "unknown: Invalid value 'unknown' for field 'type': expected application, database, server",
+                "server: section is defined more than once",
+            ]),
+            other => panic!("expected ValidationFailed, got {:?}", other.map(|_| ())),
+        }
+        
+        assert!(matches!(ConfigSet::parse("port: 1\n[server]\n", "x.conf"), Err(ConfigError::ParseError(_))));
+    }
+    
+    #[test]
+    fn layers_override_in_order() {
+        let path = std::env::temp_dir().join(format!("config_manager_layers_{}.conf", std::process::id()));
+        fs::write(&path, "type: server\nbind_address: 10.0.0.1\nport: 8000\n").unwrap();
+        let filename = path.to_str().unwrap();
+        
+        let env = vec![
+            (String::from("APP_PORT"), String::from("9000")),
+            (String::from("APP_SSL_ENABLED"), String::from("yes")),
+            (String::from("HOME"), String::from("/root")),
+        ];
+        let args = vec![String::from("--set"), String::from("port=9443"), String::from("--verbose")];
+        let layered = LayeredConfig::load(Some(filename), env, &args).unwrap();
+        fs::remove_file(&path).unwrap();
+        
+        assert!(matches!(
+            layered.config.config_type,
+            ConfigType::Server { port: 9443, ssl_enabled: true, ref bind_address } if bind_address == "10.0.0.1"
+        ));
+        let layers: Vec<(&str, ConfigLayer)> = layered.provenance.iter()
+            .map(|(key, (_, provenance))| (key.as_str(), provenance.layer))
+            .collect();
+        assert_eq!(layers, [
+            ("bind_address", ConfigLayer::File),
+            ("port", ConfigLayer::CommandLine),
+            ("ssl_enabled", ConfigLayer::Environment),
+            ("type", ConfigLayer::File),
+        ]);
+        assert!(layered.provenance_report().contains("port         = 9443                 (command line: --set port=9443)"));
+    }
+    
+    #[test]
+    fn layers_fill_defaults_and_reject_bad_overrides() {
+        let env = vec![(String::from("APP_TYPE"), String::from("database"))];
+        let args = vec![String::from("--set=username=admin")];
+        let layered = LayeredConfig::load(None, env, &args).unwrap();
+        assert!(matches!(layered.config.config_type, ConfigType::Database { port: 5432, max_connections: 100, .. }));
+        assert_eq!(layered.provenance["host"].1.layer, ConfigLayer::Default);
+        assert_eq!(layered.provenance["username"].1.source, "--set username=admin");
+        
+        let args = vec![String::from("--set"), String::from("port")];
+        assert!(matches!(LayeredConfig::parse_overrides(&args), Err(ConfigError::ParseError(_))));
+        assert!(matches!(LayeredConfig::load(None, Vec::new(), &[]), Err(ConfigError::MissingField { .. })));
+    }
+    
+    #[test]
+    fn watcher_swaps_only_valid_configs() {
+        let path = std::env::temp_dir().join(format!("config_manager_watch_{}.conf", std::process::id()));
+        let filename = path.to_str().unwrap().to_string();
+        // Bump the mtime explicitly so the test does not depend on clock granularity.
+        let write = |contents: &str, seconds: u64| {
+            fs::write(&path, contents).unwrap();
+            let file = fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(seconds)).unwrap();
+        };
+        write("type: server\nbind_address: 10.0.0.1\nport: 8000\nssl_enabled: false\n", 1_000);
+        
+        let mut watcher = ConfigWatcher::new(&filename).unwrap();
+        let seen = Arc::new(RwLock::new(Vec::new()));
+        let recorder = Arc::clone(&seen);
+        watcher.on_change(move |changes, _| recorder.write().unwrap().extend(changes.iter().map(|c| c.to_string())));
+        let before = watcher.current();
+        assert_eq!(watcher.poll().unwrap(), ReloadOutcome::Unchanged);
+        
+        write("type: server\nbind_address: 10.0.0.1\nport: 9000\nssl_enabled: true\n", 2_000);
+        assert!(matches!(watcher.poll().unwrap(), ReloadOutcome::Reloaded(ref changes) if changes.len() == 2));
+        assert_eq!(*seen.read().unwrap(), ["~ port: 8000 -> 9000", "~ ssl_enabled: false -> true"]);
+        assert!(matches!(before.config_type, ConfigType::Server { port: 8000, .. }));
+        
+        write("type: server\nbind_address: 10.0.0.1\nport: 0\nssl_enabled: true\n", 3_000);
+        assert!(matches!(watcher.poll(), Err(ConfigError::ValidationFailed(_))));
+        assert!(matches!(watcher.current().config_type, ConfigType::Server { port: 9000, .. }));
+        assert_eq!(watcher.poll().unwrap(), ReloadOutcome::Unchanged);
+        assert_eq!(seen.read().unwrap().len(), 2);
+        
+        let handle = watcher.spawn(Duration::from_millis(5));
+        write("type: server\nbind_address: 10.0.0.2\nport: 9000\nssl_enabled: true\n", 4_000);
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !matches!(&handle.current().config_type, ConfigType::Server { bind_address, .. } if bind_address == "10.0.0.2") {
+            assert!(std::time::Instant::now() < deadline, "background watcher never reloaded");
+            thread::sleep(Duration::from_millis(5));
+        }
+        drop(handle);
+        
+        fs::remove_file(&path).unwrap();
+    }
+    
+    #[test]
+    fn diffs_configs_of_different_types() {
+        let server = ConfigType::Server { bind_address: String::from("localhost"), port: 80, ssl_enabled: false };
+        let database = ConfigType::Database {
+            host: String::from("localhost"),
+            port: 80,
+            username: String::from("u"),
+            password: None,
+            max_connections: 1,
+        };
+        let changes: Vec<String> = server.diff(&database).iter().map(|c| c.to_string()).collect();
+        assert_eq!(changes, [
+            "~ type: server -> database",
+            "- bind_address: localhost",
+            "- ssl_enabled: false",
+            "+ host: localhost",
+            "+ username: u",
+            "+ max_connections: 1",
+        ]);
+    }
+    
+    #[test]
+    fn reports_json_error_positions() {
+        assert_eq!(json_error("{\n  \"type\": \"server\",\n  \"port\": 80,,\n}"), "line 3, column 14: expected a string key");
+        assert_eq!(json_error("{\"port\": 08}"), "line 1, column 10: invalid number '08'");
+        assert_eq!(json_error("{\"a\": \"\\x\"}"), "line 1, column 10: invalid escape sequence '\\x'");
+        assert_eq!(json_error("{\"a\": tru }"), "line 1, column 7: invalid literal, expected 'true'");
+        assert_eq!(json_error("{\"a\": 1} x"), "line 1, column 10: unexpected 'x' after the end of the document");
+        assert_eq!(json_error("{\"a\": [1 2]}"), "line 1, column 11: expected ',' or ']' after an array element, found '2'");
+    }
+
+    #[test]
+    fn matches_patterns() {
+        let cases = [
+            ("^[a-z]+\\d{2,3}$", "abc12", true),
+            ("^[a-z]+\\d{2,3}$", "abc1234", false),
+            ("^(localhost|.*\\..*)$", "10.0.0.1", true),
+            ("^(localhost|.*\\..*)$", "nowhere", false),
+            ("colou?r", "my color", true),
+            ("^(ab)*c$", "ababc", true),
+            ("^(ab)*c$", "abac", false),
+            ("^[^ ]+$", "no spaces", false),
+            ("^(a*)*b$", &format!("{}c", "a".repeat(5_000)), false),
+            ("^(a|a)*b$", &format!("{}b", "a".repeat(5_000)), true),
+        ];
+        for (source, text, expected) in cases {
+            assert_eq!(Pattern::new(source).unwrap().is_match(text), expected, "{} on {:?}", source, text);
+        }
+        
+        assert!(Pattern::new("(ab").is_err());
+        assert!(Pattern::new("*a").is_err());
+        assert!(Pattern::new("(a{100}){100}{100}").is_err());
+    }
+    
+    #[test]
+    fn schema_reports_every_violation_with_its_path() {
+        let fields: HashMap<String, String> = [
+            ("type", "application"),
+            ("name", ""),
+            ("version", "1.0"),
+            ("debug_mode", "sometimes"),
+            ("log_level", "verbose"),
+        ].iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        
+        match Schema::builtin().validate(&fields, "services.api") {
+            Err(ConfigError::ValidationFailed(errors)) => assert_eq!(errors, [
+                "services.api.name: must not be empty",
+                "services.api.version: '1.0' does not match the pattern /^[^.]+\\.[^.]+\\.[^.]+$/",
+                "services.api.debug_mode: expected true or false, found 'sometimes'",
+                "services.api.log_level: 'verbose' is not one of: debug, info, warning, error",
+            ]),
+            other => panic!("expected ValidationFailed, got {:?}", other),
+        }
+    }
+    
+    #[test]
+    fn schema_files_define_new_types() {
+        let schema = Schema::from_json(include_str!("../schema.json"), "schema.json").unwrap();
+        let fields = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        };
+        
+        let valid = schema.validate(&fields(&[("type", "cache"), ("tls_enabled", "yes"), ("cert_path", "/etc/c.pem"), ("extra", "x")]), "cache").unwrap();
+        match Config::parse_fields(&valid, &schema).unwrap() {
+            ConfigType::Custom { kind, fields, .. } => {
+                assert_eq!(kind, "cache");
+                assert_eq!(fields.get("port").map(String::as_str), Some("6379"));
+                assert_eq!(fields.get("tls_enabled").map(String::as_str), Some("true"));
+                assert!(!fields.contains_key("extra"));
+            }
+            other => panic!("expected a custom config, got {:?}", other),
+        }
+        
+        match schema.validate(&fields(&[("type", "cache"), ("tls_enabled", "true"), ("max_memory_mb", "8")]), "cache") {
+            Err(ConfigError::ValidationFailed(errors)) => assert_eq!(errors, [
+                "cache.max_memory_mb: 8 is below the minimum of 16",
+                "cache.cert_path: required when tls_enabled is true",
+            ]),
+            other => panic!("expected ValidationFailed, got {:?}", other),
+        }
+        
+        let bad_default = r#"{"t": {"fields": {"port": {"type": "integer", "max": 10, "default": 11}}}}"#;
+        assert!(matches!(Schema::from_json(bad_default, "s.json"), Err(ConfigError::InvalidJson { .. })));
+        let bad_rule = r#"{"t": {"fields": {}, "rules": [{"when": "a", "equals": 1, "requires": []}]}}"#;
+        assert!(matches!(Schema::from_json(bad_rule, "s.json"), Err(ConfigError::InvalidJson { .. })));
+    }
+
+    #[test]
+    fn upgrades_old_fields_step_by_step() {
+        let mut fields: HashMap<String, String> = [("type", "server"), ("listen", "[::1]:8443"), ("ssl_enabled", "no")]
+            .iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let (from, applied) = Migration::upgrade(&mut fields).unwrap();
+        assert_eq!((from, applied.len()), (1, 2));
+        assert_eq!(fields.get("bind_address").map(String::as_str), Some("[::1]"));
+        assert_eq!(fields.get("port").map(String::as_str), Some("8443"));
+        assert!(!fields.contains_key("listen"));
+        
+        let mut fields: HashMap<String, String> = [("type", "database"), ("max_conn", "7"), ("schema_version", "2")]
+            .iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        assert_eq!(Migration::upgrade(&mut fields).unwrap(), (2, vec![MIGRATIONS[1].description]));
+        assert_eq!(fields.get("max_connections").map(String::as_str), Some("7"));
+        
+        let mut clash: HashMap<String, String> = [("type", "server"), ("listen", "a:1"), ("port", "2")]
+            .iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        assert!(matches!(Migration::upgrade(&mut clash), Err(ConfigError::MigrationFailed { version: 2, .. })));
+        
+        let mut newer: HashMap<String, String> = [("type", "server"), ("schema_version", "99")]
+            .iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        assert!(matches!(Migration::upgrade(&mut newer), Err(ConfigError::MigrationFailed { version: 99, .. })));
+        
+        assert_eq!(MIGRATIONS.last().map(|migration| migration.version), Some(CURRENT_SCHEMA_VERSION));
+    }
+    
+    #[test]
+    fn migrates_files_with_a_backup() {
+        let path = std::env::temp_dir().join(format!("config_manager_migrate_{}.conf", std::process::id()));
+        let filename = path.to_str().unwrap();
+        let old = "type: database\nhost: db\nport: 5432\nusername: admin\nmax_conn: 20\n";
+        fs::write(&path, old).unwrap();
+        
+        let report = Config::migrate_file(filename).unwrap();
+        let backup = report.backup.clone().unwrap();
+        assert_eq!((report.from, report.to), (1, CURRENT_SCHEMA_VERSION));
+        assert_eq!(fs::read_to_string(&backup).unwrap(), old);
+        
+        let migrated = fs::read_to_string(&path).unwrap();
+        assert!(migrated.starts_with(&format!("schema_version: {}\n", CURRENT_SCHEMA_VERSION)));
+        assert!(migrated.contains("max_connections: 20"));
+        assert!(Config::migrate_file(filename).unwrap().backup.is_none());
+        
+        // Old files load directly too, and diff against the migrated one cleanly.
+        fs::write(&backup, old).unwrap();
+        let before = Config::load_from_file(&backup).unwrap();
+        let after = Config::load_from_file(filename).unwrap();
+        assert!(before.diff(&after).is_empty());
+        
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&backup).unwrap();
+    }
+    
+    #[test]
+    fn migration_keeps_fields_the_schema_does_not_describe() {
+        let path = std::env::temp_dir().join(format!("config_manager_migrate_extra_{}.conf", std::process::id()));
+        let filename = path.to_str().unwrap();
+        let backup = format!("{}.v1.bak", filename);
+        
+        fs::write(&path, "type: server\nlisten: 10.0.0.1:80\nowner: ops team\n").unwrap();
+        Config::migrate_file(filename).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("schema_version: {}\ntype: server\nowner: ops team\nbind_address: 10.0.0.1\nport: 80\n", CURRENT_SCHEMA_VERSION),
+        );
+        fs::remove_file(&backup).unwrap();
+        
+        // Types only a schema file knows about migrate too.
+        fs::write(&path, "type: cache\nhost: cache.internal\n").unwrap();
+        Config::migrate_file(filename).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("schema_version: {}\ntype: cache\nhost: cache.internal\n", CURRENT_SCHEMA_VERSION),
+        );
+        
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&backup).unwrap();
+    }
+    
+    #[test]
+    fn migrates_json_files_as_json() {
+        let path = std::env::temp_dir().join(format!("config_manager_migrate_{}.json", std::process::id()));
+        let filename = path.to_str().unwrap();
+        let old = "{\"type\": \"server\", \"listen\": \"10.0.0.1:80\", \"ssl_enabled\": true, \"labels\": {\"team\": \"infra\"}}";
+        fs::write(&path, old).unwrap();
+        let backup = format!("{}.v1.bak", filename);
+        fs::write(&backup, "earlier backup").unwrap();
+        
+        // An existing backup is kept and the file is left alone.
+        assert!(matches!(Config::migrate_file(filename), Err(ConfigError::SaveFailed { .. })));
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "earlier backup");
+        assert_eq!(fs::read_to_string(&path).unwrap(), old);
+        
+        fs::remove_file(&backup).unwrap();
+        let report = Config::migrate_file(filename).unwrap();
+        assert_eq!(report.backup.as_deref(), Some(backup.as_str()));
+        assert_eq!(fs::read_to_string(&backup).unwrap(), old);
+        
+        let migrated = fs::read_to_string(&path).unwrap();
+        let JsonValue::Object(members) = JsonParser::parse(&migrated).unwrap() else {
+            panic!("migrated file is not a JSON object: {}", migrated);
+        };
+        assert!(members.contains(&(String::from("bind_address"), JsonValue::String(String::from("10.0.0.1")))));
+        assert!(members.contains(&(String::from("port"), JsonValue::Number(80.0))));
+        assert!(members.contains(&(String::from("ssl_enabled"), JsonValue::Bool(true))));
+        let labels = JsonValue::Object(vec![(String::from("team"), JsonValue::String(String::from("infra")))]);
+        assert!(members.contains(&(String::from("labels"), labels)));
+        assert!(Config::load_from_file(filename).is_ok());
+        
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&backup).unwrap();
+    }
+
+    #[test]
+    fn secrets_resolve_at_load_and_stay_redacted() {
+        let secret_path = std::env::temp_dir().join(format!("config_manager_secret_{}", std::process::id()));
+        fs::write(&secret_path, "hunter2\n").unwrap();
+        let reference = format!("${{file:{}}}", secret_path.display());
+        let contents = format!("type: database\nhost: db\nusername: app\npassword: {}\n", reference);
+        
+        let config = Config {
+            config_type: Config::parse_json(&contents, "db.conf").unwrap(),
+            metadata: ConfigMetadata {
+                filename: String::from("db.conf"),
+                last_modified: None,
+                is_valid: true,
+            },
+        };
+        let ConfigType::Database { password: Some(password), .. } = &config.config_type else {
+            panic!("expected a database config with a password");
+        };
+        assert_eq!(password.expose(), "hunter2");
+        
+        let shown = [
+            config.to_string(),
+            format!("{:?}", config.config_type),
+            password.to_string(),
+        ];
+        for text in &shown {
+            assert!(!text.contains("hunter2"), "leaked in {:?}", text);
+            assert!(text.contains("[redacted]"), "not redacted in {:?}", text);
+        }
+        
+        // Saving keeps the reference, never the resolved value.
+        let saved_path = std::env::temp_dir().join(format!("config_manager_secret_{}.conf", std::process::id()));
+        config.save_to_file(saved_path.to_str().unwrap()).unwrap();
+        let saved = fs::read_to_string(&saved_path).unwrap();
+        assert!(saved.contains(&format!("password: {}", reference)) && !saved.contains("hunter2"));
+        
+        let changed = Config::parse_json(&contents.replace(&reference, "another"), "db.conf").unwrap();
+        let changes: Vec<String> = config.config_type.diff(&changed).iter().map(|c| c.to_string()).collect();
+        assert_eq!(changes, ["~ password: [redacted] -> [redacted]"]);
+        
+        fs::remove_file(&secret_path).unwrap();
+        fs::remove_file(&saved_path).unwrap();
+    }
+    
+    #[test]
+    fn secret_errors_do_not_echo_values() {
+        let unset = "type: database\nusername: app\npassword: ${env:CONFIG_MANAGER_TEST_UNSET}\n";
+        match Config::parse_json(unset, "db.conf") {
+            Err(e @ ConfigError::SecretUnavailable { .. }) => assert_eq!(
+                e.to_string(),
+                "Cannot resolve secret ${env:CONFIG_MANAGER_TEST_UNSET}: the environment variable is not set"
+            ),
+            other => panic!("expected SecretUnavailable, got {:?}", other),
+        }
+        
+        let malformed = "type: database\nusername: app\npassword: ${vault:db\n";
+        match Config::parse_json(malformed, "db.conf") {
+            Err(ConfigError::ValidationFailed(errors)) => assert_eq!(errors, [
+                "database.password: invalid secret reference, expected ${env:NAME} or ${file:PATH}",
+            ]),
+            other => panic!("expected ValidationFailed, got {:?}", other),
+        }
+        
+        let args = vec![String::from("--set"), String::from("password=hunter2")];
+        let env = vec![(String::from("APP_TYPE"), String::from("database")), (String::from("APP_USERNAME"), String::from("app"))];
+        let layered = LayeredConfig::load(None, env, &args).unwrap();
+        assert!(!layered.provenance_report().contains("hunter2"));
+        assert!(matches!(&layered.config.config_type, ConfigType::Database { password: Some(p), .. } if p.expose() == "hunter2"));
+    }
+}
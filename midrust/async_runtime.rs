@@ -1,82 +1,332 @@
 use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll, Waker};
-use std::sync::{Arc, Mutex};
-use std::collections::{VecDeque, HashMap};
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::collections::{BinaryHeap, VecDeque, HashMap};
+use std::cmp::Reverse;
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::marker::PhantomData;
+use std::thread;
 use std::time::{Duration, Instant};
 use std::io::{self, Read, Write};
 use std::net::{TcpListener, TcpStream, SocketAddr};
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::fs::File;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
 
 thread_local! {
-    static REACTOR: RefCell<Option<Rc<Reactor>>> = RefCell::new(None);
+    static CURRENT: RefCell<Option<Executor>> = const { RefCell::new(None) };
+    // The executor (by address) and worker index this thread runs, if any.
+    static WORKER: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
 }
 
+fn current_executor() -> Executor {
+    CURRENT.with(|c| {
+        c.borrow()
+            .clone()
+            .expect("must be called from a task running on an Executor")
+    })
+}
+
+fn current_reactor() -> Arc<Reactor> {
+    current_executor().reactor().clone()
+}
+
+// Tasks only point back weakly: the queues and the reactor own the tasks,
+// so a strong reference here would keep a dropped executor alive forever.
 struct Task {
-    future: Mutex<Pin<Box<dyn Future<Output = ()> + Send>>>,
+    future: Mutex<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+    executor: Weak<Shared>,
+    scheduled: AtomicBool,
+    cancelled: AtomicBool,
 }
 
 impl Task {
-    fn new(future: impl Future<Output = ()> + Send + 'static) -> Arc<Self> {
+    fn new(future: impl Future<Output = ()> + Send + 'static, executor: &Executor) -> Arc<Self> {
         Arc::new(Task {
-            future: Mutex::new(Box::pin(future)),
+            future: Mutex::new(Some(Box::pin(future))),
+            executor: Arc::downgrade(&executor.shared),
+            scheduled: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
         })
     }
 
+    // Ready only on the poll that finishes the task; a stale wake of a
+    // finished task stays Pending. A cancelled task finishes by dropping
+    // its future instead of polling it.
     fn poll(self: &Arc<Self>, cx: &mut Context) -> Poll<()> {
-        let mut future = self.future.lock().unwrap();
-        future.as_mut().poll(cx)
+        let mut slot = self.future.lock().unwrap();
+        let Some(future) = slot.as_mut() else {
+            return Poll::Pending;
+        };
+
+        if self.cancelled.load(Ordering::SeqCst) {
+            let future = slot.take();
+            drop(slot);
+            drop(future);
+            return Poll::Ready(());
+        }
+
+        let poll = future.as_mut().poll(cx);
+        if poll.is_ready() {
+            *slot = None;
+        }
+        poll
+    }
+
+    // A wake after the executor is gone does nothing; the task was dropped
+    // with it.
+    fn schedule(self: &Arc<Self>) {
+        if !self.scheduled.swap(true, Ordering::SeqCst) {
+            if let Some(shared) = self.executor.upgrade() {
+                Executor { shared }.push(self.clone());
+            }
+        }
+    }
+
+    // Takes effect at the task's next poll, which may be running right now.
+    fn abort(self: &Arc<Self>) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.schedule();
     }
 }
 
-struct Executor {
-    queue: Arc<Mutex<VecDeque<Arc<Task>>>>,
+// Tasks woken on a worker go to that worker's deque; everything else goes
+// through the injector. Idle workers steal half of a busy worker's deque.
+struct Shared {
+    injector: Mutex<VecDeque<Arc<Task>>>,
+    locals: Vec<Mutex<VecDeque<Arc<Task>>>>,
     reactor: Arc<Reactor>,
+    active: AtomicUsize,
+    // Held by the one idle worker blocked in the reactor; the others wait
+    // on `unparked`.
+    driver: Mutex<()>,
+    parked: Mutex<()>,
+    unparked: Condvar,
+    shutdown: AtomicBool,
 }
 
+#[derive(Clone)]
+struct Executor {
+    shared: Arc<Shared>,
+}
+
+// How many task polls a busy worker makes between non-blocking reactor
+// polls, so I/O and timers are not starved.
+const REACTOR_POLL_INTERVAL: usize = 61;
+
 impl Executor {
     fn new() -> Self {
+        let workers = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        Self::with_workers(workers)
+    }
+
+    fn with_workers(workers: usize) -> Self {
+        assert!(workers > 0, "an Executor needs at least one worker");
         Executor {
-            queue: Arc::new(Mutex::new(VecDeque::new())),
-            reactor: Arc::new(Reactor::new()),
+            shared: Arc::new(Shared {
+                injector: Mutex::new(VecDeque::new()),
+                locals: (0..workers).map(|_| Mutex::new(VecDeque::new())).collect(),
+                reactor: Arc::new(Reactor::new()),
+                active: AtomicUsize::new(0),
+                driver: Mutex::new(()),
+                parked: Mutex::new(()),
+                unparked: Condvar::new(),
+                shutdown: AtomicBool::new(false),
+            }),
         }
     }
 
+    fn reactor(&self) -> &Arc<Reactor> {
+        &self.shared.reactor
+    }
+
+    // Safe to call from any thread, inside the runtime or not. A panic in
+    // the future stops the whole run; the free `spawn` captures it instead.
     fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) {
-        let task = Task::new(future);
-        self.queue.lock().unwrap().push_back(task);
+        self.spawn_task(future);
     }
 
+    fn spawn_task(&self, future: impl Future<Output = ()> + Send + 'static) -> Arc<Task> {
+        self.shared.active.fetch_add(1, Ordering::SeqCst);
+        let task = Task::new(future, self);
+        task.schedule();
+        task
+    }
+
+    // Runs until every spawned task has finished.
     fn run(&self) {
-        loop {
-            let task = {
-                let mut queue = self.queue.lock().unwrap();
-                if queue.is_empty() {
-                    break;
-                }
-                queue.pop_front()
-            };
-
-            if let Some(task) = task {
-                let waker = self.create_waker(task.clone());
-                let mut cx = Context::from_waker(&waker);
-                
-                match task.poll(&mut cx) {
-                    Poll::Ready(()) => {},
-                    Poll::Pending => {
-                        self.reactor.register_task(task);
+        self.run_workers(&|| self.shared.active.load(Ordering::SeqCst) == 0);
+    }
+
+    // Runs `future` to completion on the workers, along with any other
+    // tasks, and returns its output. Tasks still pending afterwards stay
+    // queued for the next `run` or `block_on`.
+    fn block_on<F>(&self, future: F) -> F::Output
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let output = Arc::new(Mutex::new(None));
+        let slot = output.clone();
+        self.spawn(async move {
+            let value = future.await;
+            *slot.lock().unwrap() = Some(value);
+        });
+
+        self.run_workers(&|| output.lock().unwrap().is_some());
+        let value = output.lock().unwrap().take();
+        value.expect("block_on finished without an output")
+    }
+
+    // The calling thread becomes worker 0; the rest get their own threads.
+    fn run_workers(&self, done: &(dyn Fn() -> bool + Sync)) {
+        self.shared.shutdown.store(false, Ordering::SeqCst);
+        thread::scope(|scope| {
+            for index in 1..self.shared.locals.len() {
+                scope.spawn(move || self.worker_loop(index, done));
+            }
+            self.worker_loop(0, done);
+        });
+    }
+
+    fn worker_loop(&self, index: usize, done: &dyn Fn() -> bool) {
+        let previous = CURRENT.with(|c| c.replace(Some(self.clone())));
+        let previous_worker = WORKER.with(|w| w.replace(Some((self.id(), index))));
+        // A panicking task must not leave the other workers parked forever.
+        let _stop_on_panic = ShutdownOnPanic(self);
+
+        let mut ticks = 0usize;
+        while !self.shared.shutdown.load(Ordering::SeqCst) {
+            if done() {
+                self.shutdown();
+                break;
+            }
+
+            match self.next_task(index) {
+                Some(task) => {
+                    self.poll_task(task);
+                    ticks += 1;
+                    if ticks.is_multiple_of(REACTOR_POLL_INTERVAL) {
+                        if let Ok(driver) = self.shared.driver.try_lock() {
+                            self.reactor().poll_events(|| false);
+                            self.hand_off_driver(driver);
+                        }
                     }
                 }
+                None => self.park(),
+            }
+        }
+
+        WORKER.with(|w| w.set(previous_worker));
+        CURRENT.with(|c| *c.borrow_mut() = previous);
+    }
+
+    fn next_task(&self, index: usize) -> Option<Arc<Task>> {
+        if let Some(task) = self.shared.locals[index].lock().unwrap().pop_front() {
+            return Some(task);
+        }
+        if let Some(task) = self.shared.injector.lock().unwrap().pop_front() {
+            return Some(task);
+        }
+        self.steal(index)
+    }
+
+    fn steal(&self, index: usize) -> Option<Arc<Task>> {
+        let locals = &self.shared.locals;
+        for offset in 1..locals.len() {
+            let mut victim = locals[(index + offset) % locals.len()].lock().unwrap();
+            let count = victim.len().div_ceil(2);
+            if count == 0 {
+                continue;
             }
+            let at = victim.len() - count;
+            let mut stolen = victim.split_off(at);
+            drop(victim);
+
+            let task = stolen.pop_front();
+            locals[index].lock().unwrap().extend(stolen);
+            return task;
+        }
+        None
+    }
+
+    fn has_work(&self) -> bool {
+        !self.shared.injector.lock().unwrap().is_empty()
+            || self.shared.locals.iter().any(|local| !local.lock().unwrap().is_empty())
+    }
+
+    // One idle worker blocks in the reactor; the rest sleep until woken.
+    fn park(&self) {
+        if let Ok(driver) = self.shared.driver.try_lock() {
+            self.reactor().poll_events(|| {
+                !self.has_work() && !self.shared.shutdown.load(Ordering::SeqCst)
+            });
+            self.hand_off_driver(driver);
+            return;
+        }
+
+        let parked = self.shared.parked.lock().unwrap();
+        // The driver hands off under `parked`, so a role freed since the
+        // `try_lock` above is seen here rather than missed while asleep.
+        if self.has_work()
+            || self.shared.shutdown.load(Ordering::SeqCst)
+            || self.shared.driver.try_lock().is_ok()
+        {
+            return;
+        }
+        drop(self.shared.unparked.wait(parked).unwrap());
+    }
+
+    // A driver leaving to run tasks wakes a parked worker to take over the
+    // reactor, so timers and I/O are not left unwatched.
+    fn hand_off_driver(&self, driver: MutexGuard<()>) {
+        drop(driver);
+        drop(self.shared.parked.lock().unwrap());
+        self.shared.unparked.notify_one();
+    }
+
+    fn push(&self, task: Arc<Task>) {
+        let local = WORKER.with(|w| w.get())
+            .filter(|(id, _)| *id == self.id())
+            .map(|(_, index)| index);
+        match local {
+            Some(index) => self.shared.locals[index].lock().unwrap().push_back(task),
+            None => self.shared.injector.lock().unwrap().push_back(task),
+        }
+        self.unpark_one();
+    }
+
+    fn unpark_one(&self) {
+        drop(self.shared.parked.lock().unwrap());
+        self.shared.unparked.notify_one();
+        self.reactor().notify();
+    }
+
+    fn shutdown(&self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        drop(self.shared.parked.lock().unwrap());
+        self.shared.unparked.notify_all();
+        self.reactor().notify();
+    }
+
+    fn id(&self) -> usize {
+        Arc::as_ptr(&self.shared) as usize
+    }
 
-            self.reactor.poll_events();
+    fn poll_task(&self, task: Arc<Task>) {
+        task.scheduled.store(false, Ordering::SeqCst);
+        let waker = self.create_waker(task.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        if task.poll(&mut cx).is_ready() {
+            self.shared.active.fetch_sub(1, Ordering::SeqCst);
         }
     }
 
     fn create_waker(&self, task: Arc<Task>) -> Waker {
-        let queue = self.queue.clone();
         let raw_waker = {
             use std::task::RawWaker;
             use std::task::RawWakerVTable;
@@ -88,21 +338,13 @@ impl Executor {
             
             unsafe fn wake(data: *const ()) {
                 let task = Arc::from_raw(data as *const Task);
-                REACTOR.with(|r| {
-                    if let Some(reactor) = r.borrow().as_ref() {
-                        reactor.wake_task(task.clone());
-                    }
-                });
+                task.schedule();
             }
             
             unsafe fn wake_by_ref(data: *const ()) {
                 let task = Arc::from_raw(data as *const Task);
-                REACTOR.with(|r| {
-                    if let Some(reactor) = r.borrow().as_ref() {
-                        reactor.wake_task(task.clone());
-                    }
-                });
-                Arc::increment_strong_count(data as *const Task);
+                task.schedule();
+                std::mem::forget(task);
             }
             
             unsafe fn drop(data: *const ()) {
@@ -119,65 +361,361 @@ impl Executor {
     }
 }
 
+// Pending tasks are owned by the queues and by wakers in the reactor, and
+// their futures may in turn hold the reactor. Dropping them all here frees
+// the tasks, and with them the reactor and its file descriptors.
+impl Drop for Shared {
+    fn drop(&mut self) {
+        let timers = std::mem::take(&mut self.reactor.timers.lock().unwrap().wakers);
+        let io_wakers: Vec<Waker> = self.reactor.io_sources.lock().unwrap()
+            .values_mut()
+            .flat_map(|source| [source.reader.take(), source.writer.take()])
+            .flatten()
+            .collect();
+        // Outside the locks: dropping a future cancels its timers and I/O.
+        drop(timers);
+        drop(io_wakers);
+    }
+}
+
+struct ShutdownOnPanic<'a>(&'a Executor);
+
+impl Drop for ShutdownOnPanic<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.shutdown();
+        }
+    }
+}
+
+// Linux epoll, declared by hand to stay free of dependencies.
+mod sys {
+    pub const EPOLL_CLOEXEC: i32 = 0o2000000;
+    pub const EPOLL_CTL_ADD: i32 = 1;
+    pub const EPOLL_CTL_DEL: i32 = 2;
+    pub const EPOLL_CTL_MOD: i32 = 3;
+    pub const EPOLLIN: u32 = 0x001;
+    pub const EPOLLOUT: u32 = 0x004;
+    pub const EPOLLERR: u32 = 0x008;
+    pub const EPOLLHUP: u32 = 0x010;
+    pub const EPOLLRDHUP: u32 = 0x2000;
+    pub const EPOLLONESHOT: u32 = 1 << 30;
+    pub const EFD_CLOEXEC: i32 = 0o2000000;
+    pub const EFD_NONBLOCK: i32 = 0o4000;
+
+    // The kernel packs this struct on x86_64 only.
+    #[cfg_attr(target_arch = "x86_64", repr(C, packed))]
+    #[cfg_attr(not(target_arch = "x86_64"), repr(C))]
+    #[derive(Clone, Copy)]
+    pub struct EpollEvent {
+        pub events: u32,
+        pub data: u64,
+    }
+
+    extern "C" {
+        pub fn epoll_create1(flags: i32) -> i32;
+        pub fn epoll_ctl(epfd: i32, op: i32, fd: i32, event: *mut EpollEvent) -> i32;
+        pub fn epoll_wait(epfd: i32, events: *mut EpollEvent, maxevents: i32, timeout: i32) -> i32;
+        pub fn eventfd(initval: u32, flags: i32) -> i32;
+    }
+}
+
+fn cvt(result: i32) -> io::Result<i32> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+// An epoll instance plus an eventfd that lets other threads interrupt
+// `epoll_wait`.
+struct Poller {
+    epoll: OwnedFd,
+    wakeup: File,
+}
+
+impl Poller {
+    fn new() -> io::Result<Self> {
+        let epoll = unsafe { OwnedFd::from_raw_fd(cvt(sys::epoll_create1(sys::EPOLL_CLOEXEC))?) };
+        let wakeup = unsafe {
+            File::from_raw_fd(cvt(sys::eventfd(0, sys::EFD_CLOEXEC | sys::EFD_NONBLOCK))?)
+        };
+
+        let poller = Poller { epoll, wakeup };
+        poller.ctl(sys::EPOLL_CTL_ADD, poller.wakeup.as_raw_fd(), sys::EPOLLIN)?;
+        Ok(poller)
+    }
+
+    fn ctl(&self, op: i32, fd: RawFd, events: u32) -> io::Result<()> {
+        let mut event = sys::EpollEvent { events, data: fd as u64 };
+        cvt(unsafe { sys::epoll_ctl(self.epoll.as_raw_fd(), op, fd, &mut event) })?;
+        Ok(())
+    }
+
+    // A timeout of `None` blocks until an event arrives.
+    fn wait(&self, events: &mut [sys::EpollEvent], timeout: Option<Duration>) -> io::Result<usize> {
+        let timeout_ms = match timeout {
+            // Round up so a timer is never woken a little early and spun on.
+            Some(timeout) => timeout.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+
+        let n = unsafe {
+            sys::epoll_wait(self.epoll.as_raw_fd(), events.as_mut_ptr(), events.len() as i32, timeout_ms)
+        };
+        match cvt(n) {
+            Ok(n) => Ok(n as usize),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn notify(&self) {
+        // A full counter already means a wakeup is pending.
+        let _ = (&self.wakeup).write(&1u64.to_ne_bytes());
+    }
+
+    fn drain(&self) {
+        let mut buf = [0u8; 8];
+        let _ = (&self.wakeup).read(&mut buf);
+    }
+
+    fn is_wakeup(&self, fd: RawFd) -> bool {
+        fd == self.wakeup.as_raw_fd()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Interest {
+    Readable,
+    Writable,
+}
+
+// The tasks waiting on one fd. It is armed EPOLLONESHOT and re-armed for
+// whichever wakers are still waiting after each event.
+#[derive(Default)]
+struct IoSource {
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+    added: bool,
+}
+
+impl IoSource {
+    fn events(&self) -> u32 {
+        let mut events = 0;
+        if self.reader.is_some() {
+            events |= sys::EPOLLIN | sys::EPOLLRDHUP;
+        }
+        if self.writer.is_some() {
+            events |= sys::EPOLLOUT;
+        }
+        events
+    }
+}
+
+type TimerId = u64;
+
+// Pending timers in a min-heap on deadline. Cancelling only forgets the
+// waker; the heap entry is skipped when it surfaces, and the heap is
+// compacted once most of it is dead.
+struct TimerQueue {
+    heap: BinaryHeap<Reverse<(Instant, TimerId)>>,
+    wakers: HashMap<TimerId, Waker>,
+    next_id: TimerId,
+}
+
+impl TimerQueue {
+    fn new() -> Self {
+        TimerQueue {
+            heap: BinaryHeap::new(),
+            wakers: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn insert(&mut self, deadline: Instant, waker: Waker) -> TimerId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.heap.push(Reverse((deadline, id)));
+        self.wakers.insert(id, waker);
+        id
+    }
+
+    // Returns false if the timer already fired or was cancelled.
+    fn update_waker(&mut self, id: TimerId, waker: &Waker) -> bool {
+        match self.wakers.get_mut(&id) {
+            Some(current) => {
+                if !current.will_wake(waker) {
+                    *current = waker.clone();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn cancel(&mut self, id: TimerId) {
+        self.wakers.remove(&id);
+        if self.heap.len() > 64 && self.heap.len() > 2 * self.wakers.len() {
+            let wakers = &self.wakers;
+            self.heap.retain(|Reverse((_, id))| wakers.contains_key(id));
+        }
+    }
+
+    fn next_deadline(&mut self) -> Option<Instant> {
+        while let Some(Reverse((deadline, id))) = self.heap.peek() {
+            if self.wakers.contains_key(id) {
+                return Some(*deadline);
+            }
+            self.heap.pop();
+        }
+        None
+    }
+
+    fn expire(&mut self, now: Instant) -> Vec<Waker> {
+        let mut due = Vec::new();
+        while let Some(Reverse((deadline, id))) = self.heap.peek() {
+            if *deadline > now {
+                break;
+            }
+            if let Some(waker) = self.wakers.remove(id) {
+                due.push(waker);
+            }
+            self.heap.pop();
+        }
+        due
+    }
+
+    fn len(&self) -> usize {
+        self.wakers.len()
+    }
+}
+
 struct Reactor {
-    timers: Mutex<Vec<(Instant, Arc<Task>)>>,
-    io_sources: Mutex<HashMap<RawFd, Arc<Task>>>,
-    ready_queue: Mutex<VecDeque<Arc<Task>>>,
+    poller: Poller,
+    timers: Mutex<TimerQueue>,
+    io_sources: Mutex<HashMap<RawFd, IoSource>>,
+    sleeping: AtomicBool,
 }
 
 impl Reactor {
     fn new() -> Self {
         Reactor {
-            timers: Mutex::new(Vec::new()),
+            poller: Poller::new().expect("failed to create the epoll instance"),
+            timers: Mutex::new(TimerQueue::new()),
             io_sources: Mutex::new(HashMap::new()),
-            ready_queue: Mutex::new(VecDeque::new()),
+            sleeping: AtomicBool::new(false),
+        }
+    }
+
+    // Interrupts a blocked `poll_events`; cheap when nobody is sleeping.
+    fn notify(&self) {
+        if self.sleeping.load(Ordering::SeqCst) {
+            self.poller.notify();
         }
     }
 
-    fn register_task(&self, task: Arc<Task>) {
-        self.ready_queue.lock().unwrap().push_back(task);
+    fn register_timer(&self, deadline: Instant, waker: Waker) -> TimerId {
+        let id = self.timers.lock().unwrap().insert(deadline, waker);
+        // A sleeping reactor may be waiting for a later deadline.
+        self.notify();
+        id
     }
 
-    fn wake_task(&self, task: Arc<Task>) {
-        self.ready_queue.lock().unwrap().push_back(task);
+    fn cancel_timer(&self, id: TimerId) {
+        self.timers.lock().unwrap().cancel(id);
     }
 
-    fn register_timer(&self, deadline: Instant, task: Arc<Task>) {
-        let mut timers = self.timers.lock().unwrap();
-        timers.push((deadline, task));
-        timers.sort_by_key(|(d, _)| *d);
+    fn register_io(&self, fd: RawFd, interest: Interest, waker: Waker) -> io::Result<()> {
+        let mut sources = self.io_sources.lock().unwrap();
+        let source = sources.entry(fd).or_default();
+        match interest {
+            Interest::Readable => source.reader = Some(waker),
+            Interest::Writable => source.writer = Some(waker),
+        }
+
+        let op = if source.added { sys::EPOLL_CTL_MOD } else { sys::EPOLL_CTL_ADD };
+        self.poller.ctl(op, fd, source.events() | sys::EPOLLONESHOT)?;
+        source.added = true;
+        Ok(())
     }
 
-    fn register_io(&self, fd: RawFd, task: Arc<Task>) {
-        self.io_sources.lock().unwrap().insert(fd, task);
+    fn deregister_io(&self, fd: RawFd) {
+        if let Some(source) = self.io_sources.lock().unwrap().remove(&fd) {
+            if source.added {
+                let _ = self.poller.ctl(sys::EPOLL_CTL_DEL, fd, 0);
+            }
+        }
     }
 
-    fn poll_events(&self) {
-        let now = Instant::now();
-        let mut timers = self.timers.lock().unwrap();
-        
-        while let Some((deadline, _)) = timers.first() {
-            if *deadline <= now {
-                if let Some((_, task)) = timers.remove(0) {
-                    self.wake_task(task);
+    // Waits for I/O readiness or the next timer, whichever comes first, then
+    // wakes the tasks concerned. `idle` is checked after announcing that we
+    // are about to sleep, so a concurrent wake either shows up in it or
+    // interrupts the wait.
+    fn poll_events(&self, idle: impl FnOnce() -> bool) {
+        self.sleeping.store(true, Ordering::SeqCst);
+        let timeout = if idle() {
+            self.timers.lock().unwrap()
+                .next_deadline()
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+        } else {
+            Some(Duration::ZERO)
+        };
+
+        let mut events = [sys::EpollEvent { events: 0, data: 0 }; 64];
+        let n = self.poller.wait(&mut events, timeout).expect("epoll_wait failed");
+        self.sleeping.store(false, Ordering::SeqCst);
+
+        let mut wakers = Vec::new();
+        {
+            let mut sources = self.io_sources.lock().unwrap();
+            for event in &events[..n] {
+                let (flags, fd) = (event.events, event.data as RawFd);
+                if self.poller.is_wakeup(fd) {
+                    self.poller.drain();
+                    continue;
+                }
+                let Some(source) = sources.get_mut(&fd) else {
+                    continue;
+                };
+
+                let failed = flags & (sys::EPOLLERR | sys::EPOLLHUP) != 0;
+                if failed || flags & (sys::EPOLLIN | sys::EPOLLRDHUP) != 0 {
+                    wakers.extend(source.reader.take());
+                }
+                if failed || flags & sys::EPOLLOUT != 0 {
+                    wakers.extend(source.writer.take());
+                }
+                if source.events() != 0 {
+                    let _ = self.poller.ctl(sys::EPOLL_CTL_MOD, fd, source.events() | sys::EPOLLONESHOT);
                 }
-            } else {
-                break;
             }
         }
+
+        wakers.extend(self.timers.lock().unwrap().expire(Instant::now()));
+
+        for waker in wakers {
+            waker.wake();
+        }
     }
 }
 
+// Dropping a timer before it fires removes it from the reactor.
 struct AsyncTimer {
     deadline: Instant,
-    registered: bool,
+    registration: Option<(Arc<Reactor>, TimerId)>,
 }
 
 impl AsyncTimer {
     fn new(duration: Duration) -> Self {
+        Self::at(Instant::now() + duration)
+    }
+
+    fn at(deadline: Instant) -> Self {
         AsyncTimer {
-            deadline: Instant::now() + duration,
-            registered: false,
+            deadline,
+            registration: None,
         }
     }
 }
@@ -189,150 +727,259 @@ impl Future for AsyncTimer {
         let now = Instant::now();
         
         if now >= self.deadline {
-            Poll::Ready(())
-        } else {
-            if !self.registered {
-                REACTOR.with(|r| {
-                    if let Some(reactor) = r.borrow().as_ref() {
-                    }
-                });
-                self.registered = true;
+            if let Some((reactor, id)) = self.registration.take() {
+                reactor.cancel_timer(id);
+            }
+            return Poll::Ready(());
+        }
+
+        if let Some((reactor, id)) = &self.registration {
+            if reactor.timers.lock().unwrap().update_waker(*id, cx.waker()) {
+                return Poll::Pending;
             }
-            Poll::Pending
         }
+        let reactor = current_reactor();
+        let id = reactor.register_timer(self.deadline, cx.waker().clone());
+        self.registration = Some((reactor, id));
+        Poll::Pending
     }
 }
 
-struct AsyncTcpListener {
-    listener: TcpListener,
+impl Drop for AsyncTimer {
+    fn drop(&mut self) {
+        if let Some((reactor, id)) = self.registration.take() {
+            reactor.cancel_timer(id);
+        }
+    }
 }
 
-impl AsyncTcpListener {
-    fn bind(addr: SocketAddr) -> io::Result<Self> {
-        let listener = TcpListener::bind(addr)?;
-        listener.set_nonblocking(true)?;
-        Ok(AsyncTcpListener { listener })
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Elapsed;
+
+impl std::fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "deadline has elapsed")
     }
+}
 
-    async fn accept(&self) -> io::Result<(AsyncTcpStream, SocketAddr)> {
-        AcceptFuture {
-            listener: &self.listener,
-            registered: false,
-        }.await
+impl std::error::Error for Elapsed {}
+
+// Runs `future` for at most `duration`; on expiry the future is dropped.
+fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future: Box::pin(future),
+        timer: AsyncTimer::new(duration),
     }
 }
 
-struct AcceptFuture<'a> {
-    listener: &'a TcpListener,
-    registered: bool,
+struct Timeout<F: Future> {
+    future: Pin<Box<F>>,
+    timer: AsyncTimer,
 }
 
-impl<'a> Future for AcceptFuture<'a> {
-    type Output = io::Result<(AsyncTcpStream, SocketAddr)>;
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        match self.listener.accept() {
-            Ok((stream, addr)) => {
-                stream.set_nonblocking(true).ok();
-                Poll::Ready(Ok((AsyncTcpStream { stream }, addr)))
-            }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                if !self.registered {
-                    let fd = self.listener.as_raw_fd();
-                    REACTOR.with(|r| {
-                        if let Some(reactor) = r.borrow().as_ref() {
-                        }
-                    });
-                    self.registered = true;
-                }
-                Poll::Pending
-            }
-            Err(e) => Poll::Ready(Err(e)),
+        if let Poll::Ready(value) = self.future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(value));
+        }
+        match Pin::new(&mut self.timer).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
         }
     }
 }
 
-struct AsyncTcpStream {
-    stream: TcpStream,
+// Ticks every `period`, the first tick immediately. Ticks missed while the
+// task was busy are skipped rather than fired in a burst.
+fn interval(period: Duration) -> Interval {
+    assert!(!period.is_zero(), "interval period must be non-zero");
+    Interval {
+        next: Instant::now(),
+        period,
+    }
 }
 
-impl AsyncTcpStream {
-    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        ReadFuture {
-            stream: &mut self.stream,
-            buf,
-            registered: false,
-        }.await
-    }
+struct Interval {
+    next: Instant,
+    period: Duration,
+}
 
-    async fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        WriteFuture {
-            stream: &mut self.stream,
-            buf,
-            registered: false,
-        }.await
+impl Interval {
+    // Returns the instant the tick was scheduled for.
+    async fn tick(&mut self) -> Instant {
+        let scheduled = self.next;
+        AsyncTimer::at(scheduled).await;
+
+        let now = Instant::now();
+        self.next = scheduled + self.period;
+        if self.next <= now {
+            let missed = now.duration_since(self.next).as_nanos() / self.period.as_nanos() + 1;
+            self.next += self.period * missed.min(u32::MAX as u128) as u32;
+        }
+        scheduled
     }
 }
 
-struct ReadFuture<'a> {
-    stream: &'a mut TcpStream,
-    buf: &'a mut [u8],
-    registered: bool,
+// Where an fd is registered with the reactor; dropping it deregisters the fd.
+// It must be dropped before the socket it belongs to, so the fd is not
+// reused while still registered.
+struct Registration {
+    fd: RawFd,
+    reactor: Mutex<Option<Arc<Reactor>>>,
 }
 
-impl<'a> Future for ReadFuture<'a> {
-    type Output = io::Result<usize>;
-
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        match self.stream.read(self.buf) {
-            Ok(n) => Poll::Ready(Ok(n)),
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                if !self.registered {
-                    let fd = self.stream.as_raw_fd();
-                    REACTOR.with(|r| {
-                        if let Some(reactor) = r.borrow().as_ref() {
-                        }
-                    });
-                    self.registered = true;
-                }
-                Poll::Pending
-            }
-            Err(e) => Poll::Ready(Err(e)),
+impl Registration {
+    fn new(fd: RawFd) -> Self {
+        Registration {
+            fd,
+            reactor: Mutex::new(None),
         }
     }
-}
 
-struct WriteFuture<'a> {
-    stream: &'a mut TcpStream,
-    buf: &'a [u8],
-    registered: bool,
+    fn register(&self, interest: Interest, waker: &Waker) -> io::Result<()> {
+        let mut reactor = self.reactor.lock().unwrap();
+        reactor
+            .get_or_insert_with(current_reactor)
+            .register_io(self.fd, interest, waker.clone())
+    }
 }
 
-impl<'a> Future for WriteFuture<'a> {
-    type Output = io::Result<usize>;
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Some(reactor) = self.reactor.lock().unwrap().take() {
+            reactor.deregister_io(self.fd);
+        }
+    }
+}
 
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        match self.stream.write(self.buf) {
-            Ok(n) => Poll::Ready(Ok(n)),
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                if !self.registered {
-                    let fd = self.stream.as_raw_fd();
-                    REACTOR.with(|r| {
-                        if let Some(reactor) = r.borrow().as_ref() {
-                        }
-                    });
-                    self.registered = true;
-                }
-                Poll::Pending
+// Retries `op` until it stops returning WouldBlock, waiting on the reactor
+// in between.
+fn poll_io<T>(
+    registration: &Registration,
+    interest: Interest,
+    cx: &mut Context<'_>,
+    mut op: impl FnMut() -> io::Result<T>,
+) -> Poll<io::Result<T>> {
+    match op() {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+            match registration.register(interest, cx.waker()) {
+                Ok(()) => Poll::Pending,
+                Err(e) => Poll::Ready(Err(e)),
             }
-            Err(e) => Poll::Ready(Err(e)),
         }
+        result => Poll::Ready(result),
     }
 }
 
-struct Channel<T> {
-    inner: Arc<Mutex<ChannelInner<T>>>,
-}
+struct AsyncTcpListener {
+    registration: Registration,
+    listener: TcpListener,
+}
+
+impl AsyncTcpListener {
+    fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(AsyncTcpListener {
+            registration: Registration::new(listener.as_raw_fd()),
+            listener,
+        })
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    async fn accept(&self) -> io::Result<(AsyncTcpStream, SocketAddr)> {
+        AcceptFuture { listener: self }.await
+    }
+}
+
+struct AcceptFuture<'a> {
+    listener: &'a AsyncTcpListener,
+}
+
+impl<'a> Future for AcceptFuture<'a> {
+    type Output = io::Result<(AsyncTcpStream, SocketAddr)>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let listener = self.listener;
+        poll_io(&listener.registration, Interest::Readable, cx, || {
+            let (stream, addr) = listener.listener.accept()?;
+            Ok((AsyncTcpStream::from_std(stream)?, addr))
+        })
+    }
+}
+
+struct AsyncTcpStream {
+    registration: Registration,
+    stream: TcpStream,
+}
+
+impl AsyncTcpStream {
+    // The connect itself is blocking, which is immediate on loopback.
+    fn connect(addr: SocketAddr) -> io::Result<Self> {
+        Self::from_std(TcpStream::connect(addr)?)
+    }
+
+    fn from_std(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(AsyncTcpStream {
+            registration: Registration::new(stream.as_raw_fd()),
+            stream,
+        })
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        ReadFuture {
+            stream: self,
+            buf,
+        }.await
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        WriteFuture {
+            stream: self,
+            buf,
+        }.await
+    }
+}
+
+struct ReadFuture<'a> {
+    stream: &'a mut AsyncTcpStream,
+    buf: &'a mut [u8],
+}
+
+impl<'a> Future for ReadFuture<'a> {
+    type Output = io::Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let AsyncTcpStream { registration, stream } = &mut *this.stream;
+        poll_io(registration, Interest::Readable, cx, || stream.read(this.buf))
+    }
+}
+
+struct WriteFuture<'a> {
+    stream: &'a mut AsyncTcpStream,
+    buf: &'a [u8],
+}
+
+impl<'a> Future for WriteFuture<'a> {
+    type Output = io::Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let AsyncTcpStream { registration, stream } = &mut *this.stream;
+        poll_io(registration, Interest::Writable, cx, || stream.write(this.buf))
+    }
+}
+
+struct Channel<T> {
+    inner: Arc<Mutex<ChannelInner<T>>>,
+}
 
 struct ChannelInner<T> {
     queue: VecDeque<T>,
@@ -423,46 +1070,392 @@ impl<T> Future for RecvFuture<T> {
     }
 }
 
+// Why a task did not produce its output.
+enum JoinError {
+    Cancelled,
+    Panicked(Box<dyn Any + Send + 'static>),
+}
+
+impl JoinError {
+    fn is_cancelled(&self) -> bool {
+        matches!(self, JoinError::Cancelled)
+    }
+
+    fn is_panic(&self) -> bool {
+        matches!(self, JoinError::Panicked(_))
+    }
+
+    // The payload the task panicked with, for `panic::resume_unwind`.
+    fn into_panic(self) -> Box<dyn Any + Send + 'static> {
+        match self {
+            JoinError::Panicked(payload) => payload,
+            JoinError::Cancelled => panic!("task was cancelled, not panicked"),
+        }
+    }
+
+    fn panic_message(&self) -> Option<&str> {
+        let JoinError::Panicked(payload) = self else {
+            return None;
+        };
+        payload.downcast_ref::<&str>().copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+    }
+}
+
+impl std::fmt::Debug for JoinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JoinError::Cancelled => write!(f, "JoinError::Cancelled"),
+            JoinError::Panicked(_) => write!(f, "JoinError::Panicked({:?})", self.panic_message()),
+        }
+    }
+}
+
+impl std::fmt::Display for JoinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self, self.panic_message()) {
+            (JoinError::Cancelled, _) => write!(f, "task was cancelled"),
+            (JoinError::Panicked(_), Some(message)) => write!(f, "task panicked: {}", message),
+            (JoinError::Panicked(_), None) => write!(f, "task panicked"),
+        }
+    }
+}
+
+impl std::error::Error for JoinError {}
+
+struct JoinState<T> {
+    result: Option<Result<T, JoinError>>,
+    waker: Option<Waker>,
+}
+
+impl<T> JoinState<T> {
+    fn complete(&mut self, result: Result<T, JoinError>) {
+        self.result = Some(result);
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+// Wraps a spawned future: catches its panic, and reports Cancelled if the
+// task drops it before it finishes.
+struct Harness<F: Future> {
+    future: Pin<Box<F>>,
+    join: Arc<Mutex<JoinState<F::Output>>>,
+    done: bool,
+}
+
+impl<F: Future> Future for Harness<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let result = match panic::catch_unwind(AssertUnwindSafe(|| this.future.as_mut().poll(cx))) {
+            Ok(Poll::Pending) => return Poll::Pending,
+            Ok(Poll::Ready(value)) => Ok(value),
+            Err(payload) => Err(JoinError::Panicked(payload)),
+        };
+        this.done = true;
+        this.join.lock().unwrap().complete(result);
+        Poll::Ready(())
+    }
+}
+
+impl<F: Future> Drop for Harness<F> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.join.lock().unwrap().complete(Err(JoinError::Cancelled));
+        }
+    }
+}
+
+// Dropping the handle detaches the task unless `abort_on_drop` was set.
 struct JoinHandle<T> {
-    result: Arc<Mutex<Option<T>>>,
-    waker: Arc<Mutex<Option<Waker>>>,
+    join: Arc<Mutex<JoinState<T>>>,
+    task: Weak<Task>,
+    abort_on_drop: bool,
 }
 
-impl<T> Future for JoinHandle<T>
-where
-    T: Clone,
-{
-    type Output = T;
+impl<T> JoinHandle<T> {
+    // Cancels the task at its next poll; its future is dropped without
+    // running further. Does nothing if it already finished.
+    fn abort(&self) {
+        if let Some(task) = self.task.upgrade() {
+            task.abort();
+        }
+    }
+
+    fn abort_on_drop(mut self) -> Self {
+        self.abort_on_drop = true;
+        self
+    }
+
+    fn is_finished(&self) -> bool {
+        self.join.lock().unwrap().result.is_some()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let result = self.result.lock().unwrap();
+        let mut join = self.join.lock().unwrap();
         
-        if let Some(value) = result.as_ref() {
-            Poll::Ready(value.clone())
+        if let Some(result) = join.result.take() {
+            Poll::Ready(result)
         } else {
-            *self.waker.lock().unwrap() = Some(cx.waker().clone());
+            join.waker = Some(cx.waker().clone());
             Poll::Pending
         }
     }
 }
 
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        if self.abort_on_drop {
+            self.abort();
+        }
+    }
+}
+
 fn spawn<F, T>(future: F) -> JoinHandle<T>
 where
     F: Future<Output = T> + Send + 'static,
-    T: Clone + Send + 'static,
+    T: Send + 'static,
 {
-    let result = Arc::new(Mutex::new(None));
-    let waker = Arc::new(Mutex::new(None));
+    let join = Arc::new(Mutex::new(JoinState {
+        result: None,
+        waker: None,
+    }));
     
-    let result_clone = result.clone();
-    let waker_clone = waker.clone();
-    
-    REACTOR.with(|r| {
-        if let Some(reactor) = r.borrow().as_ref() {
-        }
+    let task = current_executor().spawn_task(Harness {
+        future: Box::pin(future),
+        join: join.clone(),
+        done: false,
     });
     
-    JoinHandle { result, waker }
+    JoinHandle {
+        join,
+        task: Arc::downgrade(&task),
+        abort_on_drop: false,
+    }
+}
+
+// Runs `body` with a scope to spawn children into, and does not return
+// until every child has finished. If the returned future is dropped early,
+// the children are cancelled instead.
+async fn task_scope<F, Fut, T>(body: F) -> T
+where
+    F: FnOnce(TaskScope) -> Fut,
+    Fut: Future<Output = T>,
+{
+    let scope = TaskScope::new();
+    let _cancel_if_dropped = CancelScope(scope.clone());
+    let output = body(scope.clone()).await;
+    scope.wait().await;
+    output
+}
+
+#[derive(Clone)]
+struct TaskScope {
+    inner: Arc<Mutex<ScopeInner>>,
+}
+
+struct ScopeInner {
+    children: Vec<Weak<Task>>,
+    running: usize,
+    cancelled: bool,
+    waiters: WaitQueue,
+}
+
+impl TaskScope {
+    fn new() -> Self {
+        TaskScope {
+            inner: Arc::new(Mutex::new(ScopeInner {
+                children: Vec::new(),
+                running: 0,
+                cancelled: false,
+                waiters: WaitQueue::new(),
+            })),
+        }
+    }
+
+    // Children spawned after `cancel` never start; their handles report
+    // Cancelled.
+    fn spawn<F, T>(&self, future: F) -> JoinHandle<T>
+    where
+        F: Future<Output = T> + Send + 'static,
+        T: Send + 'static,
+    {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.cancelled {
+                return JoinHandle {
+                    join: Arc::new(Mutex::new(JoinState {
+                        result: Some(Err(JoinError::Cancelled)),
+                        waker: None,
+                    })),
+                    task: Weak::new(),
+                    abort_on_drop: false,
+                };
+            }
+            inner.running += 1;
+        }
+        let member = ScopeMember(self.inner.clone());
+        let handle = spawn(async move {
+            let _member = member;
+            future.await
+        });
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.children.retain(|child| child.strong_count() > 0);
+        inner.children.push(handle.task.clone());
+        // `cancel` may have run while we were spawning.
+        if inner.cancelled {
+            handle.abort();
+        }
+        handle
+    }
+
+    fn cancel(&self) {
+        let children = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.cancelled = true;
+            std::mem::take(&mut inner.children)
+        };
+        for task in children.iter().filter_map(Weak::upgrade) {
+            task.abort();
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.inner.lock().unwrap().cancelled
+    }
+
+    // Resolves once no children are running, including cancelled ones
+    // that have not yet been dropped.
+    fn wait(&self) -> ScopeWait {
+        ScopeWait {
+            inner: self.inner.clone(),
+            slot: None,
+        }
+    }
+}
+
+// Lives inside each child's future, so it is dropped however the child ends.
+struct ScopeMember(Arc<Mutex<ScopeInner>>);
+
+impl Drop for ScopeMember {
+    fn drop(&mut self) {
+        let mut inner = self.0.lock().unwrap();
+        inner.running -= 1;
+        if inner.running == 0 {
+            inner.waiters.wake_all();
+        }
+    }
+}
+
+struct CancelScope(TaskScope);
+
+impl Drop for CancelScope {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+struct ScopeWait {
+    inner: Arc<Mutex<ScopeInner>>,
+    slot: Option<u64>,
+}
+
+impl Future for ScopeWait {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut inner = this.inner.lock().unwrap();
+
+        if inner.running == 0 {
+            if let Some(id) = this.slot.take() {
+                inner.waiters.remove(id);
+            }
+            Poll::Ready(())
+        } else {
+            inner.waiters.register(&mut this.slot, cx.waker());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for ScopeWait {
+    fn drop(&mut self) {
+        if let Some(id) = self.slot {
+            self.inner.lock().unwrap().waiters.remove(id);
+        }
+    }
+}
+
+// Waiters in arrival order. A future keeps its slot across polls and gives
+// it up when dropped, so a cancelled waiter never swallows a wakeup.
+struct WaitQueue {
+    waiters: VecDeque<(u64, Waker)>,
+    next_id: u64,
+}
+
+impl WaitQueue {
+    fn new() -> Self {
+        WaitQueue {
+            waiters: VecDeque::new(),
+            next_id: 0,
+        }
+    }
+
+    // Queues the waiter in `slot`, or refreshes its waker if still queued.
+    fn register(&mut self, slot: &mut Option<u64>, waker: &Waker) {
+        if let Some(id) = *slot {
+            if let Some((_, current)) = self.waiters.iter_mut().find(|(queued, _)| *queued == id) {
+                if !current.will_wake(waker) {
+                    *current = waker.clone();
+                }
+                return;
+            }
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.waiters.push_back((id, waker.clone()));
+        *slot = Some(id);
+    }
+
+    fn contains(&self, id: u64) -> bool {
+        self.waiters.iter().any(|(queued, _)| *queued == id)
+    }
+
+    // Returns false if the waiter had already been woken.
+    fn remove(&mut self, id: u64) -> bool {
+        match self.waiters.iter().position(|(queued, _)| *queued == id) {
+            Some(index) => {
+                self.waiters.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn wake_one(&mut self) -> bool {
+        match self.waiters.pop_front() {
+            Some((_, waker)) => {
+                waker.wake();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn wake_all(&mut self) {
+        for (_, waker) in self.waiters.drain(..) {
+            waker.wake();
+        }
+    }
 }
 
 struct Semaphore {
@@ -471,7 +1464,7 @@ struct Semaphore {
 
 struct SemaphoreInner {
     permits: usize,
-    wakers: VecDeque<Waker>,
+    waiters: WaitQueue,
 }
 
 impl Semaphore {
@@ -479,7 +1472,7 @@ impl Semaphore {
         Semaphore {
             count: Arc::new(Mutex::new(SemaphoreInner {
                 permits,
-                wakers: VecDeque::new(),
+                waiters: WaitQueue::new(),
             })),
         }
     }
@@ -487,46 +1480,81 @@ impl Semaphore {
     async fn acquire(&self) -> SemaphorePermit {
         AcquireFuture {
             inner: self.count.clone(),
+            slot: None,
+            acquired: false,
         }.await;
         
         SemaphorePermit {
             inner: self.count.clone(),
         }
     }
+
+    fn add_permits(&self, n: usize) {
+        let mut inner = self.count.lock().unwrap();
+        inner.permits += n;
+        for _ in 0..n {
+            if !inner.waiters.wake_one() {
+                break;
+            }
+        }
+    }
 }
 
 struct AcquireFuture {
     inner: Arc<Mutex<SemaphoreInner>>,
+    slot: Option<u64>,
+    acquired: bool,
 }
 
 impl Future for AcquireFuture {
     type Output = ();
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let mut inner = self.inner.lock().unwrap();
+        let this = self.get_mut();
+        let mut inner = this.inner.lock().unwrap();
         
         if inner.permits > 0 {
             inner.permits -= 1;
+            if let Some(id) = this.slot.take() {
+                inner.waiters.remove(id);
+            }
+            this.acquired = true;
             Poll::Ready(())
         } else {
-            inner.wakers.push_back(cx.waker().clone());
+            inner.waiters.register(&mut this.slot, cx.waker());
             Poll::Pending
         }
     }
 }
 
+impl Drop for AcquireFuture {
+    fn drop(&mut self) {
+        if let (Some(id), false) = (self.slot, self.acquired) {
+            let mut inner = self.inner.lock().unwrap();
+            // Woken but cancelled before taking the permit: pass it on.
+            if !inner.waiters.remove(id) && inner.permits > 0 {
+                inner.waiters.wake_one();
+            }
+        }
+    }
+}
+
 struct SemaphorePermit {
     inner: Arc<Mutex<SemaphoreInner>>,
 }
 
+impl SemaphorePermit {
+    // Keeps the permit taken; someone must `add_permits` to return it.
+    fn forget(self) {
+        std::mem::forget(self);
+    }
+}
+
 impl Drop for SemaphorePermit {
     fn drop(&mut self) {
         let mut inner = self.inner.lock().unwrap();
         inner.permits += 1;
-        
-        if let Some(waker) = inner.wakers.pop_front() {
-            waker.wake();
-        }
+        inner.waiters.wake_one();
     }
 }
 
@@ -571,21 +1599,20 @@ struct WaitFuture {
 impl Future for WaitFuture {
     type Output = BarrierWaitResult;
 
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let mut state = self.state.lock().unwrap();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut state = this.state.lock().unwrap();
         
-        let generation = self.generation.unwrap_or_else(|| {
-            let gen = state.generation;
+        let generation = *this.generation.get_or_insert_with(|| {
             state.arrived += 1;
-            self.generation = Some(gen);
-            gen
+            state.generation
         });
         
         if state.generation > generation {
             return Poll::Ready(BarrierWaitResult { is_leader: false });
         }
         
-        if state.arrived >= self.count {
+        if state.arrived >= this.count {
             state.arrived = 0;
             state.generation += 1;
             
@@ -609,4 +1636,1607 @@ impl BarrierWaitResult {
     fn is_leader(&self) -> bool {
         self.is_leader
     }
-}
\ No newline at end of file
+}
+
+fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.yielded {
+            Poll::Ready(())
+        } else {
+            self.yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+struct AsyncMutex<T> {
+    state: Mutex<MutexState>,
+    value: UnsafeCell<T>,
+}
+
+struct MutexState {
+    locked: bool,
+    waiters: WaitQueue,
+}
+
+// Access to `value` is serialized by `locked`, so the usual std::sync rules apply.
+unsafe impl<T: Send> Send for AsyncMutex<T> {}
+unsafe impl<T: Send> Sync for AsyncMutex<T> {}
+
+impl<T> AsyncMutex<T> {
+    fn new(value: T) -> Self {
+        AsyncMutex {
+            state: Mutex::new(MutexState {
+                locked: false,
+                waiters: WaitQueue::new(),
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    fn lock(&self) -> LockFuture<'_, T> {
+        LockFuture {
+            mutex: self,
+            slot: None,
+            acquired: false,
+        }
+    }
+
+    fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+struct LockFuture<'a, T> {
+    mutex: &'a AsyncMutex<T>,
+    slot: Option<u64>,
+    acquired: bool,
+}
+
+impl<'a, T> Future for LockFuture<'a, T> {
+    type Output = AsyncMutexGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut state = this.mutex.state.lock().unwrap();
+
+        if !state.locked {
+            state.locked = true;
+            if let Some(id) = this.slot.take() {
+                state.waiters.remove(id);
+            }
+            this.acquired = true;
+            Poll::Ready(AsyncMutexGuard {
+                mutex: this.mutex,
+                _marker: PhantomData,
+            })
+        } else {
+            state.waiters.register(&mut this.slot, cx.waker());
+            Poll::Pending
+        }
+    }
+}
+
+impl<'a, T> Drop for LockFuture<'a, T> {
+    fn drop(&mut self) {
+        if let (Some(id), false) = (self.slot, self.acquired) {
+            let mut state = self.mutex.state.lock().unwrap();
+            if !state.waiters.remove(id) && !state.locked {
+                state.waiters.wake_one();
+            }
+        }
+    }
+}
+
+struct AsyncMutexGuard<'a, T> {
+    mutex: &'a AsyncMutex<T>,
+    // Only Sync when T is, like std's MutexGuard.
+    _marker: PhantomData<&'a mut T>,
+}
+
+impl<'a, T> std::ops::Deref for AsyncMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> std::ops::DerefMut for AsyncMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> Drop for AsyncMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        let mut state = self.mutex.state.lock().unwrap();
+        state.locked = false;
+        state.waiters.wake_one();
+    }
+}
+
+// Writer-preferring: once a writer is waiting, new readers queue behind it.
+struct AsyncRwLock<T> {
+    state: Mutex<RwLockState>,
+    value: UnsafeCell<T>,
+}
+
+struct RwLockState {
+    readers: usize,
+    writer: bool,
+    writers_waiting: usize,
+    read_waiters: WaitQueue,
+    write_waiters: WaitQueue,
+}
+
+impl RwLockState {
+    fn wake_next(&mut self) {
+        if self.writer {
+            return;
+        }
+        if self.writers_waiting > 0 {
+            if self.readers == 0 {
+                self.write_waiters.wake_one();
+            }
+        } else {
+            self.read_waiters.wake_all();
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for AsyncRwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for AsyncRwLock<T> {}
+
+impl<T> AsyncRwLock<T> {
+    fn new(value: T) -> Self {
+        AsyncRwLock {
+            state: Mutex::new(RwLockState {
+                readers: 0,
+                writer: false,
+                writers_waiting: 0,
+                read_waiters: WaitQueue::new(),
+                write_waiters: WaitQueue::new(),
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    fn read(&self) -> ReadLockFuture<'_, T> {
+        ReadLockFuture {
+            lock: self,
+            slot: None,
+        }
+    }
+
+    fn write(&self) -> WriteLockFuture<'_, T> {
+        WriteLockFuture {
+            lock: self,
+            slot: None,
+            counted: false,
+            acquired: false,
+        }
+    }
+}
+
+struct ReadLockFuture<'a, T> {
+    lock: &'a AsyncRwLock<T>,
+    slot: Option<u64>,
+}
+
+impl<'a, T> Future for ReadLockFuture<'a, T> {
+    type Output = AsyncReadGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut state = this.lock.state.lock().unwrap();
+
+        if !state.writer && state.writers_waiting == 0 {
+            state.readers += 1;
+            if let Some(id) = this.slot.take() {
+                state.read_waiters.remove(id);
+            }
+            Poll::Ready(AsyncReadGuard { lock: this.lock })
+        } else {
+            state.read_waiters.register(&mut this.slot, cx.waker());
+            Poll::Pending
+        }
+    }
+}
+
+impl<'a, T> Drop for ReadLockFuture<'a, T> {
+    fn drop(&mut self) {
+        // Readers are always woken together, so there is nothing to pass on.
+        if let Some(id) = self.slot {
+            self.lock.state.lock().unwrap().read_waiters.remove(id);
+        }
+    }
+}
+
+struct WriteLockFuture<'a, T> {
+    lock: &'a AsyncRwLock<T>,
+    slot: Option<u64>,
+    counted: bool,
+    acquired: bool,
+}
+
+impl<'a, T> Future for WriteLockFuture<'a, T> {
+    type Output = AsyncWriteGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut state = this.lock.state.lock().unwrap();
+
+        if !state.writer && state.readers == 0 {
+            state.writer = true;
+            if this.counted {
+                state.writers_waiting -= 1;
+            }
+            if let Some(id) = this.slot.take() {
+                state.write_waiters.remove(id);
+            }
+            this.acquired = true;
+            Poll::Ready(AsyncWriteGuard { lock: this.lock })
+        } else {
+            if !this.counted {
+                state.writers_waiting += 1;
+                this.counted = true;
+            }
+            state.write_waiters.register(&mut this.slot, cx.waker());
+            Poll::Pending
+        }
+    }
+}
+
+impl<'a, T> Drop for WriteLockFuture<'a, T> {
+    fn drop(&mut self) {
+        if self.counted && !self.acquired {
+            let mut state = self.lock.state.lock().unwrap();
+            state.writers_waiting -= 1;
+            if let Some(id) = self.slot {
+                state.write_waiters.remove(id);
+            }
+            state.wake_next();
+        }
+    }
+}
+
+struct AsyncReadGuard<'a, T> {
+    lock: &'a AsyncRwLock<T>,
+}
+
+impl<'a, T> std::ops::Deref for AsyncReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T> Drop for AsyncReadGuard<'a, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.state.lock().unwrap();
+        state.readers -= 1;
+        if state.readers == 0 {
+            state.wake_next();
+        }
+    }
+}
+
+struct AsyncWriteGuard<'a, T> {
+    lock: &'a AsyncRwLock<T>,
+}
+
+impl<'a, T> std::ops::Deref for AsyncWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T> std::ops::DerefMut for AsyncWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<'a, T> Drop for AsyncWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.state.lock().unwrap();
+        state.writer = false;
+        state.wake_next();
+    }
+}
+
+struct Notify {
+    inner: Arc<Mutex<NotifyInner>>,
+}
+
+struct NotifyInner {
+    permit: bool,
+    waiters: WaitQueue,
+    // Bumped by notify_waiters, so a dropped waiter can tell which call woke it.
+    broadcasts: u64,
+}
+
+impl Notify {
+    fn new() -> Self {
+        Notify {
+            inner: Arc::new(Mutex::new(NotifyInner {
+                permit: false,
+                waiters: WaitQueue::new(),
+                broadcasts: 0,
+            })),
+        }
+    }
+
+    // Wakes one waiter, or leaves a permit for the next `notified()`.
+    fn notify_one(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.waiters.wake_one() {
+            inner.permit = true;
+        }
+    }
+
+    // Wakes everyone currently waiting; stores no permit.
+    fn notify_waiters(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.broadcasts += 1;
+        inner.waiters.wake_all();
+    }
+
+    fn notified(&self) -> Notified {
+        Notified {
+            inner: self.inner.clone(),
+            slot: None,
+            broadcasts: 0,
+            done: false,
+        }
+    }
+}
+
+struct Notified {
+    inner: Arc<Mutex<NotifyInner>>,
+    slot: Option<u64>,
+    broadcasts: u64,
+    done: bool,
+}
+
+impl Future for Notified {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut inner = this.inner.lock().unwrap();
+
+        // Leaving the queue is how a waiter learns it was notified.
+        let notified = match this.slot {
+            Some(id) => !inner.waiters.contains(id),
+            None => std::mem::take(&mut inner.permit),
+        };
+        if notified {
+            this.done = true;
+            return Poll::Ready(());
+        }
+        if this.slot.is_none() {
+            this.broadcasts = inner.broadcasts;
+        }
+        inner.waiters.register(&mut this.slot, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl Drop for Notified {
+    fn drop(&mut self) {
+        if let (Some(id), false) = (self.slot, self.done) {
+            let mut inner = self.inner.lock().unwrap();
+            if !inner.waiters.remove(id) && inner.broadcasts == self.broadcasts {
+                // Consumed a notify_one without observing it: hand it on.
+                if !inner.waiters.wake_one() {
+                    inner.permit = true;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RecvError;
+
+impl std::fmt::Display for RecvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "channel closed")
+    }
+}
+
+impl std::error::Error for RecvError {}
+
+fn oneshot<T>() -> (OneshotSender<T>, OneshotReceiver<T>) {
+    let inner = Arc::new(Mutex::new(OneshotInner {
+        value: None,
+        sender_alive: true,
+        receiver_alive: true,
+        waker: None,
+    }));
+    (
+        OneshotSender { inner: inner.clone() },
+        OneshotReceiver { inner },
+    )
+}
+
+struct OneshotInner<T> {
+    value: Option<T>,
+    sender_alive: bool,
+    receiver_alive: bool,
+    waker: Option<Waker>,
+}
+
+struct OneshotSender<T> {
+    inner: Arc<Mutex<OneshotInner<T>>>,
+}
+
+impl<T> OneshotSender<T> {
+    // Hands the value back if the receiver is already gone.
+    fn send(self, value: T) -> Result<(), T> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.receiver_alive {
+            return Err(value);
+        }
+        inner.value = Some(value);
+        if let Some(waker) = inner.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    fn is_closed(&self) -> bool {
+        !self.inner.lock().unwrap().receiver_alive
+    }
+}
+
+impl<T> Drop for OneshotSender<T> {
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.sender_alive = false;
+        if let Some(waker) = inner.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct OneshotReceiver<T> {
+    inner: Arc<Mutex<OneshotInner<T>>>,
+}
+
+impl<T> Future for OneshotReceiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Some(value) = inner.value.take() {
+            Poll::Ready(Ok(value))
+        } else if !inner.sender_alive {
+            Poll::Ready(Err(RecvError))
+        } else {
+            inner.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> Drop for OneshotReceiver<T> {
+    fn drop(&mut self) {
+        self.inner.lock().unwrap().receiver_alive = false;
+    }
+}
+
+// Each queued message holds a semaphore permit, so senders wait once
+// `capacity` messages are in flight.
+fn bounded<T>(capacity: usize) -> (BoundedSender<T>, BoundedReceiver<T>) {
+    assert!(capacity > 0, "bounded channel needs a capacity of at least 1");
+    let inner = Arc::new(Mutex::new(BoundedInner {
+        queue: VecDeque::with_capacity(capacity),
+        senders: 1,
+        receiver_alive: true,
+        waker: None,
+    }));
+    let permits = Arc::new(Semaphore::new(capacity));
+    (
+        BoundedSender {
+            inner: inner.clone(),
+            permits: permits.clone(),
+        },
+        BoundedReceiver { inner, permits },
+    )
+}
+
+struct BoundedInner<T> {
+    queue: VecDeque<T>,
+    senders: usize,
+    receiver_alive: bool,
+    waker: Option<Waker>,
+}
+
+struct BoundedSender<T> {
+    inner: Arc<Mutex<BoundedInner<T>>>,
+    permits: Arc<Semaphore>,
+}
+
+impl<T> BoundedSender<T> {
+    // Waits for room; hands the value back if the receiver is gone.
+    async fn send(&self, value: T) -> Result<(), T> {
+        let permit = self.permits.acquire().await;
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.receiver_alive {
+            // Dropping the permit wakes the next blocked sender, which fails too.
+            return Err(value);
+        }
+        inner.queue.push_back(value);
+        permit.forget();
+        if let Some(waker) = inner.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+}
+
+impl<T> Clone for BoundedSender<T> {
+    fn clone(&self) -> Self {
+        self.inner.lock().unwrap().senders += 1;
+        BoundedSender {
+            inner: self.inner.clone(),
+            permits: self.permits.clone(),
+        }
+    }
+}
+
+impl<T> Drop for BoundedSender<T> {
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.senders -= 1;
+        if inner.senders == 0 {
+            if let Some(waker) = inner.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+struct BoundedReceiver<T> {
+    inner: Arc<Mutex<BoundedInner<T>>>,
+    permits: Arc<Semaphore>,
+}
+
+impl<T> BoundedReceiver<T> {
+    fn recv(&mut self) -> BoundedRecvFuture<'_, T> {
+        BoundedRecvFuture { receiver: self }
+    }
+}
+
+impl<T> Drop for BoundedReceiver<T> {
+    fn drop(&mut self) {
+        self.inner.lock().unwrap().receiver_alive = false;
+        self.permits.add_permits(1);
+    }
+}
+
+struct BoundedRecvFuture<'a, T> {
+    receiver: &'a mut BoundedReceiver<T>,
+}
+
+impl<'a, T> Future for BoundedRecvFuture<'a, T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let receiver = &self.get_mut().receiver;
+        let mut inner = receiver.inner.lock().unwrap();
+
+        if let Some(value) = inner.queue.pop_front() {
+            drop(inner);
+            receiver.permits.add_permits(1);
+            Poll::Ready(Some(value))
+        } else if inner.senders == 0 {
+            Poll::Ready(None)
+        } else {
+            inner.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BroadcastError {
+    // The receiver fell this many messages behind; it resumes at the oldest kept.
+    Lagged(u64),
+    Closed,
+}
+
+impl std::fmt::Display for BroadcastError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BroadcastError::Lagged(missed) => write!(f, "receiver lagged by {} messages", missed),
+            BroadcastError::Closed => write!(f, "channel closed"),
+        }
+    }
+}
+
+impl std::error::Error for BroadcastError {}
+
+fn broadcast<T: Clone>(capacity: usize) -> (BroadcastSender<T>, BroadcastReceiver<T>) {
+    assert!(capacity > 0, "broadcast channel needs a capacity of at least 1");
+    let inner = Arc::new(Mutex::new(BroadcastInner {
+        buffer: VecDeque::with_capacity(capacity),
+        capacity,
+        next_seq: 0,
+        senders: 1,
+        receivers: 1,
+        waiters: WaitQueue::new(),
+    }));
+    (
+        BroadcastSender { inner: inner.clone() },
+        BroadcastReceiver { inner, next: 0 },
+    )
+}
+
+struct BroadcastInner<T> {
+    // The last `capacity` messages, tagged with their sequence numbers.
+    buffer: VecDeque<(u64, T)>,
+    capacity: usize,
+    next_seq: u64,
+    senders: usize,
+    receivers: usize,
+    waiters: WaitQueue,
+}
+
+struct BroadcastSender<T> {
+    inner: Arc<Mutex<BroadcastInner<T>>>,
+}
+
+impl<T: Clone> BroadcastSender<T> {
+    // Returns how many receivers will see the value, or the value if there are none.
+    fn send(&self, value: T) -> Result<usize, T> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.receivers == 0 {
+            return Err(value);
+        }
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        if inner.buffer.len() == inner.capacity {
+            inner.buffer.pop_front();
+        }
+        inner.buffer.push_back((seq, value));
+        inner.waiters.wake_all();
+        Ok(inner.receivers)
+    }
+
+    fn subscribe(&self) -> BroadcastReceiver<T> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.receivers += 1;
+        BroadcastReceiver {
+            inner: self.inner.clone(),
+            next: inner.next_seq,
+        }
+    }
+}
+
+impl<T> Clone for BroadcastSender<T> {
+    fn clone(&self) -> Self {
+        self.inner.lock().unwrap().senders += 1;
+        BroadcastSender {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Drop for BroadcastSender<T> {
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.senders -= 1;
+        if inner.senders == 0 {
+            inner.waiters.wake_all();
+        }
+    }
+}
+
+struct BroadcastReceiver<T> {
+    inner: Arc<Mutex<BroadcastInner<T>>>,
+    next: u64,
+}
+
+impl<T: Clone> BroadcastReceiver<T> {
+    fn recv(&mut self) -> BroadcastRecvFuture<'_, T> {
+        BroadcastRecvFuture {
+            receiver: self,
+            slot: None,
+        }
+    }
+}
+
+impl<T> Drop for BroadcastReceiver<T> {
+    fn drop(&mut self) {
+        self.inner.lock().unwrap().receivers -= 1;
+    }
+}
+
+struct BroadcastRecvFuture<'a, T> {
+    receiver: &'a mut BroadcastReceiver<T>,
+    slot: Option<u64>,
+}
+
+impl<'a, T: Clone> Future for BroadcastRecvFuture<'a, T> {
+    type Output = Result<T, BroadcastError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut inner = this.receiver.inner.lock().unwrap();
+        let oldest = inner.buffer.front().map_or(inner.next_seq, |(seq, _)| *seq);
+
+        if this.receiver.next < oldest {
+            let missed = oldest - this.receiver.next;
+            this.receiver.next = oldest;
+            return Poll::Ready(Err(BroadcastError::Lagged(missed)));
+        }
+        if this.receiver.next < inner.next_seq {
+            let value = inner.buffer[(this.receiver.next - oldest) as usize].1.clone();
+            this.receiver.next += 1;
+            return Poll::Ready(Ok(value));
+        }
+        if inner.senders == 0 {
+            return Poll::Ready(Err(BroadcastError::Closed));
+        }
+        inner.waiters.register(&mut this.slot, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl<'a, T> Drop for BroadcastRecvFuture<'a, T> {
+    fn drop(&mut self) {
+        if let Some(id) = self.slot {
+            self.receiver.inner.lock().unwrap().waiters.remove(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::poll_fn;
+
+    fn loopback() -> SocketAddr {
+        "127.0.0.1:0".parse().unwrap()
+    }
+
+    #[test]
+    fn test_tcp_echo_over_loopback() {
+        let executor = Executor::new();
+        let reply = Arc::new(Mutex::new(Vec::new()));
+        let reply_clone = reply.clone();
+
+        executor.spawn(async move {
+            let listener = AsyncTcpListener::bind(loopback()).unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            let server = spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 16];
+                let n = stream.read(&mut buf).await.unwrap();
+                stream.write(&buf[..n]).await.unwrap()
+            });
+
+            let mut client = AsyncTcpStream::connect(addr).unwrap();
+            client.write(b"ping").await.unwrap();
+            let mut buf = [0u8; 16];
+            let n = client.read(&mut buf).await.unwrap();
+            reply_clone.lock().unwrap().extend_from_slice(&buf[..n]);
+            assert_eq!(server.await.unwrap(), 4);
+        });
+        executor.run();
+
+        assert_eq!(*reply.lock().unwrap(), b"ping");
+    }
+
+    #[test]
+    fn test_read_woken_by_data_from_another_thread() {
+        let listener = AsyncTcpListener::bind(loopback()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(String::new()));
+        let received_clone = received.clone();
+
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            let mut stream = TcpStream::connect(addr).unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            stream.write_all(b"late").unwrap();
+        });
+
+        let executor = Executor::new();
+        let start = Instant::now();
+        executor.spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 16];
+            let n = stream.read(&mut buf).await.unwrap();
+            received_clone.lock().unwrap().push_str(std::str::from_utf8(&buf[..n]).unwrap());
+        });
+        executor.run();
+        writer.join().unwrap();
+
+        assert_eq!(*received.lock().unwrap(), "late");
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_timers_sleep_in_epoll_wait() {
+        let executor = Executor::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        for (name, millis) in [("slow", 60), ("fast", 20)] {
+            let order = order.clone();
+            executor.spawn(async move {
+                AsyncTimer::new(Duration::from_millis(millis)).await;
+                order.lock().unwrap().push(name);
+            });
+        }
+
+        let start = Instant::now();
+        executor.run();
+        let elapsed = start.elapsed();
+
+        assert_eq!(*order.lock().unwrap(), ["fast", "slow"]);
+        assert!(elapsed >= Duration::from_millis(60));
+        assert!(elapsed < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_dropping_a_stream_deregisters_its_fd() {
+        let executor = Executor::new();
+        let checked = Arc::new(AtomicBool::new(false));
+        let checked_clone = checked.clone();
+
+        executor.spawn(async move {
+            let listener = AsyncTcpListener::bind(loopback()).unwrap();
+            let mut stream = AsyncTcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let fd = stream.stream.as_raw_fd();
+            let reactor = current_reactor();
+
+            // Poll a read once so it registers, then abandon it.
+            let mut buf = [0u8; 8];
+            let mut read = Box::pin(stream.read(&mut buf));
+            poll_fn(|cx| {
+                assert!(read.as_mut().poll(cx).is_pending());
+                Poll::Ready(())
+            }).await;
+            drop(read);
+            assert!(reactor.io_sources.lock().unwrap().contains_key(&fd));
+
+            drop(stream);
+            assert!(!reactor.io_sources.lock().unwrap().contains_key(&fd));
+            checked_clone.store(true, Ordering::SeqCst);
+        });
+        executor.run();
+
+        assert!(checked.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_thousands_of_timers_fire_in_deadline_order() {
+        let executor = Executor::new();
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        // Far enough out that every timer is registered before any is due.
+        let base = Instant::now() + Duration::from_millis(50);
+
+        for i in 0..2000u64 {
+            let fired = fired.clone();
+            // Spread deadlines out of insertion order.
+            let millis = (i * 7919) % 40;
+            executor.spawn(async move {
+                let deadline = base + Duration::from_millis(millis);
+                AsyncTimer::at(deadline).await;
+                assert!(Instant::now() >= deadline);
+                fired.lock().unwrap().push(millis);
+            });
+        }
+        executor.run();
+
+        let fired = fired.lock().unwrap();
+        assert_eq!(fired.len(), 2000);
+        assert_eq!(executor.reactor().timers.lock().unwrap().len(), 0);
+        let mut by_deadline = fired.clone();
+        by_deadline.dedup();
+        assert!(by_deadline.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn test_dropped_timers_are_cancelled() {
+        let executor = Executor::new();
+        let reactor = executor.reactor().clone();
+
+        executor.spawn(async move {
+            let reactor = current_reactor();
+            let mut timers: Vec<_> = (0..500).map(|_| Box::pin(AsyncTimer::new(Duration::from_secs(60)))).collect();
+            poll_fn(|cx| {
+                for timer in &mut timers {
+                    assert!(timer.as_mut().poll(cx).is_pending());
+                }
+                Poll::Ready(())
+            }).await;
+            assert_eq!(reactor.timers.lock().unwrap().len(), 500);
+
+            drop(timers);
+            let mut queue = reactor.timers.lock().unwrap();
+            assert_eq!(queue.len(), 0);
+            assert!(queue.heap.len() <= 64);
+            assert_eq!(queue.next_deadline(), None);
+        });
+
+        // Nothing is left to wait for, so this returns immediately.
+        let start = Instant::now();
+        executor.run();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(reactor.timers.lock().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_timeout_and_interval() {
+        let executor = Executor::new();
+        let ticks = Arc::new(Mutex::new(Vec::new()));
+        let ticks_clone = ticks.clone();
+
+        executor.spawn(async move {
+            let quick = timeout(Duration::from_millis(200), async {
+                AsyncTimer::new(Duration::from_millis(5)).await;
+                7
+            }).await;
+            assert_eq!(quick, Ok(7));
+
+            let start = Instant::now();
+            let slow = timeout(Duration::from_millis(20), AsyncTimer::new(Duration::from_secs(60))).await;
+            assert_eq!(slow, Err(Elapsed));
+            assert!(start.elapsed() < Duration::from_secs(1));
+            assert_eq!(current_reactor().timers.lock().unwrap().len(), 0);
+
+            let mut every = interval(Duration::from_millis(15));
+            for _ in 0..4 {
+                let scheduled = every.tick().await;
+                ticks_clone.lock().unwrap().push(scheduled);
+            }
+        });
+        executor.run();
+
+        let ticks = ticks.lock().unwrap();
+        assert_eq!(ticks.len(), 4);
+        // Ticks stay on the 15ms grid; a late one skips grid points.
+        let period = Duration::from_millis(15).as_nanos();
+        for pair in ticks.windows(2) {
+            let gap = (pair[1] - pair[0]).as_nanos();
+            assert!(gap > 0 && gap % period == 0, "gap of {}ns", gap);
+        }
+    }
+
+    #[test]
+    fn test_cpu_bound_tasks_run_in_parallel() {
+        let executor = Executor::with_workers(4);
+        let arrived = Arc::new(AtomicUsize::new(0));
+        let threads = Arc::new(Mutex::new(Vec::new()));
+
+        for _ in 0..4 {
+            let arrived = arrived.clone();
+            let threads = threads.clone();
+            executor.spawn(async move {
+                threads.lock().unwrap().push(thread::current().id());
+                arrived.fetch_add(1, Ordering::SeqCst);
+                // Never yields: only finishes if all four run at once.
+                let deadline = Instant::now() + Duration::from_secs(10);
+                while arrived.load(Ordering::SeqCst) < 4 && Instant::now() < deadline {
+                    thread::yield_now();
+                }
+            });
+        }
+        executor.run();
+
+        assert_eq!(arrived.load(Ordering::SeqCst), 4);
+        let mut threads = threads.lock().unwrap().clone();
+        threads.sort_by_key(|id| format!("{:?}", id));
+        threads.dedup();
+        assert_eq!(threads.len(), 4);
+    }
+
+    #[test]
+    fn test_idle_workers_steal_spawned_tasks() {
+        let executor = Executor::with_workers(4);
+        let threads = executor.block_on(async {
+            let handles: Vec<_> = (0..64)
+                .map(|_| spawn(async {
+                    let start = Instant::now();
+                    while start.elapsed() < Duration::from_millis(2) {
+                        std::hint::spin_loop();
+                    }
+                    thread::current().id()
+                }))
+                .collect();
+
+            let mut threads = Vec::new();
+            for handle in handles {
+                threads.push(handle.await.unwrap());
+            }
+            threads
+        });
+
+        let mut distinct = threads.clone();
+        distinct.sort_by_key(|id| format!("{:?}", id));
+        distinct.dedup();
+        assert_eq!(threads.len(), 64);
+        assert!(distinct.len() > 1, "all subtasks ran on one worker");
+    }
+
+    #[test]
+    fn test_channels_and_semaphores_across_workers() {
+        let executor = Executor::with_workers(4);
+        let (sum, max_inside) = executor.block_on(async {
+            let (tx, rx) = Channel::new();
+            let tx = Arc::new(tx);
+            let semaphore = Arc::new(Semaphore::new(2));
+            let inside = Arc::new(AtomicUsize::new(0));
+            let max_inside = Arc::new(AtomicUsize::new(0));
+
+            for i in 1..=20u64 {
+                let (tx, semaphore) = (tx.clone(), semaphore.clone());
+                let (inside, max_inside) = (inside.clone(), max_inside.clone());
+                spawn(async move {
+                    let _permit = semaphore.acquire().await;
+                    let now = inside.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_inside.fetch_max(now, Ordering::SeqCst);
+                    AsyncTimer::new(Duration::from_millis(2)).await;
+                    inside.fetch_sub(1, Ordering::SeqCst);
+                    tx.send(i).ok();
+                });
+            }
+            drop(tx);
+
+            let mut sum = 0;
+            while let Some(i) = rx.recv().await {
+                sum += i;
+            }
+            (sum, max_inside.load(Ordering::SeqCst))
+        });
+
+        assert_eq!(sum, 210);
+        assert!((1..=2).contains(&max_inside));
+    }
+
+    #[test]
+    fn test_spawn_from_outside_the_runtime() {
+        let executor = Executor::with_workers(2);
+        let (tx, rx) = Channel::new();
+        let outside = executor.clone();
+
+        let spawner = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(30));
+            outside.spawn(async move {
+                tx.send("from outside").ok();
+            });
+        });
+
+        let message = executor.block_on(async move { rx.recv().await });
+        spawner.join().unwrap();
+        assert_eq!(message, Some("from outside"));
+    }
+
+    #[test]
+    fn test_mutex_guard_held_across_await() {
+        let executor = Executor::with_workers(4);
+        let (count, overlapped) = executor.block_on(async {
+            let counter = Arc::new(AsyncMutex::new(0u64));
+            let inside = Arc::new(AtomicBool::new(false));
+            let overlapped = Arc::new(AtomicBool::new(false));
+            let (tx, rx) = Channel::new();
+            let tx = Arc::new(tx);
+
+            for _ in 0..8 {
+                let (counter, tx) = (counter.clone(), tx.clone());
+                let (inside, overlapped) = (inside.clone(), overlapped.clone());
+                spawn(async move {
+                    for _ in 0..50 {
+                        let mut guard = counter.lock().await;
+                        if inside.swap(true, Ordering::SeqCst) {
+                            overlapped.store(true, Ordering::SeqCst);
+                        }
+                        let seen = *guard;
+                        yield_now().await;
+                        *guard = seen + 1;
+                        inside.store(false, Ordering::SeqCst);
+                    }
+                    tx.send(()).ok();
+                });
+            }
+            drop(tx);
+            while rx.recv().await.is_some() {}
+
+            let counter = Arc::try_unwrap(counter).ok().unwrap();
+            (counter.into_inner(), overlapped.load(Ordering::SeqCst))
+        });
+
+        assert_eq!(count, 400);
+        assert!(!overlapped);
+    }
+
+    #[test]
+    fn test_cancelled_lock_waiter_passes_the_lock_on() {
+        let executor = Executor::with_workers(2);
+        let value = executor.block_on(async {
+            let mutex = Arc::new(AsyncMutex::new(0));
+            let guard = mutex.lock().await;
+
+            let gave_up = timeout(Duration::from_millis(10), mutex.lock()).await;
+            assert!(gave_up.is_err());
+
+            let waiter = mutex.clone();
+            let (tx, rx) = oneshot();
+            spawn(async move {
+                *waiter.lock().await += 1;
+                tx.send(()).ok();
+            });
+            AsyncTimer::new(Duration::from_millis(5)).await;
+            drop(guard);
+            rx.await.unwrap();
+            let value = *mutex.lock().await;
+            value
+        });
+        assert_eq!(value, 1);
+    }
+
+    #[test]
+    fn test_rwlock_readers_share_and_writers_exclude() {
+        let executor = Executor::with_workers(4);
+        let (max_readers, violated, total) = executor.block_on(async {
+            let lock = Arc::new(AsyncRwLock::new(0u64));
+            let readers = Arc::new(AtomicUsize::new(0));
+            let writing = Arc::new(AtomicBool::new(false));
+            let max_readers = Arc::new(AtomicUsize::new(0));
+            let violated = Arc::new(AtomicBool::new(false));
+            let (tx, rx) = Channel::new();
+            let tx = Arc::new(tx);
+
+            for task in 0..12 {
+                let (lock, tx) = (lock.clone(), tx.clone());
+                let (readers, writing) = (readers.clone(), writing.clone());
+                let (max_readers, violated) = (max_readers.clone(), violated.clone());
+                spawn(async move {
+                    for _ in 0..20 {
+                        if task % 4 == 0 {
+                            let mut guard = lock.write().await;
+                            if writing.swap(true, Ordering::SeqCst) || readers.load(Ordering::SeqCst) > 0 {
+                                violated.store(true, Ordering::SeqCst);
+                            }
+                            yield_now().await;
+                            *guard += 1;
+                            writing.store(false, Ordering::SeqCst);
+                        } else {
+                            let _guard = lock.read().await;
+                            let now = readers.fetch_add(1, Ordering::SeqCst) + 1;
+                            max_readers.fetch_max(now, Ordering::SeqCst);
+                            if writing.load(Ordering::SeqCst) {
+                                violated.store(true, Ordering::SeqCst);
+                            }
+                            AsyncTimer::new(Duration::from_millis(1)).await;
+                            readers.fetch_sub(1, Ordering::SeqCst);
+                        }
+                    }
+                    tx.send(()).ok();
+                });
+            }
+            drop(tx);
+            while rx.recv().await.is_some() {}
+
+            let total = *lock.read().await;
+            (max_readers.load(Ordering::SeqCst), violated.load(Ordering::SeqCst), total)
+        });
+
+        assert!(!violated);
+        assert!(max_readers > 1, "readers never overlapped");
+        assert_eq!(total, 60);
+    }
+
+    #[test]
+    fn test_notify_one_stores_a_permit_and_notify_waiters_wakes_all() {
+        let executor = Executor::with_workers(4);
+        let woken = executor.block_on(async {
+            let notify = Arc::new(Notify::new());
+            notify.notify_one();
+            notify.notified().await;
+
+            let woken = Arc::new(AtomicUsize::new(0));
+            let (tx, rx) = bounded(8);
+            for _ in 0..5 {
+                let (notify, woken, tx) = (notify.clone(), woken.clone(), tx.clone());
+                spawn(async move {
+                    let notified = notify.notified();
+                    let mut notified = std::pin::pin!(notified);
+                    // Register before reporting in, so notify_waiters can't miss us.
+                    poll_fn(|cx| {
+                        let _ = notified.as_mut().poll(cx);
+                        Poll::Ready(())
+                    }).await;
+                    tx.send(()).await.ok();
+                    notified.await;
+                    woken.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+            drop(tx);
+            let mut rx = rx;
+            for _ in 0..5 {
+                rx.recv().await.unwrap();
+            }
+            notify.notify_waiters();
+            // Every task drops its sender only after being woken.
+            assert_eq!(rx.recv().await, None);
+
+            // notify_waiters leaves no permit behind.
+            let leftover = timeout(Duration::from_millis(10), notify.notified()).await;
+            assert!(leftover.is_err());
+            woken.load(Ordering::SeqCst)
+        });
+        assert_eq!(woken, 5);
+    }
+
+    #[test]
+    fn test_oneshot_delivers_or_reports_a_dropped_sender() {
+        let executor = Executor::with_workers(2);
+        executor.block_on(async {
+            let (tx, rx) = oneshot();
+            spawn(async move {
+                AsyncTimer::new(Duration::from_millis(5)).await;
+                tx.send(42).unwrap();
+            });
+            assert_eq!(rx.await, Ok(42));
+
+            let (tx, rx) = oneshot::<u32>();
+            spawn(async move {
+                AsyncTimer::new(Duration::from_millis(5)).await;
+                drop(tx);
+            });
+            assert_eq!(rx.await, Err(RecvError));
+
+            let (tx, rx) = oneshot();
+            drop(rx);
+            assert!(tx.is_closed());
+            assert_eq!(tx.send("late"), Err("late"));
+        });
+    }
+
+    #[test]
+    fn test_bounded_channel_applies_backpressure() {
+        let executor = Executor::with_workers(4);
+        let (received, max_queued) = executor.block_on(async {
+            let (tx, mut rx) = bounded(4);
+            let sent = Arc::new(AtomicUsize::new(0));
+            for producer in 0..4u64 {
+                let (tx, sent) = (tx.clone(), sent.clone());
+                spawn(async move {
+                    for i in 0..50 {
+                        tx.send(producer * 100 + i).await.unwrap();
+                        sent.fetch_add(1, Ordering::SeqCst);
+                    }
+                });
+            }
+            drop(tx);
+
+            let mut received = Vec::new();
+            let mut max_queued = 0;
+            loop {
+                AsyncTimer::new(Duration::from_micros(200)).await;
+                max_queued = max_queued.max(sent.load(Ordering::SeqCst).saturating_sub(received.len()));
+                match rx.recv().await {
+                    Some(value) => received.push(value),
+                    None => break,
+                }
+            }
+            (received, max_queued)
+        });
+
+        assert_eq!(received.len(), 200);
+        assert!(max_queued <= 4, "{} messages queued past capacity 4", max_queued);
+        for producer in 0..4u64 {
+            let own: Vec<_> = received.iter().filter(|v| **v / 100 == producer).collect();
+            assert!(own.windows(2).all(|w| w[0] < w[1]));
+        }
+    }
+
+    #[test]
+    fn test_bounded_senders_fail_once_the_receiver_is_dropped() {
+        let executor = Executor::with_workers(2);
+        let failures = executor.block_on(async {
+            let (tx, rx) = bounded(1);
+            tx.send(0).await.unwrap();
+
+            let (done_tx, done_rx) = Channel::new();
+            let done_tx = Arc::new(done_tx);
+            for i in 1..=3 {
+                let (tx, done_tx) = (tx.clone(), done_tx.clone());
+                spawn(async move {
+                    done_tx.send(tx.send(i).await.is_err()).ok();
+                });
+            }
+            drop(done_tx);
+            AsyncTimer::new(Duration::from_millis(10)).await;
+            drop(rx);
+
+            let mut failures = 0;
+            while let Some(failed) = done_rx.recv().await {
+                failures += failed as usize;
+            }
+            failures
+        });
+        assert_eq!(failures, 3);
+    }
+
+    #[test]
+    fn test_broadcast_fans_out_and_reports_lag() {
+        let executor = Executor::with_workers(4);
+        let (sums, lagged) = executor.block_on(async {
+            let (tx, first) = broadcast::<u64>(16);
+            let mut receivers = vec![first];
+            receivers.extend((0..3).map(|_| tx.subscribe()));
+
+            let (sum_tx, sum_rx) = Channel::new();
+            let sum_tx = Arc::new(sum_tx);
+            for mut rx in receivers {
+                let sum_tx = sum_tx.clone();
+                spawn(async move {
+                    let mut sum = 0;
+                    loop {
+                        match rx.recv().await {
+                            Ok(value) => sum += value,
+                            Err(BroadcastError::Closed) => break,
+                            Err(BroadcastError::Lagged(_)) => panic!("fast receiver lagged"),
+                        }
+                    }
+                    sum_tx.send(sum).ok();
+                });
+            }
+            drop(sum_tx);
+
+            for i in 1..=100 {
+                tx.send(i).unwrap();
+                if i % 4 == 0 {
+                    AsyncTimer::new(Duration::from_millis(1)).await;
+                }
+            }
+            drop(tx);
+
+            let (tx, mut slow) = broadcast(4);
+            for i in 1..=10 {
+                tx.send(i).unwrap();
+            }
+            let lagged = slow.recv().await;
+            assert_eq!(slow.recv().await, Ok(7));
+
+            let mut sums = Vec::new();
+            while let Some(sum) = sum_rx.recv().await {
+                sums.push(sum);
+            }
+            (sums, lagged)
+        });
+
+        assert_eq!(sums, vec![5050; 4]);
+        assert_eq!(lagged, Err(BroadcastError::Lagged(6)));
+    }
+
+    // Flags `dropped` when the future holding it is dropped.
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_abort_drops_the_task_and_reports_cancellation() {
+        let executor = Executor::with_workers(2);
+        let reactor = executor.reactor().clone();
+        let (result, dropped, finished) = executor.block_on(async {
+            let dropped = Arc::new(AtomicBool::new(false));
+            let finished = Arc::new(AtomicBool::new(false));
+            let (flag, done) = (DropFlag(dropped.clone()), finished.clone());
+
+            let handle = spawn(async move {
+                let _flag = flag;
+                AsyncTimer::new(Duration::from_secs(60)).await;
+                done.store(true, Ordering::SeqCst);
+            });
+            AsyncTimer::new(Duration::from_millis(5)).await;
+            assert!(!handle.is_finished());
+            handle.abort();
+            let result = handle.await;
+            (result, dropped.load(Ordering::SeqCst), finished.load(Ordering::SeqCst))
+        });
+
+        assert!(result.unwrap_err().is_cancelled());
+        assert!(dropped);
+        assert!(!finished);
+        assert_eq!(reactor.timers.lock().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_abort_after_completion_keeps_the_output() {
+        let executor = Executor::with_workers(2);
+        let result = executor.block_on(async {
+            let handle = spawn(async { 7 });
+            while !handle.is_finished() {
+                yield_now().await;
+            }
+            handle.abort();
+            handle.await
+        });
+        assert_eq!(result.unwrap(), 7);
+    }
+
+    #[test]
+    fn test_panicking_task_becomes_a_join_error() {
+        let executor = Executor::with_workers(2);
+        let (error, survivor) = executor.block_on(async {
+            let failing = spawn(async {
+                yield_now().await;
+                panic!("task exploded");
+            });
+            let survivor = spawn(async {
+                AsyncTimer::new(Duration::from_millis(5)).await;
+                "still running"
+            });
+            (failing.await.unwrap_err(), survivor.await.unwrap())
+        });
+
+        assert!(error.is_panic());
+        assert_eq!(error.to_string(), "task panicked: task exploded");
+        assert_eq!(error.into_panic().downcast_ref::<&str>(), Some(&"task exploded"));
+        assert_eq!(survivor, "still running");
+    }
+
+    #[test]
+    fn test_dropping_a_handle_detaches_unless_abort_on_drop() {
+        let executor = Executor::with_workers(2);
+        let (detached, aborted) = executor.block_on(async {
+            let detached = Arc::new(AtomicBool::new(false));
+            let aborted = Arc::new(AtomicBool::new(false));
+
+            let flag = detached.clone();
+            drop(spawn(async move {
+                AsyncTimer::new(Duration::from_millis(5)).await;
+                flag.store(true, Ordering::SeqCst);
+            }));
+            let flag = aborted.clone();
+            drop(spawn(async move {
+                AsyncTimer::new(Duration::from_millis(5)).await;
+                flag.store(true, Ordering::SeqCst);
+            }).abort_on_drop());
+
+            AsyncTimer::new(Duration::from_millis(30)).await;
+            (detached.load(Ordering::SeqCst), aborted.load(Ordering::SeqCst))
+        });
+
+        assert!(detached);
+        assert!(!aborted);
+    }
+
+    #[test]
+    fn test_task_scope_waits_for_every_child() {
+        let executor = Executor::with_workers(4);
+        let (finished, total) = executor.block_on(async {
+            let finished = Arc::new(AtomicUsize::new(0));
+            let counted = finished.clone();
+            let total = task_scope(|scope| async move {
+                for i in 0..10u64 {
+                    let (finished, nested) = (counted.clone(), scope.clone());
+                    scope.spawn(async move {
+                        AsyncTimer::new(Duration::from_millis(i * 2)).await;
+                        // Children may spawn siblings; the scope waits for those too.
+                        let finished_too = finished.clone();
+                        nested.spawn(async move {
+                            yield_now().await;
+                            finished_too.fetch_add(1, Ordering::SeqCst);
+                        });
+                        finished.fetch_add(1, Ordering::SeqCst);
+                    });
+                }
+                10
+            }).await;
+            (finished.load(Ordering::SeqCst), total)
+        });
+
+        assert_eq!(finished, 20);
+        assert_eq!(total, 10);
+    }
+
+    #[test]
+    fn test_task_scope_cancel_stops_children_before_returning() {
+        let executor = Executor::with_workers(4);
+        let (first, all_dropped) = executor.block_on(async {
+            let flags: Vec<_> = (0..5).map(|_| Arc::new(AtomicBool::new(false))).collect();
+            let children_flags = flags.clone();
+
+            let first = task_scope(|scope| async move {
+                let (tx, rx) = oneshot();
+                let tx = Arc::new(Mutex::new(Some(tx)));
+                for (i, dropped) in children_flags.into_iter().enumerate() {
+                    let tx = tx.clone();
+                    scope.spawn(async move {
+                        let _flag = DropFlag(dropped);
+                        AsyncTimer::new(Duration::from_millis(if i == 3 { 1 } else { 60_000 })).await;
+                        if let Some(tx) = tx.lock().unwrap().take() {
+                            tx.send(i).ok();
+                        }
+                        poll_fn(|_| Poll::<()>::Pending).await;
+                    });
+                }
+                let first = rx.await.unwrap();
+                scope.cancel();
+                assert!(scope.is_cancelled());
+                let late = scope.spawn(async { "never runs" });
+                assert!(late.await.unwrap_err().is_cancelled());
+                first
+            }).await;
+
+            (first, flags.iter().all(|flag| flag.load(Ordering::SeqCst)))
+        });
+
+        assert_eq!(first, 3);
+        assert!(all_dropped);
+    }
+
+    #[test]
+    fn test_dropping_a_task_scope_cancels_its_children() {
+        let executor = Executor::with_workers(2);
+        let dropped = executor.block_on(async {
+            let dropped = Arc::new(AtomicBool::new(false));
+            let flag = dropped.clone();
+            let outcome = timeout(Duration::from_millis(10), task_scope(|scope| async move {
+                scope.spawn(async move {
+                    let _flag = DropFlag(flag);
+                    AsyncTimer::new(Duration::from_secs(60)).await;
+                });
+            })).await;
+            assert!(outcome.is_err());
+
+            while !dropped.load(Ordering::SeqCst) {
+                yield_now().await;
+            }
+            true
+        });
+        assert!(dropped);
+    }
+
+    #[test]
+    fn test_dropping_the_executor_frees_pending_tasks() {
+        let flags: Vec<_> = (0..50).map(|_| Arc::new(AtomicBool::new(false))).collect();
+        for flag in &flags {
+            let executor = Executor::with_workers(2);
+            let reactor = Arc::downgrade(executor.reactor());
+            let flag = flag.clone();
+            executor.block_on(async move {
+                current_executor().spawn(async move {
+                    let _flag = DropFlag(flag);
+                    AsyncTimer::new(Duration::from_secs(3600)).await;
+                });
+                AsyncTimer::new(Duration::from_millis(1)).await;
+            });
+
+            drop(executor);
+            // The reactor owns the epoll and eventfd descriptors.
+            assert!(reactor.upgrade().is_none());
+        }
+        assert!(flags.iter().all(|flag| flag.load(Ordering::SeqCst)));
+    }
+}
@@ -1,5 +1,13 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
 use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone, PartialEq)]
 enum TokenType {
@@ -35,6 +43,9 @@ enum TokenType {
     Else,
     While,
     For,
+    In,
+    Break,
+    Continue,
     Return,
     True,
     False,
@@ -42,27 +53,184 @@ enum TokenType {
     And,
     Or,
     Not,
-    EOF,
+    Import,
+    Throw,
+    Try,
+    Catch,
+    Eof,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+struct Span {
+    start: usize,
+    end: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Span {
+    fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start,
+            end: other.end.max(self.end),
+            line: self.line,
+            column: self.column,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ErrorKind {
+    UnexpectedCharacter,
+    UnterminatedString,
+    UnexpectedToken,
+    InvalidAssignmentTarget,
+    LoopControlOutsideLoop,
+    UndefinedVariable,
+    TypeError,
+    IndexOutOfBounds,
+    ArgumentCount,
+    NotCallable,
+    NativeError,
+    DivisionByZero,
+    Thrown,
+    UseBeforeDefine,
+    DuplicateDeclaration,
+    ReturnOutsideFunction,
+    UnreachableCode,
+    ImportError,
+    ImportCycle,
+    Unsupported,
+    LimitExceeded,
+    Internal,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct LangError {
+    kind: ErrorKind,
+    message: String,
+    span: Option<Span>,
+    // Names of the functions active where the error was raised, innermost
+    // first; filled in as the error leaves its first call frame.
+    stack: Option<Vec<String>>,
+}
+
+impl LangError {
+    fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        LangError {
+            kind,
+            message: message.into(),
+            span: None,
+            stack: None,
+        }
+    }
+
+    fn at(kind: ErrorKind, message: impl Into<String>, span: Span) -> Self {
+        LangError {
+            kind,
+            message: message.into(),
+            span: Some(span),
+            stack: None,
+        }
+    }
+
+    // Errors keep the innermost span, so the caret points at the operation
+    // that actually failed rather than the statement around it.
+    fn or_span(mut self, span: Span) -> Self {
+        if self.span.is_none() {
+            self.span = Some(span);
+        }
+        self
+    }
+
+    fn or_stack(mut self, stack: impl FnOnce() -> Vec<String>) -> Self {
+        if self.stack.is_none() {
+            self.stack = Some(stack());
+        }
+        self
+    }
+
+    /// Whether `try`/`catch` may handle this error. Exceeded limits must stay
+    /// fatal or a script could catch its way past its sandbox.
+    fn is_catchable(&self) -> bool {
+        !matches!(self.kind, ErrorKind::LimitExceeded | ErrorKind::Internal)
+    }
+
+    fn render(&self, source: &str) -> String {
+        let mut output = format!("error[{:?}]: {}", self.kind, self.message);
+
+        let Some(span) = self.span else {
+            return output;
+        };
+        let Some(line_text) = source.lines().nth(span.line.saturating_sub(1)) else {
+            return output;
+        };
+
+        let gutter = span.line.to_string().len();
+        let line_len = line_text.chars().count();
+        let width = (span.end - span.start).clamp(1, (line_len + 1).saturating_sub(span.column).max(1));
+
+        output.push_str(&format!("\n{:>w$}--> line {}, column {}", "", span.line, span.column, w = gutter));
+        output.push_str(&format!("\n{:>w$} |", "", w = gutter));
+        output.push_str(&format!("\n{} | {}", span.line, line_text));
+        output.push_str(&format!(
+            "\n{:>w$} | {}{}",
+            "",
+            " ".repeat(span.column.saturating_sub(1)),
+            "^".repeat(width),
+            w = gutter
+        ));
+        output
+    }
+}
+
+impl fmt::Display for LangError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "{} (line {}, column {})", self.message, span.line, span.column),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+fn render_errors(errors: &[LangError], source: &str) -> String {
+    errors.iter().map(|e| e.render(source)).collect::<Vec<_>>().join("\n\n")
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Token {
+    kind: TokenType,
+    span: Span,
 }
 
 struct Lexer {
     input: Vec<char>,
     position: usize,
     current_char: Option<char>,
+    line: usize,
+    column: usize,
 }
 
 impl Lexer {
     fn new(input: String) -> Self {
         let chars: Vec<char> = input.chars().collect();
-        let current_char = chars.get(0).copied();
+        let current_char = chars.first().copied();
         Lexer {
             input: chars,
             position: 0,
             current_char,
+            line: 1,
+            column: 1,
         }
     }
 
     fn advance(&mut self) {
+        if self.current_char == Some('\n') {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
         self.position += 1;
         self.current_char = self.input.get(self.position).copied();
     }
@@ -71,6 +239,15 @@ impl Lexer {
         self.input.get(self.position + offset).copied()
     }
 
+    fn span_from(&self, start: usize, line: usize, column: usize) -> Span {
+        Span {
+            start,
+            end: self.position,
+            line,
+            column,
+        }
+    }
+
     fn skip_whitespace(&mut self) {
         while let Some(ch) = self.current_char {
             if ch.is_whitespace() {
@@ -111,14 +288,14 @@ impl Lexer {
         ident
     }
 
-    fn read_string(&mut self) -> String {
+    fn read_string(&mut self) -> Option<String> {
         let mut string = String::new();
         self.advance();
 
         while let Some(ch) = self.current_char {
             if ch == '"' {
                 self.advance();
-                break;
+                return Some(string);
             }
             if ch == '\\' {
                 self.advance();
@@ -137,28 +314,41 @@ impl Lexer {
             }
         }
 
-        string
+        None
     }
 
-    fn next_token(&mut self) -> TokenType {
+    fn next_token(&mut self) -> Result<Token, LangError> {
         self.skip_whitespace();
+        let (start, line, column) = (self.position, self.line, self.column);
+        let kind = self.next_token_kind()?;
+        Ok(Token {
+            kind,
+            span: self.span_from(start, line, column),
+        })
+    }
+
+    fn next_token_kind(&mut self) -> Result<TokenType, LangError> {
+        let (start, line, column) = (self.position, self.line, self.column);
 
         match self.current_char {
-            None => TokenType::EOF,
+            None => Ok(TokenType::Eof),
             Some(ch) => {
                 if ch.is_numeric() {
-                    return TokenType::Number(self.read_number());
+                    return Ok(TokenType::Number(self.read_number()));
                 }
 
                 if ch.is_alphabetic() || ch == '_' {
                     let ident = self.read_identifier();
-                    return match ident.as_str() {
+                    return Ok(match ident.as_str() {
                         "let" => TokenType::Let,
                         "fn" => TokenType::Fn,
                         "if" => TokenType::If,
                         "else" => TokenType::Else,
                         "while" => TokenType::While,
                         "for" => TokenType::For,
+                        "in" => TokenType::In,
+                        "break" => TokenType::Break,
+                        "continue" => TokenType::Continue,
                         "return" => TokenType::Return,
                         "true" => TokenType::True,
                         "false" => TokenType::False,
@@ -166,12 +356,23 @@ impl Lexer {
                         "and" => TokenType::And,
                         "or" => TokenType::Or,
                         "not" => TokenType::Not,
+                        "import" => TokenType::Import,
+                        "throw" => TokenType::Throw,
+                        "try" => TokenType::Try,
+                        "catch" => TokenType::Catch,
                         _ => TokenType::Identifier(ident),
-                    };
+                    });
                 }
 
                 if ch == '"' {
-                    return TokenType::String(self.read_string());
+                    return match self.read_string() {
+                        Some(s) => Ok(TokenType::String(s)),
+                        None => Err(LangError::at(
+                            ErrorKind::UnterminatedString,
+                            "Unterminated string literal",
+                            self.span_from(start, line, column),
+                        )),
+                    };
                 }
 
                 let token = match ch {
@@ -229,28 +430,44 @@ impl Lexer {
                             TokenType::Greater
                         }
                     }
-                    _ => TokenType::EOF,
+                    _ => {
+                        self.advance();
+                        return Err(LangError::at(
+                            ErrorKind::UnexpectedCharacter,
+                            format!("Unexpected character '{}'", ch),
+                            self.span_from(start, line, column),
+                        ));
+                    }
                 };
 
                 self.advance();
-                token
+                Ok(token)
             }
         }
     }
 
-    fn tokenize(&mut self) -> Vec<TokenType> {
+    fn tokenize(&mut self) -> Result<Vec<Token>, Vec<LangError>> {
         let mut tokens = Vec::new();
+        let mut errors = Vec::new();
 
         loop {
-            let token = self.next_token();
-            if token == TokenType::EOF {
-                tokens.push(token);
-                break;
+            match self.next_token() {
+                Ok(token) => {
+                    let done = token.kind == TokenType::Eof;
+                    tokens.push(token);
+                    if done {
+                        break;
+                    }
+                }
+                Err(e) => errors.push(e),
             }
-            tokens.push(token);
         }
 
-        tokens
+        if errors.is_empty() {
+            Ok(tokens)
+        } else {
+            Err(errors)
+        }
     }
 }
 
@@ -260,26 +477,61 @@ enum Expr {
     String(String),
     Bool(bool),
     Nil,
-    Identifier(String),
+    Identifier {
+        name: String,
+        depth: Option<usize>,
+        span: Span,
+    },
     Binary {
         left: Box<Expr>,
         op: BinaryOp,
         right: Box<Expr>,
+        span: Span,
     },
     Unary {
         op: UnaryOp,
         expr: Box<Expr>,
+        span: Span,
     },
     Call {
         callee: Box<Expr>,
         args: Vec<Expr>,
+        span: Span,
     },
     Index {
         object: Box<Expr>,
         index: Box<Expr>,
+        span: Span,
     },
     Array(Vec<Expr>),
-    Object(HashMap<String, Expr>),
+    Object(Vec<(String, Expr)>),
+    Property {
+        object: Box<Expr>,
+        name: String,
+        span: Span,
+    },
+    Assign {
+        name: String,
+        value: Box<Expr>,
+        depth: Option<usize>,
+        span: Span,
+    },
+    IndexAssign {
+        object: Box<Expr>,
+        index: Box<Expr>,
+        value: Box<Expr>,
+        span: Span,
+    },
+    PropertyAssign {
+        object: Box<Expr>,
+        name: String,
+        value: Box<Expr>,
+        span: Span,
+    },
+    Function {
+        params: Vec<String>,
+        body: Rc<Vec<Stmt>>,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -308,26 +560,54 @@ enum UnaryOp {
 #[derive(Debug, Clone)]
 enum Stmt {
     Expression(Expr),
-    Let { name: String, value: Expr },
-    Function { name: String, params: Vec<String>, body: Vec<Stmt> },
-    Return(Option<Expr>),
+    Let { name: String, value: Expr, span: Span },
+    Function { name: String, params: Vec<String>, body: Rc<Vec<Stmt>>, span: Span },
+    Return(Option<Expr>, Span),
     If { condition: Expr, then_branch: Vec<Stmt>, else_branch: Option<Vec<Stmt>> },
     While { condition: Expr, body: Vec<Stmt> },
+    For {
+        initializer: Option<Box<Stmt>>,
+        condition: Option<Expr>,
+        increment: Option<Expr>,
+        body: Vec<Stmt>,
+    },
+    ForIn { variable: String, iterable: Expr, body: Vec<Stmt> },
+    Break,
+    Continue,
     Block(Vec<Stmt>),
+    Import { path: String, alias: String, span: Span },
+    Throw(Expr, Span),
+    Try { body: Vec<Stmt>, variable: String, handler: Vec<Stmt> },
 }
 
 struct Parser {
-    tokens: Vec<TokenType>,
+    tokens: Vec<Token>,
     position: usize,
+    loop_depth: usize,
+    errors: Vec<LangError>,
 }
 
 impl Parser {
-    fn new(tokens: Vec<TokenType>) -> Self {
-        Parser { tokens, position: 0 }
+    fn new(tokens: Vec<Token>) -> Self {
+        Parser { tokens, position: 0, loop_depth: 0, errors: Vec::new() }
     }
 
     fn current(&self) -> &TokenType {
-        self.tokens.get(self.position).unwrap_or(&TokenType::EOF)
+        self.peek(0)
+    }
+
+    fn peek(&self, offset: usize) -> &TokenType {
+        self.tokens.get(self.position + offset).map_or(&TokenType::Eof, |t| &t.kind)
+    }
+
+    fn current_span(&self) -> Span {
+        self.tokens.get(self.position)
+            .or_else(|| self.tokens.last())
+            .map_or_else(Span::default, |t| t.span)
+    }
+
+    fn previous_span(&self) -> Span {
+        self.tokens.get(self.position.saturating_sub(1)).map_or_else(Span::default, |t| t.span)
     }
 
     fn advance(&mut self) {
@@ -336,32 +616,107 @@ impl Parser {
         }
     }
 
-    fn expect(&mut self, expected: TokenType) -> Result<(), String> {
+    fn error(&self, kind: ErrorKind, message: impl Into<String>) -> LangError {
+        LangError::at(kind, message, self.current_span())
+    }
+
+    fn expect(&mut self, expected: TokenType) -> Result<(), LangError> {
         if std::mem::discriminant(self.current()) == std::mem::discriminant(&expected) {
             self.advance();
             Ok(())
         } else {
-            Err(format!("Expected {:?}, found {:?}", expected, self.current()))
+            Err(self.error(
+                ErrorKind::UnexpectedToken,
+                format!("Expected {:?}, found {:?}", expected, self.current()),
+            ))
         }
     }
 
-    fn parse_program(&mut self) -> Result<Vec<Stmt>, String> {
+    fn parse_program(&mut self) -> Result<Vec<Stmt>, Vec<LangError>> {
         let mut statements = Vec::new();
 
-        while *self.current() != TokenType::EOF {
-            statements.push(self.parse_statement()?);
+        while *self.current() != TokenType::Eof {
+            let start = self.position;
+            match self.parse_statement() {
+                Ok(stmt) => statements.push(stmt),
+                Err(e) => self.recover(e, start),
+            }
+
+            if *self.current() == TokenType::RightBrace {
+                let e = self.error(ErrorKind::UnexpectedToken, "Unmatched '}'");
+                self.errors.push(e);
+                self.advance();
+            }
         }
 
-        Ok(statements)
+        if self.errors.is_empty() {
+            Ok(statements)
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    // Panic-mode recovery: record the error and skip ahead to something that
+    // looks like a statement boundary so later errors can still be reported.
+    fn recover(&mut self, error: LangError, start: usize) {
+        self.errors.push(error);
+
+        if self.position == start {
+            self.advance();
+        }
+
+        loop {
+            match self.current() {
+                TokenType::Eof | TokenType::RightBrace => return,
+                TokenType::Semicolon => {
+                    self.advance();
+                    return;
+                }
+                TokenType::Let | TokenType::Fn | TokenType::If | TokenType::While | TokenType::For
+                | TokenType::Return | TokenType::Break | TokenType::Continue | TokenType::Import
+                | TokenType::Throw | TokenType::Try => return,
+                TokenType::LeftBrace => {
+                    self.skip_braces();
+                    return;
+                }
+                _ => self.advance(),
+            }
+        }
+    }
+
+    fn skip_braces(&mut self) {
+        let mut depth = 0;
+        loop {
+            match self.current() {
+                TokenType::Eof => return,
+                TokenType::LeftBrace => depth += 1,
+                TokenType::RightBrace => {
+                    depth -= 1;
+                    if depth == 0 {
+                        self.advance();
+                        return;
+                    }
+                }
+                _ => {}
+            }
+            self.advance();
+        }
     }
 
-    fn parse_statement(&mut self) -> Result<Stmt, String> {
+    fn parse_statement(&mut self) -> Result<Stmt, LangError> {
         match self.current().clone() {
             TokenType::Let => self.parse_let_statement(),
-            TokenType::Fn => self.parse_function(),
+            TokenType::Fn if matches!(self.peek(1), TokenType::Identifier(_)) => {
+                self.parse_function()
+            }
             TokenType::Return => self.parse_return(),
             TokenType::If => self.parse_if(),
             TokenType::While => self.parse_while(),
+            TokenType::For => self.parse_for(),
+            TokenType::Break | TokenType::Continue => self.parse_loop_control(),
+            TokenType::Import => self.parse_import(),
+            TokenType::Throw => self.parse_throw(),
+            TokenType::Try => self.parse_try(),
             TokenType::LeftBrace => self.parse_block(),
             _ => {
                 let expr = self.parse_expression()?;
@@ -371,31 +726,54 @@ impl Parser {
         }
     }
 
-    fn parse_let_statement(&mut self) -> Result<Stmt, String> {
+    fn parse_statements(&mut self) -> Result<Vec<Stmt>, LangError> {
+        self.expect(TokenType::LeftBrace)?;
+        let mut statements = Vec::new();
+
+        while !matches!(self.current(), TokenType::RightBrace | TokenType::Eof) {
+            let start = self.position;
+            match self.parse_statement() {
+                Ok(stmt) => statements.push(stmt),
+                Err(e) => self.recover(e, start),
+            }
+        }
+
+        self.expect(TokenType::RightBrace)?;
+        Ok(statements)
+    }
+
+    fn parse_let_statement(&mut self) -> Result<Stmt, LangError> {
         self.advance();
         
         let name = match self.current() {
             TokenType::Identifier(n) => n.clone(),
-            _ => return Err("Expected identifier".to_string()),
+            _ => return Err(self.error(ErrorKind::UnexpectedToken, "Expected identifier")),
         };
+        let span = self.current_span();
         self.advance();
 
         self.expect(TokenType::Equal)?;
         let value = self.parse_expression()?;
         self.expect(TokenType::Semicolon)?;
 
-        Ok(Stmt::Let { name, value })
+        Ok(Stmt::Let { name, value, span })
     }
 
-    fn parse_function(&mut self) -> Result<Stmt, String> {
+    fn parse_function(&mut self) -> Result<Stmt, LangError> {
         self.advance();
 
         let name = match self.current() {
             TokenType::Identifier(n) => n.clone(),
-            _ => return Err("Expected function name".to_string()),
+            _ => return Err(self.error(ErrorKind::UnexpectedToken, "Expected function name")),
         };
+        let span = self.current_span();
         self.advance();
 
+        let (params, body) = self.parse_function_rest()?;
+        Ok(Stmt::Function { name, params, body, span })
+    }
+
+    fn parse_function_rest(&mut self) -> Result<(Vec<String>, Rc<Vec<Stmt>>), LangError> {
         self.expect(TokenType::LeftParen)?;
         let mut params = Vec::new();
 
@@ -413,19 +791,16 @@ impl Parser {
         }
 
         self.expect(TokenType::RightParen)?;
-        self.expect(TokenType::LeftBrace)?;
-
-        let mut body = Vec::new();
-        while *self.current() != TokenType::RightBrace {
-            body.push(self.parse_statement()?);
-        }
 
-        self.expect(TokenType::RightBrace)?;
+        let enclosing_loops = std::mem::replace(&mut self.loop_depth, 0);
+        let body = self.parse_statements();
+        self.loop_depth = enclosing_loops;
 
-        Ok(Stmt::Function { name, params, body })
+        Ok((params, Rc::new(body?)))
     }
 
-    fn parse_return(&mut self) -> Result<Stmt, String> {
+    fn parse_return(&mut self) -> Result<Stmt, LangError> {
+        let span = self.current_span();
         self.advance();
 
         let value = if *self.current() == TokenType::Semicolon {
@@ -435,31 +810,46 @@ impl Parser {
         };
 
         self.expect(TokenType::Semicolon)?;
-        Ok(Stmt::Return(value))
+        Ok(Stmt::Return(value, span))
+    }
+
+    fn parse_throw(&mut self) -> Result<Stmt, LangError> {
+        let span = self.current_span();
+        self.advance();
+
+        let value = self.parse_expression()?;
+        self.expect(TokenType::Semicolon)?;
+        Ok(Stmt::Throw(value, span))
+    }
+
+    fn parse_try(&mut self) -> Result<Stmt, LangError> {
+        self.advance();
+        let body = self.parse_statements()?;
+
+        self.expect(TokenType::Catch)?;
+        self.expect(TokenType::LeftParen)?;
+        let variable = match self.current() {
+            TokenType::Identifier(name) => name.clone(),
+            _ => return Err(self.error(ErrorKind::UnexpectedToken, "Expected a variable name after 'catch ('")),
+        };
+        self.advance();
+        self.expect(TokenType::RightParen)?;
+
+        let handler = self.parse_statements()?;
+        Ok(Stmt::Try { body, variable, handler })
     }
 
-    fn parse_if(&mut self) -> Result<Stmt, String> {
+    fn parse_if(&mut self) -> Result<Stmt, LangError> {
         self.advance();
         self.expect(TokenType::LeftParen)?;
         let condition = self.parse_expression()?;
         self.expect(TokenType::RightParen)?;
 
-        self.expect(TokenType::LeftBrace)?;
-        let mut then_branch = Vec::new();
-        while *self.current() != TokenType::RightBrace {
-            then_branch.push(self.parse_statement()?);
-        }
-        self.expect(TokenType::RightBrace)?;
+        let then_branch = self.parse_statements()?;
 
         let else_branch = if *self.current() == TokenType::Else {
             self.advance();
-            self.expect(TokenType::LeftBrace)?;
-            let mut else_stmts = Vec::new();
-            while *self.current() != TokenType::RightBrace {
-                else_stmts.push(self.parse_statement()?);
-            }
-            self.expect(TokenType::RightBrace)?;
-            Some(else_stmts)
+            Some(self.parse_statements()?)
         } else {
             None
         };
@@ -467,71 +857,186 @@ impl Parser {
         Ok(Stmt::If { condition, then_branch, else_branch })
     }
 
-    fn parse_while(&mut self) -> Result<Stmt, String> {
+    fn parse_while(&mut self) -> Result<Stmt, LangError> {
         self.advance();
         self.expect(TokenType::LeftParen)?;
         let condition = self.parse_expression()?;
         self.expect(TokenType::RightParen)?;
 
-        self.expect(TokenType::LeftBrace)?;
-        let mut body = Vec::new();
-        while *self.current() != TokenType::RightBrace {
-            body.push(self.parse_statement()?);
+        let body = self.parse_loop_body()?;
+        Ok(Stmt::While { condition, body })
+    }
+
+    fn parse_for(&mut self) -> Result<Stmt, LangError> {
+        self.advance();
+        self.expect(TokenType::LeftParen)?;
+
+        if let (TokenType::Identifier(variable), TokenType::In) = (self.current().clone(), self.peek(1)) {
+            self.advance();
+            self.advance();
+            let iterable = self.parse_expression()?;
+            self.expect(TokenType::RightParen)?;
+
+            let body = self.parse_loop_body()?;
+            return Ok(Stmt::ForIn { variable, iterable, body });
         }
-        self.expect(TokenType::RightBrace)?;
 
-        Ok(Stmt::While { condition, body })
+        let initializer = match self.current() {
+            TokenType::Semicolon => {
+                self.advance();
+                None
+            }
+            TokenType::Let => Some(Box::new(self.parse_let_statement()?)),
+            _ => {
+                let expr = self.parse_expression()?;
+                self.expect(TokenType::Semicolon)?;
+                Some(Box::new(Stmt::Expression(expr)))
+            }
+        };
+
+        let condition = if *self.current() == TokenType::Semicolon {
+            None
+        } else {
+            Some(self.parse_expression()?)
+        };
+        self.expect(TokenType::Semicolon)?;
+
+        let increment = if *self.current() == TokenType::RightParen {
+            None
+        } else {
+            Some(self.parse_expression()?)
+        };
+        self.expect(TokenType::RightParen)?;
+
+        let body = self.parse_loop_body()?;
+        Ok(Stmt::For { initializer, condition, increment, body })
     }
 
-    fn parse_block(&mut self) -> Result<Stmt, String> {
-        self.expect(TokenType::LeftBrace)?;
-        let mut statements = Vec::new();
+    fn parse_loop_body(&mut self) -> Result<Vec<Stmt>, LangError> {
+        self.loop_depth += 1;
+        let body = self.parse_statements();
+        self.loop_depth -= 1;
+        body
+    }
+
+    fn parse_import(&mut self) -> Result<Stmt, LangError> {
+        let span = self.current_span();
+        self.advance();
+
+        let path = match self.current() {
+            TokenType::String(path) => path.clone(),
+            _ => return Err(self.error(ErrorKind::UnexpectedToken, "Expected a module path string after 'import'")),
+        };
+        self.advance();
+
+        // `as` is only special here, so it stays usable as a variable name.
+        let alias = if *self.current() == TokenType::Identifier("as".to_string()) {
+            self.advance();
+            match self.current() {
+                TokenType::Identifier(alias) => {
+                    let alias = alias.clone();
+                    self.advance();
+                    alias
+                }
+                _ => return Err(self.error(ErrorKind::UnexpectedToken, "Expected a module name after 'as'")),
+            }
+        } else {
+            match module_name(&path) {
+                Some(name) => name,
+                None => {
+                    let message = format!("Cannot name module \"{}\"; use `import \"{}\" as name;`", path, path);
+                    return Err(LangError::at(ErrorKind::UnexpectedToken, message, span));
+                }
+            }
+        };
+
+        self.expect(TokenType::Semicolon)?;
+        Ok(Stmt::Import { path, alias, span })
+    }
+
+    fn parse_loop_control(&mut self) -> Result<Stmt, LangError> {
+        let stmt = match self.current() {
+            TokenType::Break => Stmt::Break,
+            _ => Stmt::Continue,
+        };
 
-        while *self.current() != TokenType::RightBrace {
-            statements.push(self.parse_statement()?);
+        if self.loop_depth == 0 {
+            return Err(self.error(
+                ErrorKind::LoopControlOutsideLoop,
+                format!("{:?} outside of a loop", self.current()),
+            ));
         }
 
-        self.expect(TokenType::RightBrace)?;
-        Ok(Stmt::Block(statements))
+        self.advance();
+        self.expect(TokenType::Semicolon)?;
+        Ok(stmt)
+    }
+
+    fn parse_block(&mut self) -> Result<Stmt, LangError> {
+        Ok(Stmt::Block(self.parse_statements()?))
     }
 
-    fn parse_expression(&mut self) -> Result<Expr, String> {
-        self.parse_or()
+    fn parse_expression(&mut self) -> Result<Expr, LangError> {
+        self.parse_assignment()
     }
 
-    fn parse_or(&mut self) -> Result<Expr, String> {
+    fn parse_assignment(&mut self) -> Result<Expr, LangError> {
+        let start = self.current_span();
+        let target = self.parse_or()?;
+
+        if *self.current() != TokenType::Equal {
+            return Ok(target);
+        }
+        let equals = self.current_span();
+        self.advance();
+        let value = Box::new(self.parse_assignment()?);
+        let span = start.to(self.previous_span());
+
+        match target {
+            Expr::Identifier { name, .. } => Ok(Expr::Assign { name, value, depth: None, span }),
+            Expr::Index { object, index, .. } => Ok(Expr::IndexAssign { object, index, value, span }),
+            Expr::Property { object, name, .. } => Ok(Expr::PropertyAssign { object, name, value, span }),
+            _ => Err(LangError::at(ErrorKind::InvalidAssignmentTarget, "Invalid assignment target", equals)),
+        }
+    }
+
+    fn binary(&self, left: Expr, op: BinaryOp, right: Expr, start: Span) -> Expr {
+        Expr::Binary {
+            left: Box::new(left),
+            op,
+            right: Box::new(right),
+            span: start.to(self.previous_span()),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, LangError> {
+        let start = self.current_span();
         let mut left = self.parse_and()?;
 
         while *self.current() == TokenType::Or {
             self.advance();
             let right = self.parse_and()?;
-            left = Expr::Binary {
-                left: Box::new(left),
-                op: BinaryOp::Or,
-                right: Box::new(right),
-            };
+            left = self.binary(left, BinaryOp::Or, right, start);
         }
 
         Ok(left)
     }
 
-    fn parse_and(&mut self) -> Result<Expr, String> {
+    fn parse_and(&mut self) -> Result<Expr, LangError> {
+        let start = self.current_span();
         let mut left = self.parse_equality()?;
 
         while *self.current() == TokenType::And {
             self.advance();
             let right = self.parse_equality()?;
-            left = Expr::Binary {
-                left: Box::new(left),
-                op: BinaryOp::And,
-                right: Box::new(right),
-            };
+            left = self.binary(left, BinaryOp::And, right, start);
         }
 
         Ok(left)
     }
 
-    fn parse_equality(&mut self) -> Result<Expr, String> {
+    fn parse_equality(&mut self) -> Result<Expr, LangError> {
+        let start = self.current_span();
         let mut left = self.parse_comparison()?;
 
         while matches!(self.current(), TokenType::EqualEqual | TokenType::BangEqual) {
@@ -542,17 +1047,14 @@ impl Parser {
             };
             self.advance();
             let right = self.parse_comparison()?;
-            left = Expr::Binary {
-                left: Box::new(left),
-                op,
-                right: Box::new(right),
-            };
+            left = self.binary(left, op, right, start);
         }
 
         Ok(left)
     }
 
-    fn parse_comparison(&mut self) -> Result<Expr, String> {
+    fn parse_comparison(&mut self) -> Result<Expr, LangError> {
+        let start = self.current_span();
         let mut left = self.parse_term()?;
 
         while matches!(self.current(), TokenType::Less | TokenType::LessEqual | 
@@ -566,17 +1068,14 @@ impl Parser {
             };
             self.advance();
             let right = self.parse_term()?;
-            left = Expr::Binary {
-                left: Box::new(left),
-                op,
-                right: Box::new(right),
-            };
+            left = self.binary(left, op, right, start);
         }
 
         Ok(left)
     }
 
-    fn parse_term(&mut self) -> Result<Expr, String> {
+    fn parse_term(&mut self) -> Result<Expr, LangError> {
+        let start = self.current_span();
         let mut left = self.parse_factor()?;
 
         while matches!(self.current(), TokenType::Plus | TokenType::Minus) {
@@ -587,17 +1086,14 @@ impl Parser {
             };
             self.advance();
             let right = self.parse_factor()?;
-            left = Expr::Binary {
-                left: Box::new(left),
-                op,
-                right: Box::new(right),
-            };
+            left = self.binary(left, op, right, start);
         }
 
         Ok(left)
     }
 
-    fn parse_factor(&mut self) -> Result<Expr, String> {
+    fn parse_factor(&mut self) -> Result<Expr, LangError> {
+        let start = self.current_span();
         let mut left = self.parse_unary()?;
 
         while matches!(self.current(), TokenType::Star | TokenType::Slash | TokenType::Percent) {
@@ -609,39 +1105,31 @@ impl Parser {
             };
             self.advance();
             let right = self.parse_unary()?;
-            left = Expr::Binary {
-                left: Box::new(left),
-                op,
-                right: Box::new(right),
-            };
+            left = self.binary(left, op, right, start);
         }
 
         Ok(left)
     }
 
-    fn parse_unary(&mut self) -> Result<Expr, String> {
-        match self.current() {
-            TokenType::Minus => {
-                self.advance();
-                let expr = self.parse_unary()?;
-                Ok(Expr::Unary {
-                    op: UnaryOp::Neg,
-                    expr: Box::new(expr),
-                })
-            }
-            TokenType::Not => {
-                self.advance();
-                let expr = self.parse_unary()?;
-                Ok(Expr::Unary {
-                    op: UnaryOp::Not,
-                    expr: Box::new(expr),
-                })
-            }
-            _ => self.parse_call(),
-        }
+    fn parse_unary(&mut self) -> Result<Expr, LangError> {
+        let start = self.current_span();
+        let op = match self.current() {
+            TokenType::Minus => UnaryOp::Neg,
+            TokenType::Not => UnaryOp::Not,
+            _ => return self.parse_call(),
+        };
+
+        self.advance();
+        let expr = self.parse_unary()?;
+        Ok(Expr::Unary {
+            op,
+            expr: Box::new(expr),
+            span: start.to(self.previous_span()),
+        })
     }
 
-    fn parse_call(&mut self) -> Result<Expr, String> {
+    fn parse_call(&mut self) -> Result<Expr, LangError> {
+        let start = self.current_span();
         let mut expr = self.parse_primary()?;
 
         loop {
@@ -661,6 +1149,7 @@ impl Parser {
                     expr = Expr::Call {
                         callee: Box::new(expr),
                         args,
+                        span: start.to(self.previous_span()),
                     };
                 }
                 TokenType::LeftBracket => {
@@ -670,6 +1159,23 @@ impl Parser {
                     expr = Expr::Index {
                         object: Box::new(expr),
                         index: Box::new(index),
+                        span: start.to(self.previous_span()),
+                    };
+                }
+                TokenType::Dot => {
+                    self.advance();
+                    let name = match self.current() {
+                        TokenType::Identifier(n) => n.clone(),
+                        other => {
+                            let message = format!("Expected property name after '.', found {:?}", other);
+                            return Err(self.error(ErrorKind::UnexpectedToken, message));
+                        }
+                    };
+                    self.advance();
+                    expr = Expr::Property {
+                        object: Box::new(expr),
+                        name,
+                        span: start.to(self.previous_span()),
                     };
                 }
                 _ => break,
@@ -679,7 +1185,7 @@ impl Parser {
         Ok(expr)
     }
 
-    fn parse_primary(&mut self) -> Result<Expr, String> {
+    fn parse_primary(&mut self) -> Result<Expr, LangError> {
         match self.current().clone() {
             TokenType::Number(n) => {
                 self.advance();
@@ -702,8 +1208,9 @@ impl Parser {
                 Ok(Expr::Nil)
             }
             TokenType::Identifier(name) => {
+                let span = self.current_span();
                 self.advance();
-                Ok(Expr::Identifier(name))
+                Ok(Expr::Identifier { name, depth: None, span })
             }
             TokenType::LeftParen => {
                 self.advance();
@@ -711,6 +1218,37 @@ impl Parser {
                 self.expect(TokenType::RightParen)?;
                 Ok(expr)
             }
+            TokenType::Fn => {
+                self.advance();
+                let (params, body) = self.parse_function_rest()?;
+                Ok(Expr::Function { params, body })
+            }
+            TokenType::LeftBrace => {
+                self.advance();
+                let mut fields = Vec::new();
+
+                while *self.current() != TokenType::RightBrace {
+                    let key = match self.current() {
+                        TokenType::Identifier(k) | TokenType::String(k) => k.clone(),
+                        other => {
+                            let message = format!("Expected object key, found {:?}", other);
+                            return Err(self.error(ErrorKind::UnexpectedToken, message));
+                        }
+                    };
+                    self.advance();
+                    self.expect(TokenType::Colon)?;
+                    fields.push((key, self.parse_expression()?));
+
+                    if *self.current() == TokenType::Comma {
+                        self.advance();
+                    } else {
+                        break;
+                    }
+                }
+
+                self.expect(TokenType::RightBrace)?;
+                Ok(Expr::Object(fields))
+            }
             TokenType::LeftBracket => {
                 self.advance();
                 let mut elements = Vec::new();
@@ -725,247 +1263,4281 @@ impl Parser {
                 self.expect(TokenType::RightBracket)?;
                 Ok(Expr::Array(elements))
             }
-            _ => Err(format!("Unexpected token: {:?}", self.current())),
+            _ => Err(self.error(ErrorKind::UnexpectedToken, format!("Unexpected token: {:?}", self.current()))),
         }
     }
 }
 
-#[derive(Debug, Clone)]
-enum Value {
-    Number(f64),
-    String(String),
-    Bool(bool),
-    Nil,
-    Function { params: Vec<String>, body: Vec<Stmt> },
-    Array(Vec<Value>),
+fn parse_source(source: &str) -> Result<Vec<Stmt>, Vec<LangError>> {
+    let tokens = Lexer::new(source.to_string()).tokenize()?;
+    Parser::new(tokens).parse_program()
 }
 
-struct Environment {
-    scopes: Vec<HashMap<String, Value>>,
+// ============= RESOLVER =============
+
+#[derive(Clone, Copy)]
+struct Binding {
+    defined: bool,
+    // Function nesting level the binding was declared at; a pending binding
+    // may still be captured by a nested function that runs after it is defined.
+    function_depth: usize,
 }
 
-impl Environment {
+/// Static pass run before execution. Records how many scopes up each local
+/// variable lives so the tree-walker can jump straight to it, and reports
+/// errors that do not need the program to run. Globals stay late-bound so
+/// the REPL can define and redefine them across inputs, which means the
+/// use-before-define and duplicate `let` checks only cover locals: top-level
+/// code (including `if`/`while` bodies there) is never reported for them.
+struct Resolver {
+    scopes: Vec<HashMap<String, Binding>>,
+    function_depth: usize,
+    errors: Vec<LangError>,
+}
+
+impl Resolver {
     fn new() -> Self {
-        Environment {
-            scopes: vec![HashMap::new()],
+        Resolver {
+            scopes: Vec::new(),
+            function_depth: 0,
+            errors: Vec::new(),
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    fn end_scope(&mut self) {
+        self.scopes.pop();
+    }
+
+    /// Declares every `let` and `fn` of a statement list up front so uses
+    /// before the declaration are caught instead of silently reaching a global.
+    fn declare_all(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            if let Stmt::Let { name, span, .. }
+            | Stmt::Function { name, span, .. }
+            | Stmt::Import { alias: name, span, .. } = stmt
+            {
+                self.declare(name, *span);
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &str, span: Span) {
+        let function_depth = self.function_depth;
+        let Some(scope) = self.scopes.last_mut() else {
+            return;
+        };
+
+        if scope.contains_key(name) {
+            self.duplicate(name, span);
+            return;
         }
+        scope.insert(name.to_string(), Binding { defined: false, function_depth });
+    }
+
+    fn duplicate(&mut self, name: &str, span: Span) {
+        self.errors.push(LangError::at(
+            ErrorKind::DuplicateDeclaration,
+            format!("'{}' is already declared in this scope", name),
+            span,
+        ));
     }
 
-    fn define(&mut self, name: String, value: Value) {
+    fn define(&mut self, name: &str) {
+        let function_depth = self.function_depth;
         if let Some(scope) = self.scopes.last_mut() {
-            scope.insert(name, value);
+            scope.insert(name.to_string(), Binding { defined: true, function_depth });
         }
     }
 
-    fn get(&self, name: &str) -> Option<Value> {
-        for scope in self.scopes.iter().rev() {
-            if let Some(value) = scope.get(name) {
-                return Some(value.clone());
+    fn resolve_local(&mut self, name: &str, span: Span) -> Option<usize> {
+        for (distance, scope) in self.scopes.iter().rev().enumerate() {
+            if let Some(binding) = scope.get(name) {
+                if !binding.defined && binding.function_depth == self.function_depth {
+                    self.errors.push(LangError::at(
+                        ErrorKind::UseBeforeDefine,
+                        format!("'{}' is used before it is defined", name),
+                        span,
+                    ));
+                }
+                return Some(distance);
             }
         }
         None
     }
 
-    fn push_scope(&mut self) {
-        self.scopes.push(HashMap::new());
+    fn resolve_block(&mut self, stmts: &mut [Stmt]) {
+        self.begin_scope();
+        self.declare_all(stmts);
+        self.resolve_statements(stmts);
+        self.end_scope();
     }
 
-    fn pop_scope(&mut self) {
-        self.scopes.pop();
+    /// `if`, `while` and `for` bodies share the enclosing scope, so a `let`
+    /// there may rebind a name the scope already has, as it does at runtime
+    /// on every iteration. Only a repeat within the body itself is an error.
+    fn resolve_unscoped(&mut self, stmts: &mut [Stmt]) {
+        let function_depth = self.function_depth;
+        let mut declared = HashSet::new();
+        for stmt in stmts.iter() {
+            if let Stmt::Let { name, span, .. }
+            | Stmt::Function { name, span, .. }
+            | Stmt::Import { alias: name, span, .. } = stmt
+            {
+                if !declared.insert(name.as_str()) {
+                    self.duplicate(name, *span);
+                } else if let Some(scope) = self.scopes.last_mut() {
+                    scope.entry(name.clone()).or_insert(Binding { defined: false, function_depth });
+                }
+            }
+        }
+        self.resolve_statements(stmts);
     }
-}
-
-struct Interpreter {
-    environment: Environment,
-}
 
-impl Interpreter {
-    fn new() -> Self {
-        Interpreter {
-            environment: Environment::new(),
+    fn resolve_statements(&mut self, stmts: &mut [Stmt]) {
+        for (i, stmt) in stmts.iter().enumerate() {
+            let (keyword, span) = match stmt {
+                Stmt::Return(_, span) => ("return", span),
+                Stmt::Throw(_, span) => ("throw", span),
+                _ => continue,
+            };
+            if i + 1 < stmts.len() {
+                let message = format!("Unreachable code after {}", keyword);
+                self.errors.push(LangError::at(ErrorKind::UnreachableCode, message, *span));
+            }
+            break;
         }
-    }
 
-    fn interpret(&mut self, program: Vec<Stmt>) -> Result<(), String> {
-        for stmt in program {
-            self.execute_statement(stmt)?;
+        for stmt in stmts {
+            self.resolve_statement(stmt);
         }
-        Ok(())
     }
 
-    fn execute_statement(&mut self, stmt: Stmt) -> Result<Option<Value>, String> {
+    fn resolve_statement(&mut self, stmt: &mut Stmt) {
         match stmt {
-            Stmt::Expression(expr) => {
-                self.evaluate_expression(expr)?;
-                Ok(None)
-            }
-            Stmt::Let { name, value } => {
-                let val = self.evaluate_expression(value)?;
-                self.environment.define(name, val);
-                Ok(None)
+            Stmt::Expression(expr) => self.resolve_expression(expr),
+            Stmt::Let { name, value, .. } => {
+                self.resolve_expression(value);
+                self.define(name);
             }
-            Stmt::Function { name, params, body } => {
-                let func = Value::Function { params, body };
-                self.environment.define(name, func);
-                Ok(None)
+            Stmt::Function { name, params, body, .. } => {
+                // Defined before the body so the function can call itself.
+                self.define(name);
+                self.resolve_function(params, body);
             }
-            Stmt::Return(expr) => {
-                let value = if let Some(e) = expr {
-                    self.evaluate_expression(e)?
-                } else {
-                    Value::Nil
-                };
-                Ok(Some(value))
+            Stmt::Return(value, span) => {
+                if self.function_depth == 0 {
+                    self.errors.push(LangError::at(
+                        ErrorKind::ReturnOutsideFunction,
+                        "Cannot return from top-level code",
+                        *span,
+                    ));
+                }
+                if let Some(value) = value {
+                    self.resolve_expression(value);
+                }
             }
             Stmt::If { condition, then_branch, else_branch } => {
-                let cond_val = self.evaluate_expression(condition)?;
-                
-                if self.is_truthy(&cond_val) {
-                    for stmt in then_branch {
-                        if let Some(ret) = self.execute_statement(stmt)? {
-                            return Ok(Some(ret));
-                        }
-                    }
-                } else if let Some(else_stmts) = else_branch {
-                    for stmt in else_stmts {
-                        if let Some(ret) = self.execute_statement(stmt)? {
-                            return Ok(Some(ret));
-                        }
-                    }
+                self.resolve_expression(condition);
+                self.resolve_unscoped(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.resolve_unscoped(else_branch);
                 }
-                Ok(None)
             }
             Stmt::While { condition, body } => {
-                loop {
-                    let cond_val = self.evaluate_expression(condition.clone())?;
-                    if !self.is_truthy(&cond_val) {
-                        break;
-                    }
-                    
-                    for stmt in &body {
-                        if let Some(ret) = self.execute_statement(stmt.clone())? {
-                            return Ok(Some(ret));
-                        }
-                    }
-                }
-                Ok(None)
+                self.resolve_expression(condition);
+                self.resolve_unscoped(body);
             }
-            Stmt::Block(stmts) => {
-                self.environment.push_scope();
-                let mut result = None;
-                
-                for stmt in stmts {
-                    if let Some(ret) = self.execute_statement(stmt)? {
-                        result = Some(ret);
-                        break;
-                    }
+            Stmt::For { initializer, condition, increment, body } => {
+                self.begin_scope();
+                if let Some(initializer) = initializer {
+                    self.declare_all(std::slice::from_ref(initializer.as_ref()));
+                    self.resolve_statement(initializer);
                 }
-                
-                self.environment.pop_scope();
-                Ok(result)
+                if let Some(condition) = condition {
+                    self.resolve_expression(condition);
+                }
+                self.resolve_unscoped(body);
+                if let Some(increment) = increment {
+                    self.resolve_expression(increment);
+                }
+                self.end_scope();
+            }
+            Stmt::ForIn { variable, iterable, body } => {
+                self.resolve_expression(iterable);
+                self.begin_scope();
+                self.define(variable);
+                self.declare_all(body);
+                self.resolve_statements(body);
+                self.end_scope();
+            }
+            Stmt::Break | Stmt::Continue => {}
+            Stmt::Block(stmts) => self.resolve_block(stmts),
+            Stmt::Import { alias, .. } => self.define(alias),
+            Stmt::Throw(value, _) => self.resolve_expression(value),
+            Stmt::Try { body, variable, handler } => {
+                self.resolve_block(body);
+                self.begin_scope();
+                self.define(variable);
+                self.declare_all(handler);
+                self.resolve_statements(handler);
+                self.end_scope();
             }
         }
     }
 
-    fn evaluate_expression(&mut self, expr: Expr) -> Result<Value, String> {
+    fn resolve_function(&mut self, params: &[String], body: &mut Rc<Vec<Stmt>>) {
+        let body = Rc::make_mut(body);
+        self.function_depth += 1;
+        self.begin_scope();
+        for param in params {
+            self.define(param);
+        }
+        self.declare_all(body);
+        self.resolve_statements(body);
+        self.end_scope();
+        self.function_depth -= 1;
+    }
+
+    fn resolve_expression(&mut self, expr: &mut Expr) {
         match expr {
-            Expr::Number(n) => Ok(Value::Number(n)),
-            Expr::String(s) => Ok(Value::String(s)),
-            Expr::Bool(b) => Ok(Value::Bool(b)),
-            Expr::Nil => Ok(Value::Nil),
-            Expr::Identifier(name) => {
-                self.environment.get(&name)
-                    .ok_or_else(|| format!("Undefined variable: {}", name))
-            }
-            Expr::Binary { left, op, right } => {
-                let left_val = self.evaluate_expression(*left)?;
-                let right_val = self.evaluate_expression(*right)?;
-                self.apply_binary_op(left_val, op, right_val)
+            Expr::Number(_) | Expr::String(_) | Expr::Bool(_) | Expr::Nil => {}
+            Expr::Identifier { name, depth, span } => *depth = self.resolve_local(name, *span),
+            Expr::Binary { left, right, .. } => {
+                self.resolve_expression(left);
+                self.resolve_expression(right);
             }
-            Expr::Unary { op, expr } => {
-                let val = self.evaluate_expression(*expr)?;
-                self.apply_unary_op(op, val)
+            Expr::Unary { expr, .. } => self.resolve_expression(expr),
+            Expr::Call { callee, args, .. } => {
+                self.resolve_expression(callee);
+                for arg in args {
+                    self.resolve_expression(arg);
+                }
             }
-            Expr::Call { callee, args } => {
-                let func = self.evaluate_expression(*callee)?;
-                let arg_vals: Result<Vec<_>, _> = args.into_iter()
-                    .map(|arg| self.evaluate_expression(arg))
-                    .collect();
-                self.call_function(func, arg_vals?)
+            Expr::Index { object, index, .. } => {
+                self.resolve_expression(object);
+                self.resolve_expression(index);
             }
             Expr::Array(elements) => {
-                let vals: Result<Vec<_>, _> = elements.into_iter()
-                    .map(|e| self.evaluate_expression(e))
-                    .collect();
-                Ok(Value::Array(vals?))
+                for element in elements {
+                    self.resolve_expression(element);
+                }
             }
-            _ => Err("Unsupported expression".to_string()),
+            Expr::Object(fields) => {
+                for (_, value) in fields {
+                    self.resolve_expression(value);
+                }
+            }
+            Expr::Property { object, .. } => self.resolve_expression(object),
+            Expr::Assign { name, value, depth, span } => {
+                self.resolve_expression(value);
+                *depth = self.resolve_local(name, *span);
+            }
+            Expr::IndexAssign { object, index, value, .. } => {
+                self.resolve_expression(object);
+                self.resolve_expression(index);
+                self.resolve_expression(value);
+            }
+            Expr::PropertyAssign { object, value, .. } => {
+                self.resolve_expression(object);
+                self.resolve_expression(value);
+            }
+            Expr::Function { params, body } => self.resolve_function(params, body),
         }
     }
+}
 
-    fn apply_binary_op(&self, left: Value, op: BinaryOp, right: Value) -> Result<Value, String> {
-        match (left, right) {
-            (Value::Number(l), Value::Number(r)) => {
-                Ok(match op {
-                    BinaryOp::Add => Value::Number(l + r),
-                    BinaryOp::Sub => Value::Number(l - r),
-                    BinaryOp::Mul => Value::Number(l * r),
-                    BinaryOp::Div => Value::Number(l / r),
-                    BinaryOp::Mod => Value::Number(l % r),
-                    BinaryOp::Equal => Value::Bool(l == r),
-                    BinaryOp::NotEqual => Value::Bool(l != r),
-                    BinaryOp::Less => Value::Bool(l < r),
-                    BinaryOp::LessEqual => Value::Bool(l <= r),
-                    BinaryOp::Greater => Value::Bool(l > r),
-                    BinaryOp::GreaterEqual => Value::Bool(l >= r),
-                    _ => return Err("Invalid operation".to_string()),
-                })
+/// Runs the resolver over a parsed program, returning it annotated with
+/// variable depths or every static error found.
+fn resolve_program(mut program: Vec<Stmt>) -> Result<Vec<Stmt>, Vec<LangError>> {
+    let mut resolver = Resolver::new();
+    resolver.resolve_statements(&mut program);
+
+    if resolver.errors.is_empty() {
+        Ok(program)
+    } else {
+        Err(resolver.errors)
+    }
+}
+
+// ============= OPTIMIZER =============
+
+/// AST-to-AST pass that folds constant arithmetic, drops branches and loops
+/// whose condition is a literal, and inlines `let` bindings that hold a
+/// literal and are never assigned. Scopes are left intact so resolved depths
+/// stay valid; run it after `resolve_program`, which rejects the
+/// use-before-define programs it would otherwise miscompile.
+struct Optimizer {
+    // `Some(literal)` for an inlinable constant, `None` for a binding that
+    // shadows any outer constant of the same name.
+    scopes: Vec<HashMap<String, Option<Expr>>>,
+    assigned: HashSet<String>,
+    global_declarations: HashMap<String, usize>,
+}
+
+fn optimize(program: Vec<Stmt>) -> Vec<Stmt> {
+    let mut assigned = HashSet::new();
+    collect_assigned_names(&program, &mut assigned);
+
+    // Globals can be redeclared, so only a single top-level `let` is safe to inline.
+    let mut global_declarations = HashMap::new();
+    for name in declared_names(&program) {
+        *global_declarations.entry(name.to_string()).or_insert(0) += 1;
+    }
+
+    let mut optimizer = Optimizer {
+        scopes: Vec::new(),
+        assigned,
+        global_declarations,
+    };
+    optimizer.optimize_block(program, Vec::new())
+}
+
+/// Names a statement list declares into its own scope, including those from
+/// `if` and `while` bodies, which share the enclosing scope.
+fn declared_names(stmts: &[Stmt]) -> Vec<&str> {
+    let mut names = Vec::new();
+    for stmt in stmts {
+        match stmt {
+            Stmt::Let { name, .. } | Stmt::Function { name, .. } | Stmt::Import { alias: name, .. } => names.push(name.as_str()),
+            Stmt::If { then_branch, else_branch, .. } => {
+                names.extend(declared_names(then_branch));
+                names.extend(else_branch.iter().flat_map(|stmts| declared_names(stmts)));
             }
-            _ => Err("Type error in binary operation".to_string()),
+            Stmt::While { body, .. } => names.extend(declared_names(body)),
+            _ => {}
         }
     }
+    names
+}
 
-    fn apply_unary_op(&self, op: UnaryOp, val: Value) -> Result<Value, String> {
-        match (op, val) {
-            (UnaryOp::Neg, Value::Number(n)) => Ok(Value::Number(-n)),
-            (UnaryOp::Not, v) => Ok(Value::Bool(!self.is_truthy(&v))),
-            _ => Err("Type error in unary operation".to_string()),
+fn collect_assigned_names(stmts: &[Stmt], names: &mut HashSet<String>) {
+    for stmt in stmts {
+        match stmt {
+            Stmt::Expression(expr) | Stmt::Let { value: expr, .. } | Stmt::Return(Some(expr), _) | Stmt::Throw(expr, _) => {
+                collect_assigned_in_expr(expr, names);
+            }
+            Stmt::Function { body, .. } => collect_assigned_names(body, names),
+            Stmt::If { condition, then_branch, else_branch } => {
+                collect_assigned_in_expr(condition, names);
+                collect_assigned_names(then_branch, names);
+                if let Some(else_branch) = else_branch {
+                    collect_assigned_names(else_branch, names);
+                }
+            }
+            Stmt::While { condition, body } => {
+                collect_assigned_in_expr(condition, names);
+                collect_assigned_names(body, names);
+            }
+            Stmt::For { initializer, condition, increment, body } => {
+                if let Some(initializer) = initializer {
+                    collect_assigned_names(std::slice::from_ref(initializer.as_ref()), names);
+                }
+                for expr in condition.iter().chain(increment.iter()) {
+                    collect_assigned_in_expr(expr, names);
+                }
+                collect_assigned_names(body, names);
+            }
+            Stmt::ForIn { iterable, body, .. } => {
+                collect_assigned_in_expr(iterable, names);
+                collect_assigned_names(body, names);
+            }
+            Stmt::Block(stmts) => collect_assigned_names(stmts, names),
+            Stmt::Try { body, handler, .. } => {
+                collect_assigned_names(body, names);
+                collect_assigned_names(handler, names);
+            }
+            Stmt::Return(None, _) | Stmt::Break | Stmt::Continue | Stmt::Import { .. } => {}
         }
     }
+}
 
-    fn call_function(&mut self, func: Value, args: Vec<Value>) -> Result<Value, String> {
-        match func {
-            Value::Function { params, body } => {
-                if params.len() != args.len() {
-                    return Err("Argument count mismatch".to_string());
-                }
+fn collect_assigned_in_expr(expr: &Expr, names: &mut HashSet<String>) {
+    match expr {
+        Expr::Number(_) | Expr::String(_) | Expr::Bool(_) | Expr::Nil | Expr::Identifier { .. } => {}
+        Expr::Assign { name, value, .. } => {
+            names.insert(name.clone());
+            collect_assigned_in_expr(value, names);
+        }
+        Expr::Binary { left, right, .. } => {
+            collect_assigned_in_expr(left, names);
+            collect_assigned_in_expr(right, names);
+        }
+        Expr::Unary { expr, .. } | Expr::Property { object: expr, .. } => collect_assigned_in_expr(expr, names),
+        Expr::Call { callee, args, .. } => {
+            collect_assigned_in_expr(callee, names);
+            for arg in args {
+                collect_assigned_in_expr(arg, names);
+            }
+        }
+        Expr::Index { object, index, .. } => {
+            collect_assigned_in_expr(object, names);
+            collect_assigned_in_expr(index, names);
+        }
+        Expr::Array(elements) => {
+            for element in elements {
+                collect_assigned_in_expr(element, names);
+            }
+        }
+        Expr::Object(fields) => {
+            for (_, value) in fields {
+                collect_assigned_in_expr(value, names);
+            }
+        }
+        Expr::IndexAssign { object, index, value, .. } => {
+            collect_assigned_in_expr(object, names);
+            collect_assigned_in_expr(index, names);
+            collect_assigned_in_expr(value, names);
+        }
+        Expr::PropertyAssign { object, value, .. } => {
+            collect_assigned_in_expr(object, names);
+            collect_assigned_in_expr(value, names);
+        }
+        Expr::Function { body, .. } => collect_assigned_names(body, names),
+    }
+}
 
-                self.environment.push_scope();
+fn literal_value(expr: &Expr) -> Option<Value> {
+    match expr {
+        Expr::Number(n) => Some(Value::Number(*n)),
+        Expr::String(s) => Some(Value::String(s.clone())),
+        Expr::Bool(b) => Some(Value::Bool(*b)),
+        Expr::Nil => Some(Value::Nil),
+        _ => None,
+    }
+}
 
-                for (param, arg) in params.iter().zip(args.iter()) {
-                    self.environment.define(param.clone(), arg.clone());
-                }
+fn literal_expr(value: Value) -> Option<Expr> {
+    match value {
+        // No literal spells NaN, and infinity only as an oversized number.
+        Value::Number(n) if n.is_finite() => Some(Expr::Number(n)),
+        Value::String(s) => Some(Expr::String(s)),
+        Value::Bool(b) => Some(Expr::Bool(b)),
+        Value::Nil => Some(Expr::Nil),
+        _ => None,
+    }
+}
 
-                let mut result = Value::Nil;
-                for stmt in body {
-                    if let Some(ret) = self.execute_statement(stmt)? {
-                        result = ret;
-                        break;
-                    }
+impl Optimizer {
+    /// Optimizes a statement list in a fresh scope that starts with `bindings`
+    /// (parameters, loop variables) shadowing any outer constants.
+    fn optimize_block(&mut self, stmts: Vec<Stmt>, bindings: Vec<String>) -> Vec<Stmt> {
+        let mut scope: HashMap<_, _> = bindings.into_iter().map(|name| (name, None)).collect();
+        // Nested functions may capture names declared later in the block.
+        for name in declared_names(&stmts) {
+            scope.insert(name.to_string(), None);
+        }
+        self.scopes.push(scope);
+        let stmts = stmts.into_iter().flat_map(|stmt| self.optimize_statement(stmt)).collect();
+        self.scopes.pop();
+        stmts
+    }
+
+    /// Optimizes an `if`/`while`/`for` body, which runs in the enclosing
+    /// scope. It may run any number of times, so its declarations are not
+    /// constants before or after it.
+    fn optimize_unscoped(&mut self, stmts: Vec<Stmt>) -> Vec<Stmt> {
+        let declared: Vec<String> = declared_names(&stmts).into_iter().map(str::to_string).collect();
+        for name in &declared {
+            self.bind(name, None);
+        }
+        let stmts = stmts.into_iter().flat_map(|stmt| self.optimize_statement(stmt)).collect();
+        for name in &declared {
+            self.bind(name, None);
+        }
+        stmts
+    }
+
+    fn bind(&mut self, name: &str, constant: Option<Expr>) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string(), constant);
+        }
+    }
+
+    fn optimize_statement(&mut self, stmt: Stmt) -> Vec<Stmt> {
+        let stmt = match stmt {
+            Stmt::Expression(expr) => Stmt::Expression(self.optimize_expression(expr)),
+            Stmt::Let { name, value, span } => {
+                let value = self.optimize_expression(value);
+                let redeclared_global = self.scopes.len() == 1 && self.global_declarations.get(&name) != Some(&1);
+                let constant = literal_value(&value)
+                    .filter(|_| !self.assigned.contains(&name) && !redeclared_global)
+                    .map(|_| value.clone());
+                self.bind(&name, constant);
+                Stmt::Let { name, value, span }
+            }
+            Stmt::Function { name, params, body, span } => {
+                self.bind(&name, None);
+                let body = self.optimize_function_body(&params, body);
+                Stmt::Function { name, params, body, span }
+            }
+            Stmt::Return(value, span) => Stmt::Return(value.map(|value| self.optimize_expression(value)), span),
+            Stmt::If { condition, then_branch, else_branch } => {
+                let condition = self.optimize_expression(condition);
+                match literal_value(&condition).map(|value| value.is_truthy()) {
+                    // The taken branch is spliced in place, since it has no scope of its own.
+                    Some(true) => return self.optimize_unscoped(then_branch),
+                    Some(false) => return else_branch.map_or_else(Vec::new, |stmts| self.optimize_unscoped(stmts)),
+                    None => Stmt::If {
+                        condition,
+                        then_branch: self.optimize_unscoped(then_branch),
+                        else_branch: else_branch.map(|stmts| self.optimize_unscoped(stmts)),
+                    },
                 }
+            }
+            Stmt::While { condition, body } => {
+                let condition = self.optimize_expression(condition);
+                if literal_value(&condition).is_some_and(|value| !value.is_truthy()) {
+                    return Vec::new();
+                }
+                Stmt::While { condition, body: self.optimize_unscoped(body) }
+            }
+            Stmt::For { initializer, condition, increment, body } => {
+                self.scopes.push(HashMap::new());
+                let initializer = initializer.and_then(|init| self.optimize_statement(*init).pop()).map(Box::new);
+                let condition = condition.map(|expr| self.optimize_expression(expr));
+                let body = self.optimize_unscoped(body);
+                let increment = increment.map(|expr| self.optimize_expression(expr));
+                self.scopes.pop();
+                Stmt::For { initializer, condition, increment, body }
+            }
+            Stmt::ForIn { variable, iterable, body } => {
+                let iterable = self.optimize_expression(iterable);
+                let body = self.optimize_block(body, vec![variable.clone()]);
+                Stmt::ForIn { variable, iterable, body }
+            }
+            Stmt::Block(stmts) => Stmt::Block(self.optimize_block(stmts, Vec::new())),
+            Stmt::Import { ref alias, .. } => {
+                self.bind(alias, None);
+                stmt
+            }
+            Stmt::Break | Stmt::Continue => stmt,
+            Stmt::Throw(value, span) => Stmt::Throw(self.optimize_expression(value), span),
+            Stmt::Try { body, variable, handler } => {
+                let body = self.optimize_block(body, Vec::new());
+                let handler = self.optimize_block(handler, vec![variable.clone()]);
+                Stmt::Try { body, variable, handler }
+            }
+        };
+        vec![stmt]
+    }
 
-                self.environment.pop_scope();
-                Ok(result)
+    fn optimize_function_body(&mut self, params: &[String], body: Rc<Vec<Stmt>>) -> Rc<Vec<Stmt>> {
+        let body = Rc::try_unwrap(body).unwrap_or_else(|shared| (*shared).clone());
+        Rc::new(self.optimize_block(body, params.to_vec()))
+    }
+
+    fn lookup_constant(&self, name: &str) -> Option<Expr> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name)).cloned().flatten()
+    }
+
+    fn optimize_expression(&mut self, expr: Expr) -> Expr {
+        match expr {
+            Expr::Identifier { name, depth, span } => {
+                self.lookup_constant(&name).unwrap_or(Expr::Identifier { name, depth, span })
             }
-            _ => Err("Not a function".to_string()),
+            Expr::Binary { left, op, right, span } => {
+                let left = self.optimize_expression(*left);
+                let right = self.optimize_expression(*right);
+                let folded = literal_value(&left)
+                    .zip(literal_value(&right))
+                    .and_then(|(l, r)| Value::binary_op(l, op.clone(), r).ok())
+                    .and_then(literal_expr);
+                // Operations that would fail are left for the runtime to report.
+                folded.unwrap_or(Expr::Binary { left: Box::new(left), op, right: Box::new(right), span })
+            }
+            Expr::Unary { op, expr, span } => {
+                let expr = self.optimize_expression(*expr);
+                let folded = literal_value(&expr)
+                    .and_then(|value| Value::unary_op(op.clone(), value).ok())
+                    .and_then(literal_expr);
+                folded.unwrap_or(Expr::Unary { op, expr: Box::new(expr), span })
+            }
+            Expr::Call { callee, args, span } => Expr::Call {
+                callee: Box::new(self.optimize_expression(*callee)),
+                args: args.into_iter().map(|arg| self.optimize_expression(arg)).collect(),
+                span,
+            },
+            Expr::Index { object, index, span } => Expr::Index {
+                object: Box::new(self.optimize_expression(*object)),
+                index: Box::new(self.optimize_expression(*index)),
+                span,
+            },
+            Expr::Array(elements) => {
+                Expr::Array(elements.into_iter().map(|element| self.optimize_expression(element)).collect())
+            }
+            Expr::Object(fields) => Expr::Object(
+                fields.into_iter().map(|(key, value)| (key, self.optimize_expression(value))).collect(),
+            ),
+            Expr::Property { object, name, span } => Expr::Property {
+                object: Box::new(self.optimize_expression(*object)),
+                name,
+                span,
+            },
+            Expr::Assign { name, value, depth, span } => Expr::Assign {
+                name,
+                value: Box::new(self.optimize_expression(*value)),
+                depth,
+                span,
+            },
+            Expr::IndexAssign { object, index, value, span } => Expr::IndexAssign {
+                object: Box::new(self.optimize_expression(*object)),
+                index: Box::new(self.optimize_expression(*index)),
+                value: Box::new(self.optimize_expression(*value)),
+                span,
+            },
+            Expr::PropertyAssign { object, name, value, span } => Expr::PropertyAssign {
+                object: Box::new(self.optimize_expression(*object)),
+                name,
+                value: Box::new(self.optimize_expression(*value)),
+                span,
+            },
+            Expr::Function { params, body } => {
+                let body = self.optimize_function_body(&params, body);
+                Expr::Function { params, body }
+            }
+            literal => literal,
         }
     }
+}
 
-    fn is_truthy(&self, value: &Value) -> bool {
-        match value {
-            Value::Bool(b) => *b,
-            Value::Nil => false,
-            _ => true,
+/// Name recorded in stack traces for `fn (...) { ... }` expressions.
+const ANONYMOUS_FUNCTION: &str = "<anonymous>";
+
+/// A caught error as scripts see it, through `e.message`, `e.kind` and `e.stack`.
+#[derive(Debug)]
+struct ErrorValue {
+    kind: ErrorKind,
+    message: String,
+    stack: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Number(f64),
+    String(String),
+    Bool(bool),
+    Nil,
+    Function { name: String, params: Vec<String>, body: Rc<Vec<Stmt>>, closure: Environment },
+    CompiledFunction(Rc<VmClosure>),
+    NativeFunction(Rc<NativeFunction>),
+    Array(Rc<RefCell<Vec<Value>>>),
+    Object(Rc<RefCell<BTreeMap<String, Value>>>),
+    Error(Rc<ErrorValue>),
+}
+
+impl Value {
+    fn array(elements: Vec<Value>) -> Value {
+        Value::Array(Rc::new(RefCell::new(elements)))
+    }
+
+    fn object(fields: BTreeMap<String, Value>) -> Value {
+        Value::Object(Rc::new(RefCell::new(fields)))
+    }
+
+    /// The value a `catch` clause binds for `error`.
+    fn caught(error: LangError) -> Value {
+        Value::Error(Rc::new(ErrorValue {
+            kind: error.kind,
+            message: error.message,
+            stack: error.stack.unwrap_or_default(),
+        }))
+    }
+
+    /// The error raised by `throw value`. Caught errors are rethrown as they
+    /// were; any other value becomes the message of a new error.
+    fn thrown(self) -> LangError {
+        match self {
+            Value::Error(error) => {
+                let mut rethrown = LangError::new(error.kind, error.message.clone());
+                rethrown.stack = Some(error.stack.clone());
+                rethrown
+            }
+            other => LangError::new(ErrorKind::Thrown, other.to_string()),
+        }
+    }
+
+    fn get_property(&self, name: &str) -> Result<Value, LangError> {
+        match self {
+            Value::Object(fields) => Ok(fields.borrow().get(name).cloned().unwrap_or(Value::Nil)),
+            Value::Error(error) => Ok(match name {
+                "message" => Value::String(error.message.clone()),
+                "kind" => Value::String(format!("{:?}", error.kind)),
+                "stack" => Value::array(error.stack.iter().map(|name| Value::String(name.clone())).collect()),
+                _ => Value::Nil,
+            }),
+            _ => Err(LangError::new(
+                ErrorKind::TypeError,
+                format!("Cannot read property '{}' of a non-object", name),
+            )),
+        }
+    }
+
+    fn set_property(&self, name: &str, value: Value) -> Result<(), LangError> {
+        match self {
+            Value::Object(fields) => {
+                fields.borrow_mut().insert(name.to_string(), value);
+                Ok(())
+            }
+            _ => Err(LangError::new(
+                ErrorKind::TypeError,
+                format!("Cannot set property '{}' on a non-object", name),
+            )),
         }
     }
-}
\ No newline at end of file
+
+    fn equals(&self, other: &Value) -> bool {
+        self.equals_seen(other, &mut HashSet::new())
+    }
+
+    /// Structural equality that tolerates cycles: a pair of containers
+    /// already being compared further up is assumed equal.
+    fn equals_seen(&self, other: &Value, seen: &mut HashSet<(usize, usize)>) -> bool {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            (Value::Array(a), Value::Array(b)) => {
+                if Rc::ptr_eq(a, b) || !seen.insert((Rc::as_ptr(a) as usize, Rc::as_ptr(b) as usize)) {
+                    return true;
+                }
+                let (a, b) = (a.borrow(), b.borrow());
+                a.len() == b.len() && a.iter().zip(b.iter()).all(|(x, y)| x.equals_seen(y, seen))
+            }
+            (Value::Object(a), Value::Object(b)) => {
+                if Rc::ptr_eq(a, b) || !seen.insert((Rc::as_ptr(a) as usize, Rc::as_ptr(b) as usize)) {
+                    return true;
+                }
+                let (a, b) = (a.borrow(), b.borrow());
+                a.len() == b.len()
+                    && a.iter().zip(b.iter()).all(|((ka, va), (kb, vb))| ka == kb && va.equals_seen(vb, seen))
+            }
+            (Value::Function { body: a, closure: ea, .. }, Value::Function { body: b, closure: eb, .. }) => {
+                Rc::ptr_eq(a, b) && Rc::ptr_eq(&ea.scope, &eb.scope)
+            }
+            (Value::CompiledFunction(a), Value::CompiledFunction(b)) => Rc::ptr_eq(a, b),
+            (Value::NativeFunction(a), Value::NativeFunction(b)) => Rc::ptr_eq(a, b),
+            (Value::Error(a), Value::Error(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+
+    fn get_index(&self, index: &Value) -> Result<Value, LangError> {
+        match self {
+            Value::Object(_) => match index {
+                Value::String(key) => self.get_property(key),
+                _ => Err(LangError::new(ErrorKind::TypeError, "Object keys must be strings")),
+            },
+            Value::Array(elements) => {
+                let elements = elements.borrow();
+                let i = Self::array_index(index, elements.len())?;
+                Ok(elements[i].clone())
+            }
+            Value::String(s) => {
+                let chars: Vec<char> = s.chars().collect();
+                let i = Self::array_index(index, chars.len())?;
+                Ok(Value::String(chars[i].to_string()))
+            }
+            _ => Err(LangError::new(ErrorKind::TypeError, "Only arrays, strings and objects can be indexed")),
+        }
+    }
+
+    fn set_index(&self, index: &Value, value: Value) -> Result<(), LangError> {
+        match self {
+            Value::Object(_) => match index {
+                Value::String(key) => self.set_property(key, value),
+                _ => Err(LangError::new(ErrorKind::TypeError, "Object keys must be strings")),
+            },
+            Value::Array(elements) => {
+                let mut elements = elements.borrow_mut();
+                let i = Self::array_index(index, elements.len())?;
+                elements[i] = value;
+                Ok(())
+            }
+            _ => Err(LangError::new(ErrorKind::TypeError, "Only arrays and objects support index assignment")),
+        }
+    }
+
+    fn array_index(index: &Value, len: usize) -> Result<usize, LangError> {
+        match index {
+            Value::Number(n) if n.fract() == 0.0 && *n >= 0.0 && (*n as usize) < len => Ok(*n as usize),
+            Value::Number(n) => Err(LangError::new(
+                ErrorKind::IndexOutOfBounds,
+                format!("Index {} out of bounds for length {}", n, len),
+            )),
+            _ => Err(LangError::new(ErrorKind::TypeError, "Index must be a number")),
+        }
+    }
+
+    fn iter_values(&self) -> Result<Vec<Value>, LangError> {
+        match self {
+            Value::Array(elements) => Ok(elements.borrow().clone()),
+            Value::String(s) => Ok(s.chars().map(|c| Value::String(c.to_string())).collect()),
+            Value::Object(fields) => Ok(fields.borrow().keys().map(|k| Value::String(k.clone())).collect()),
+            _ => Err(LangError::new(ErrorKind::TypeError, "Only arrays, strings and objects can be iterated")),
+        }
+    }
+
+    fn binary_op(left: Value, op: BinaryOp, right: Value) -> Result<Value, LangError> {
+        match op {
+            BinaryOp::Equal => return Ok(Value::Bool(left.equals(&right))),
+            BinaryOp::NotEqual => return Ok(Value::Bool(!left.equals(&right))),
+            _ => {}
+        }
+
+        match (left, right) {
+            (Value::Number(_), Value::Number(r)) if r == 0.0 && matches!(op, BinaryOp::Div | BinaryOp::Mod) => {
+                Err(LangError::new(ErrorKind::DivisionByZero, "Division by zero"))
+            }
+            (Value::Number(l), Value::Number(r)) => {
+                Ok(match op {
+                    BinaryOp::Add => Value::Number(l + r),
+                    BinaryOp::Sub => Value::Number(l - r),
+                    BinaryOp::Mul => Value::Number(l * r),
+                    BinaryOp::Div => Value::Number(l / r),
+                    BinaryOp::Mod => Value::Number(l % r),
+                    BinaryOp::Equal => Value::Bool(l == r),
+                    BinaryOp::NotEqual => Value::Bool(l != r),
+                    BinaryOp::Less => Value::Bool(l < r),
+                    BinaryOp::LessEqual => Value::Bool(l <= r),
+                    BinaryOp::Greater => Value::Bool(l > r),
+                    BinaryOp::GreaterEqual => Value::Bool(l >= r),
+                    _ => return Err(LangError::new(ErrorKind::TypeError, "Invalid operation")),
+                })
+            }
+            _ => Err(LangError::new(ErrorKind::TypeError, "Type error in binary operation")),
+        }
+    }
+
+    fn unary_op(op: UnaryOp, val: Value) -> Result<Value, LangError> {
+        match (op, val) {
+            (UnaryOp::Neg, Value::Number(n)) => Ok(Value::Number(-n)),
+            (UnaryOp::Not, v) => Ok(Value::Bool(!v.is_truthy())),
+            _ => Err(LangError::new(ErrorKind::TypeError, "Type error in unary operation")),
+        }
+    }
+
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Bool(b) => *b,
+            Value::Nil => false,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_inside(f, &mut Vec::new())
+    }
+}
+
+impl Value {
+    /// `open` holds the containers currently being printed; meeting one of
+    /// them again prints `[cycle]` instead of recursing forever.
+    fn fmt_inside(&self, f: &mut fmt::Formatter, open: &mut Vec<usize>) -> fmt::Result {
+        let container = match self {
+            Value::Array(elements) => Some(Rc::as_ptr(elements) as usize),
+            Value::Object(fields) => Some(Rc::as_ptr(fields) as usize),
+            _ => None,
+        };
+        if let Some(ptr) = container {
+            if open.contains(&ptr) {
+                return write!(f, "[cycle]");
+            }
+            open.push(ptr);
+        }
+        let result = self.fmt_contents(f, open);
+        if container.is_some() {
+            open.pop();
+        }
+        result
+    }
+
+    fn fmt_contents(&self, f: &mut fmt::Formatter, open: &mut Vec<usize>) -> fmt::Result {
+        match self {
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Nil => write!(f, "nil"),
+            Value::Function { params, .. } => write!(f, "<fn({})>", params.join(", ")),
+            Value::CompiledFunction(closure) => write!(f, "{:?}", closure),
+            Value::NativeFunction(native) => write!(f, "{:?}", native),
+            Value::Array(elements) => {
+                write!(f, "[")?;
+                for (i, element) in elements.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    element.fmt_nested(f, open)?;
+                }
+                write!(f, "]")
+            }
+            Value::Object(fields) => {
+                let fields = fields.borrow();
+                if fields.is_empty() {
+                    return write!(f, "{{}}");
+                }
+                write!(f, "{{ ")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: ", key)?;
+                    value.fmt_nested(f, open)?;
+                }
+                write!(f, " }}")
+            }
+            Value::Error(error) => write!(f, "<error: {}>", error.message),
+        }
+    }
+
+    fn fmt_nested(&self, f: &mut fmt::Formatter, open: &mut Vec<usize>) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{:?}", s),
+            other => other.fmt_inside(f, open),
+        }
+    }
+}
+
+// ============= NATIVE FUNCTIONS =============
+
+type NativeFn = dyn Fn(&[Value]) -> Result<Value, String>;
+
+struct NativeFunction {
+    name: String,
+    arity: Option<usize>,
+    func: Box<NativeFn>,
+}
+
+impl fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<native fn {}>", self.name)
+    }
+}
+
+impl NativeFunction {
+    /// Wraps `func` as a callable script value.
+    fn value(
+        name: &str,
+        arity: Option<usize>,
+        func: impl Fn(&[Value]) -> Result<Value, String> + 'static,
+    ) -> Value {
+        Value::NativeFunction(Rc::new(NativeFunction {
+            name: name.to_string(),
+            arity,
+            func: Box::new(func),
+        }))
+    }
+
+    fn call(&self, args: &[Value]) -> Result<Value, LangError> {
+        if let Some(arity) = self.arity
+            && arity != args.len()
+        {
+            return Err(LangError::new(
+                ErrorKind::ArgumentCount,
+                format!("{}() expects {} arguments but got {}", self.name, arity, args.len()),
+            ));
+        }
+        (self.func)(args).map_err(|message| LangError::new(ErrorKind::NativeError, message))
+    }
+}
+
+fn number_arg(name: &str, args: &[Value], i: usize) -> Result<f64, String> {
+    match args.get(i) {
+        Some(Value::Number(n)) => Ok(*n),
+        _ => Err(format!("{}() expects a number as argument {}", name, i + 1)),
+    }
+}
+
+fn string_arg<'a>(name: &str, args: &'a [Value], i: usize) -> Result<&'a str, String> {
+    match args.get(i) {
+        Some(Value::String(s)) => Ok(s),
+        _ => Err(format!("{}() expects a string as argument {}", name, i + 1)),
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Bool(_) => "bool",
+        Value::Nil => "nil",
+        Value::Function { .. } | Value::CompiledFunction(_) | Value::NativeFunction(_) => "function",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+        Value::Error(_) => "error",
+    }
+}
+
+fn math_native(name: &'static str, op: fn(f64) -> f64) -> Value {
+    NativeFunction::value(name, Some(1), move |args| Ok(Value::Number(op(number_arg(name, args, 0)?))))
+}
+
+fn native_prelude() -> Vec<(&'static str, Value)> {
+    vec![
+        ("print", NativeFunction::value("print", None, |args| {
+            let line: Vec<String> = args.iter().map(|v| v.to_string()).collect();
+            println!("{}", line.join(" "));
+            Ok(Value::Nil)
+        })),
+        ("len", NativeFunction::value("len", Some(1), |args| match &args[0] {
+            Value::String(s) => Ok(Value::Number(s.chars().count() as f64)),
+            Value::Array(elements) => Ok(Value::Number(elements.borrow().len() as f64)),
+            Value::Object(fields) => Ok(Value::Number(fields.borrow().len() as f64)),
+            other => Err(format!("len() is not defined for {}", type_name(other))),
+        })),
+        ("push", NativeFunction::value("push", Some(2), |args| match &args[0] {
+            Value::Array(elements) => {
+                elements.borrow_mut().push(args[1].clone());
+                Ok(Value::Number(elements.borrow().len() as f64))
+            }
+            _ => Err("push() expects an array as argument 1".to_string()),
+        })),
+        ("pop", NativeFunction::value("pop", Some(1), |args| match &args[0] {
+            Value::Array(elements) => Ok(elements.borrow_mut().pop().unwrap_or(Value::Nil)),
+            _ => Err("pop() expects an array as argument 1".to_string()),
+        })),
+        ("keys", NativeFunction::value("keys", Some(1), |args| match &args[0] {
+            Value::Object(_) => Ok(Value::array(args[0].iter_values().map_err(|e| e.message)?)),
+            _ => Err("keys() expects an object as argument 1".to_string()),
+        })),
+        ("str", NativeFunction::value("str", Some(1), |args| Ok(Value::String(args[0].to_string())))),
+        ("num", NativeFunction::value("num", Some(1), |args| match &args[0] {
+            Value::Number(n) => Ok(Value::Number(*n)),
+            Value::String(s) => s.trim()
+                .parse()
+                .map(Value::Number)
+                .map_err(|_| format!("num() cannot convert \"{}\" to a number", s)),
+            Value::Bool(b) => Ok(Value::Number(if *b { 1.0 } else { 0.0 })),
+            other => Err(format!("num() cannot convert {} to a number", type_name(other))),
+        })),
+        ("type_of", NativeFunction::value("type_of", Some(1), |args| {
+            Ok(Value::String(type_name(&args[0]).to_string()))
+        })),
+        ("split", NativeFunction::value("split", Some(2), |args| {
+            let s = string_arg("split", args, 0)?;
+            let separator = string_arg("split", args, 1)?;
+            let parts: Vec<Value> = if separator.is_empty() {
+                s.chars().map(|c| Value::String(c.to_string())).collect()
+            } else {
+                s.split(separator).map(|part| Value::String(part.to_string())).collect()
+            };
+            Ok(Value::array(parts))
+        })),
+        ("join", NativeFunction::value("join", Some(2), |args| {
+            let separator = string_arg("join", args, 1)?;
+            match &args[0] {
+                Value::Array(elements) => {
+                    let parts: Vec<String> = elements.borrow().iter().map(|v| v.to_string()).collect();
+                    Ok(Value::String(parts.join(separator)))
+                }
+                _ => Err("join() expects an array as argument 1".to_string()),
+            }
+        })),
+        ("substring", NativeFunction::value("substring", Some(3), |args| {
+            let chars: Vec<char> = string_arg("substring", args, 0)?.chars().collect();
+            let start = number_arg("substring", args, 1)?;
+            let end = number_arg("substring", args, 2)?;
+            if start < 0.0 || end < start || end as usize > chars.len() {
+                return Err(format!("substring() range {}..{} out of bounds for length {}", start, end, chars.len()));
+            }
+            Ok(Value::String(chars[start as usize..end as usize].iter().collect()))
+        })),
+        ("abs", math_native("abs", f64::abs)),
+        ("floor", math_native("floor", f64::floor)),
+        ("ceil", math_native("ceil", f64::ceil)),
+        ("round", math_native("round", f64::round)),
+        ("sqrt", math_native("sqrt", f64::sqrt)),
+        ("pow", NativeFunction::value("pow", Some(2), |args| {
+            Ok(Value::Number(number_arg("pow", args, 0)?.powf(number_arg("pow", args, 1)?)))
+        })),
+        ("min", NativeFunction::value("min", Some(2), |args| {
+            Ok(Value::Number(number_arg("min", args, 0)?.min(number_arg("min", args, 1)?)))
+        })),
+        ("max", NativeFunction::value("max", Some(2), |args| {
+            Ok(Value::Number(number_arg("max", args, 0)?.max(number_arg("max", args, 1)?)))
+        })),
+        ("clock", NativeFunction::value("clock", Some(0), |_| {
+            let elapsed = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_err(|e| e.to_string())?;
+            Ok(Value::Number(elapsed.as_secs_f64()))
+        })),
+    ]
+}
+
+// ============= RESOURCE LIMITS =============
+
+/// Stack reserved for the thread scripts run on. Pages are only committed
+/// as they are touched, so this mostly costs address space.
+const SCRIPT_STACK_SIZE: usize = 1 << 30;
+
+/// Worst Rust stack use of one tree-walker script call, measured in debug
+/// builds with the call nested inside loops, `try` and an array literal.
+const TREE_WALKER_STACK_PER_CALL: usize = 256 << 10;
+
+/// The tree-walker recurses on the Rust stack for every script call, so it
+/// never goes deeper than `SCRIPT_STACK_SIZE` allows, whatever `Limits` says.
+const TREE_WALKER_MAX_CALL_DEPTH: usize = SCRIPT_STACK_SIZE / TREE_WALKER_STACK_PER_CALL;
+
+/// Runs `f` on a thread with `SCRIPT_STACK_SIZE` of stack, which the
+/// tree-walker's call depth cap assumes.
+fn on_script_stack<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    std::thread::Builder::new()
+        .name("script".to_string())
+        .stack_size(SCRIPT_STACK_SIZE)
+        .spawn(f)
+        .expect("failed to spawn the script thread")
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
+
+/// Execution limits shared by both backends. `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default)]
+struct Limits {
+    /// Statements and expressions evaluated (tree-walker) or instructions
+    /// executed (VM) per run.
+    max_steps: Option<u64>,
+    /// VM frames live on the heap, so only the tree-walker is capped by
+    /// default (see `TREE_WALKER_MAX_CALL_DEPTH`).
+    max_call_depth: Option<usize>,
+    /// Largest array (elements) or string (characters) a script may build.
+    max_collection_len: Option<usize>,
+    timeout: Option<Duration>,
+}
+
+impl Limits {
+    /// Preset for running untrusted scripts.
+    fn sandboxed() -> Self {
+        Limits {
+            max_steps: Some(10_000_000),
+            max_call_depth: Some(1_000),
+            max_collection_len: Some(1_000_000),
+            timeout: Some(Duration::from_secs(5)),
+        }
+    }
+
+    fn for_tree_walker(self) -> Self {
+        let cap = TREE_WALKER_MAX_CALL_DEPTH;
+        Limits {
+            max_call_depth: Some(self.max_call_depth.map_or(cap, |depth| depth.min(cap))),
+            ..self
+        }
+    }
+}
+
+// The clock is only read every this many steps to keep `tick` cheap.
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+fn limit_error(message: String) -> LangError {
+    LangError::new(ErrorKind::LimitExceeded, message)
+}
+
+/// Tracks one run's consumption against its `Limits`.
+struct Budget {
+    limits: Limits,
+    steps: u64,
+    deadline: Option<Instant>,
+}
+
+impl Budget {
+    fn new(limits: Limits) -> Self {
+        Budget {
+            limits,
+            steps: 0,
+            deadline: None,
+        }
+    }
+
+    fn start(&mut self) {
+        self.steps = 0;
+        self.deadline = self.limits.timeout.map(|timeout| Instant::now() + timeout);
+    }
+
+    fn tick(&mut self) -> Result<(), LangError> {
+        self.steps += 1;
+        if let Some(max) = self.limits.max_steps
+            && self.steps > max
+        {
+            return Err(limit_error(format!("Step budget of {} exceeded", max)));
+        }
+
+        if self.steps.is_multiple_of(DEADLINE_CHECK_INTERVAL)
+            && let (Some(deadline), Some(timeout)) = (self.deadline, self.limits.timeout)
+            && Instant::now() >= deadline
+        {
+            return Err(limit_error(format!("Timed out after {} ms", timeout.as_millis())));
+        }
+        Ok(())
+    }
+
+    fn check_call_depth(&self, depth: usize) -> Result<(), LangError> {
+        match self.limits.max_call_depth {
+            Some(max) if depth > max => Err(limit_error(format!("Maximum call depth of {} exceeded", max))),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_size(&self, value: &Value) -> Result<(), LangError> {
+        let Some(max) = self.limits.max_collection_len else {
+            return Ok(());
+        };
+
+        let (what, len) = match value {
+            Value::Array(elements) => ("Array", elements.borrow().len()),
+            Value::String(s) => ("String", s.chars().count()),
+            _ => return Ok(()),
+        };
+        if len > max {
+            return Err(limit_error(format!("{} of length {} exceeds the limit of {}", what, len, max)));
+        }
+        Ok(())
+    }
+
+    /// Natives can grow their arguments in place (`push`) as well as return
+    /// new values, so both are checked after every native call.
+    fn check_native_call(&self, args: &[Value], result: &Value) -> Result<(), LangError> {
+        for value in args.iter().chain(std::iter::once(result)) {
+            self.check_size(value)?;
+        }
+        Ok(())
+    }
+}
+
+struct Scope {
+    values: HashMap<String, Value>,
+    parent: Option<Rc<RefCell<Scope>>>,
+}
+
+#[derive(Clone)]
+struct Environment {
+    scope: Rc<RefCell<Scope>>,
+}
+
+impl fmt::Debug for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<environment>")
+    }
+}
+
+impl Environment {
+    fn new() -> Self {
+        Environment {
+            scope: Rc::new(RefCell::new(Scope {
+                values: HashMap::new(),
+                parent: None,
+            })),
+        }
+    }
+
+    fn enclosed(parent: &Environment) -> Self {
+        Environment {
+            scope: Rc::new(RefCell::new(Scope {
+                values: HashMap::new(),
+                parent: Some(parent.scope.clone()),
+            })),
+        }
+    }
+
+    fn define(&self, name: String, value: Value) {
+        self.scope.borrow_mut().values.insert(name, value);
+    }
+
+    /// The outermost scope, i.e. the globals of the module this scope belongs to.
+    fn root(&self) -> Environment {
+        let mut scope = self.scope.clone();
+        loop {
+            let parent = scope.borrow().parent.clone();
+            match parent {
+                Some(parent) => scope = parent,
+                None => return Environment { scope },
+            }
+        }
+    }
+
+    /// The scope `distance` hops up the chain, as computed by the resolver.
+    fn ancestor(&self, distance: usize) -> Rc<RefCell<Scope>> {
+        let mut scope = self.scope.clone();
+        for _ in 0..distance {
+            let parent = scope.borrow().parent.clone().expect("resolved scope depth");
+            scope = parent;
+        }
+        scope
+    }
+
+    fn get_at(&self, distance: usize, name: &str) -> Option<Value> {
+        self.ancestor(distance).borrow().values.get(name).cloned()
+    }
+
+    fn assign_at(&self, distance: usize, name: &str, value: Value) -> bool {
+        match self.ancestor(distance).borrow_mut().values.get_mut(name) {
+            Some(slot) => {
+                *slot = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn get_local(&self, name: &str) -> Option<Value> {
+        self.scope.borrow().values.get(name).cloned()
+    }
+}
+
+enum Flow {
+    Return(Value),
+    Break,
+    Continue,
+}
+
+struct Interpreter {
+    globals: Environment,
+    environment: Environment,
+    // Namespaces of loaded modules keyed by canonical path.
+    modules: HashMap<PathBuf, Value>,
+    // Files currently executing, innermost last; imports resolve against the
+    // last one and a repeat means an import cycle.
+    loading: Vec<PathBuf>,
+    budget: Budget,
+    // Names of the script functions being executed, outermost first.
+    call_stack: Vec<String>,
+}
+
+impl Interpreter {
+    fn new() -> Self {
+        let globals = Environment::new();
+        for (name, native) in native_prelude() {
+            globals.define(name.to_string(), native);
+        }
+
+        Interpreter {
+            environment: globals.clone(),
+            globals,
+            modules: HashMap::new(),
+            loading: Vec::new(),
+            budget: Budget::new(Limits::default().for_tree_walker()),
+            call_stack: Vec::new(),
+        }
+    }
+
+    fn set_limits(&mut self, limits: Limits) {
+        self.budget = Budget::new(limits.for_tree_walker());
+    }
+
+    /// An interpreter for the script at `path`, so its imports resolve
+    /// relative to it.
+    fn for_script(path: &Path) -> Self {
+        let mut interpreter = Interpreter::new();
+        interpreter.loading.push(fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()));
+        interpreter
+    }
+
+    #[cfg(test)]
+    fn register_native(
+        &mut self,
+        name: &str,
+        arity: Option<usize>,
+        func: impl Fn(&[Value]) -> Result<Value, String> + 'static,
+    ) {
+        self.globals.define(name.to_string(), NativeFunction::value(name, arity, func));
+    }
+
+    #[cfg(test)]
+    fn interpret(&mut self, program: Vec<Stmt>) -> Result<(), LangError> {
+        let program = resolve_program(program).map_err(|mut errors| errors.remove(0))?;
+        self.run_resolved(program)?;
+        Ok(())
+    }
+
+    /// Runs a program that already went through `resolve_program`, handing
+    /// back the value of a trailing expression statement so the REPL can echo it.
+    fn run_resolved(&mut self, program: Vec<Stmt>) -> Result<Option<Value>, LangError> {
+        self.budget.start();
+        self.call_stack.clear();
+        self.run_program(program)
+    }
+
+    /// `run_resolved` without resetting the budget, for imported modules.
+    fn run_program(&mut self, mut program: Vec<Stmt>) -> Result<Option<Value>, LangError> {
+        let last = match program.last() {
+            Some(Stmt::Expression(_)) => program.pop(),
+            _ => None,
+        };
+        for stmt in program {
+            self.execute_statement(stmt)?;
+        }
+
+        match last {
+            Some(Stmt::Expression(expr)) => self.evaluate_expression(expr).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    #[cfg(test)]
+    fn global(&self, name: &str) -> Option<Value> {
+        self.globals.get_local(name)
+    }
+
+    /// User-defined globals sorted by name; the native prelude is left out.
+    fn bindings(&self) -> Vec<(String, Value)> {
+        let mut bindings: Vec<_> = self.globals.scope.borrow().values.iter()
+            .filter(|(_, value)| !matches!(value, Value::NativeFunction(_)))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        bindings.sort_by(|a, b| a.0.cmp(&b.0));
+        bindings
+    }
+
+    fn execute_in(&mut self, environment: Environment, stmts: &[Stmt]) -> Result<Option<Flow>, LangError> {
+        let previous = std::mem::replace(&mut self.environment, environment);
+        let mut result = Ok(None);
+
+        for stmt in stmts {
+            match self.execute_statement(stmt.clone()) {
+                Ok(None) => {}
+                other => {
+                    result = other;
+                    break;
+                }
+            }
+        }
+
+        self.environment = previous;
+        result
+    }
+
+    fn execute_loop_body(&mut self, environment: Environment, body: &[Stmt]) -> Result<Option<Flow>, LangError> {
+        match self.execute_in(environment, body)? {
+            Some(Flow::Continue) => Ok(None),
+            flow => Ok(flow),
+        }
+    }
+
+    fn execute_statement(&mut self, stmt: Stmt) -> Result<Option<Flow>, LangError> {
+        self.budget.tick()?;
+        match stmt {
+            Stmt::Expression(expr) => {
+                self.evaluate_expression(expr)?;
+                Ok(None)
+            }
+            Stmt::Let { name, value, .. } => {
+                let val = self.evaluate_expression(value)?;
+                self.environment.define(name, val);
+                Ok(None)
+            }
+            Stmt::Function { name, params, body, .. } => {
+                let func = Value::Function {
+                    name: name.clone(),
+                    params,
+                    body,
+                    closure: self.environment.clone(),
+                };
+                self.environment.define(name, func);
+                Ok(None)
+            }
+            Stmt::Return(expr, _) => {
+                let value = if let Some(e) = expr {
+                    self.evaluate_expression(e)?
+                } else {
+                    Value::Nil
+                };
+                Ok(Some(Flow::Return(value)))
+            }
+            Stmt::If { condition, then_branch, else_branch } => {
+                let cond_val = self.evaluate_expression(condition)?;
+                
+                let branch = if cond_val.is_truthy() {
+                    then_branch
+                } else if let Some(else_stmts) = else_branch {
+                    else_stmts
+                } else {
+                    return Ok(None);
+                };
+                self.execute_in(self.environment.clone(), &branch)
+            }
+            Stmt::While { condition, body } => {
+                loop {
+                    let cond_val = self.evaluate_expression(condition.clone())?;
+                    if !cond_val.is_truthy() {
+                        break;
+                    }
+                    
+                    match self.execute_loop_body(self.environment.clone(), &body)? {
+                        Some(Flow::Break) => break,
+                        Some(flow) => return Ok(Some(flow)),
+                        None => {}
+                    }
+                }
+                Ok(None)
+            }
+            Stmt::For { initializer, condition, increment, body } => {
+                let loop_env = Environment::enclosed(&self.environment);
+                let previous = std::mem::replace(&mut self.environment, loop_env);
+                let result = self.execute_for(initializer, condition, increment, &body);
+                self.environment = previous;
+                result
+            }
+            Stmt::ForIn { variable, iterable, body } => {
+                let items = self.evaluate_expression(iterable)?.iter_values()?;
+
+                for item in items {
+                    let iteration_env = Environment::enclosed(&self.environment);
+                    iteration_env.define(variable.clone(), item);
+
+                    match self.execute_loop_body(iteration_env, &body)? {
+                        Some(Flow::Break) => break,
+                        Some(flow) => return Ok(Some(flow)),
+                        None => {}
+                    }
+                }
+                Ok(None)
+            }
+            Stmt::Import { path, alias, span } => {
+                let module = self.load_module(&path).map_err(|e| e.or_span(span))?;
+                self.environment.define(alias, module);
+                Ok(None)
+            }
+            Stmt::Break => Ok(Some(Flow::Break)),
+            Stmt::Continue => Ok(Some(Flow::Continue)),
+            Stmt::Block(stmts) => {
+                let block_env = Environment::enclosed(&self.environment);
+                self.execute_in(block_env, &stmts)
+            }
+            Stmt::Throw(value, span) => {
+                let value = self.evaluate_expression(value)?;
+                Err(value.thrown().or_span(span))
+            }
+            Stmt::Try { body, variable, handler } => {
+                let body_env = Environment::enclosed(&self.environment);
+                match self.execute_in(body_env, &body) {
+                    Err(error) if error.is_catchable() => {
+                        let error = error.or_stack(|| self.stack_trace());
+                        let handler_env = Environment::enclosed(&self.environment);
+                        handler_env.define(variable, Value::caught(error));
+                        self.execute_in(handler_env, &handler)
+                    }
+                    result => result,
+                }
+            }
+        }
+    }
+
+    fn stack_trace(&self) -> Vec<String> {
+        self.call_stack.iter().rev().cloned().collect()
+    }
+
+    fn execute_for(
+        &mut self,
+        initializer: Option<Box<Stmt>>,
+        condition: Option<Expr>,
+        increment: Option<Expr>,
+        body: &[Stmt],
+    ) -> Result<Option<Flow>, LangError> {
+        if let Some(init) = initializer {
+            self.execute_statement(*init)?;
+        }
+
+        loop {
+            if let Some(cond) = &condition
+                && !self.evaluate_expression(cond.clone())?.is_truthy()
+            {
+                break;
+            }
+
+            match self.execute_loop_body(self.environment.clone(), body)? {
+                Some(Flow::Break) => break,
+                Some(flow) => return Ok(Some(flow)),
+                None => {}
+            }
+
+            if let Some(incr) = &increment {
+                self.evaluate_expression(incr.clone())?;
+            }
+        }
+        Ok(None)
+    }
+
+    fn evaluate_expression(&mut self, expr: Expr) -> Result<Value, LangError> {
+        self.budget.tick()?;
+        match expr {
+            Expr::Number(n) => Ok(Value::Number(n)),
+            Expr::String(s) => Ok(Value::String(s)),
+            Expr::Bool(b) => Ok(Value::Bool(b)),
+            Expr::Nil => Ok(Value::Nil),
+            Expr::Identifier { name, depth, span } => {
+                let value = match depth {
+                    Some(distance) => self.environment.get_at(distance, &name),
+                    None => self.globals.get_local(&name),
+                };
+                value.ok_or_else(|| {
+                    LangError::at(ErrorKind::UndefinedVariable, format!("Undefined variable: {}", name), span)
+                })
+            }
+            Expr::Binary { left, op, right, span } => {
+                let left_val = self.evaluate_expression(*left)?;
+                let right_val = self.evaluate_expression(*right)?;
+                Value::binary_op(left_val, op, right_val).map_err(|e| e.or_span(span))
+            }
+            Expr::Unary { op, expr, span } => {
+                let val = self.evaluate_expression(*expr)?;
+                Value::unary_op(op, val).map_err(|e| e.or_span(span))
+            }
+            Expr::Call { callee, args, span } => {
+                let func = self.evaluate_expression(*callee)?;
+                let arg_vals: Result<Vec<_>, _> = args.into_iter()
+                    .map(|arg| self.evaluate_expression(arg))
+                    .collect();
+                self.call_function(func, arg_vals?).map_err(|e| e.or_span(span))
+            }
+            Expr::Array(elements) => {
+                let vals: Result<Vec<_>, _> = elements.into_iter()
+                    .map(|e| self.evaluate_expression(e))
+                    .collect();
+                let array = Value::array(vals?);
+                self.budget.check_size(&array)?;
+                Ok(array)
+            }
+            Expr::Index { object, index, span } => {
+                let object_val = self.evaluate_expression(*object)?;
+                let index_val = self.evaluate_expression(*index)?;
+                object_val.get_index(&index_val).map_err(|e| e.or_span(span))
+            }
+            Expr::Object(fields) => {
+                let mut values = BTreeMap::new();
+                for (key, expr) in fields {
+                    let val = self.evaluate_expression(expr)?;
+                    values.insert(key, val);
+                }
+                Ok(Value::object(values))
+            }
+            Expr::Property { object, name, span } => {
+                self.evaluate_expression(*object)?
+                    .get_property(&name)
+                    .map_err(|e| e.or_span(span))
+            }
+            Expr::PropertyAssign { object, name, value, span } => {
+                let object_val = self.evaluate_expression(*object)?;
+                let val = self.evaluate_expression(*value)?;
+                object_val.set_property(&name, val.clone()).map_err(|e| e.or_span(span))?;
+                Ok(val)
+            }
+            Expr::Assign { name, value, depth, span } => {
+                let val = self.evaluate_expression(*value)?;
+                let assigned = match depth {
+                    Some(distance) => self.environment.assign_at(distance, &name, val.clone()),
+                    None => self.globals.assign_at(0, &name, val.clone()),
+                };
+                if assigned {
+                    Ok(val)
+                } else {
+                    Err(LangError::at(ErrorKind::UndefinedVariable, format!("Undefined variable: {}", name), span))
+                }
+            }
+            Expr::IndexAssign { object, index, value, span } => {
+                let object_val = self.evaluate_expression(*object)?;
+                let index_val = self.evaluate_expression(*index)?;
+                let val = self.evaluate_expression(*value)?;
+                object_val.set_index(&index_val, val.clone()).map_err(|e| e.or_span(span))?;
+                Ok(val)
+            }
+            Expr::Function { params, body } => Ok(Value::Function {
+                name: ANONYMOUS_FUNCTION.to_string(),
+                params,
+                body,
+                closure: self.environment.clone(),
+            }),
+        }
+    }
+
+    /// Loads (or fetches from the cache) the module at `path`, relative to the
+    /// importing file, and returns its top-level bindings as an object.
+    fn load_module(&mut self, path: &str) -> Result<Value, LangError> {
+        let import_error = |message: String| LangError::new(ErrorKind::ImportError, message);
+        let base = match self.loading.last().and_then(|file| file.parent()) {
+            Some(dir) => dir.to_path_buf(),
+            None => PathBuf::from("."),
+        };
+        let file = fs::canonicalize(base.join(path))
+            .map_err(|e| import_error(format!("Cannot import \"{}\": {}", path, e)))?;
+
+        if let Some(module) = self.modules.get(&file) {
+            return Ok(module.clone());
+        }
+        if let Some(start) = self.loading.iter().position(|loading| *loading == file) {
+            let chain: Vec<String> = self.loading[start..].iter()
+                .chain(std::iter::once(&file))
+                .map(|file| file.file_name().unwrap_or_default().to_string_lossy().into_owned())
+                .collect();
+            return Err(LangError::new(ErrorKind::ImportCycle, format!("Import cycle: {}", chain.join(" -> "))));
+        }
+
+        let source = fs::read_to_string(&file)
+            .map_err(|e| import_error(format!("Cannot import \"{}\": {}", path, e)))?;
+        let program = parse_source(&source)
+            .and_then(resolve_program)
+            .map_err(|errors| import_error(format!("In module \"{}\": {}", path, errors[0])))?;
+        let exported: Vec<String> = program.iter()
+            .filter_map(|stmt| match stmt {
+                Stmt::Let { name, .. } | Stmt::Function { name, .. } => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
+
+        // Each module gets its own globals, seeded with the natives visible here.
+        let module_globals = Environment::new();
+        for (name, value) in self.globals.scope.borrow().values.iter() {
+            if let Value::NativeFunction(_) = value {
+                module_globals.define(name.clone(), value.clone());
+            }
+        }
+
+        let caller_globals = std::mem::replace(&mut self.globals, module_globals.clone());
+        let caller_env = std::mem::replace(&mut self.environment, module_globals.clone());
+        self.loading.push(file.clone());
+        let result = self.run_program(program);
+        self.loading.pop();
+        self.globals = caller_globals;
+        self.environment = caller_env;
+
+        result.map_err(|error| {
+            // Keep the outer error: a cycle or import failure deeper down
+            // is already described in terms of module paths.
+            match error.kind {
+                ErrorKind::ImportCycle | ErrorKind::ImportError => error,
+                _ => import_error(format!("In module \"{}\": {}", path, error)),
+            }
+        })?;
+
+        let namespace = exported.into_iter()
+            .filter_map(|name| module_globals.get_local(&name).map(|value| (name, value)))
+            .collect();
+        let module = Value::object(namespace);
+        self.modules.insert(file, module.clone());
+        Ok(module)
+    }
+
+    fn call_function(&mut self, func: Value, args: Vec<Value>) -> Result<Value, LangError> {
+        match func {
+            Value::Function { name, params, body, closure } => {
+                if params.len() != args.len() {
+                    return Err(LangError::new(ErrorKind::ArgumentCount, "Argument count mismatch"));
+                }
+
+                let call_env = Environment::enclosed(&closure);
+                for (param, arg) in params.iter().zip(args) {
+                    call_env.define(param.clone(), arg);
+                }
+
+                self.budget.check_call_depth(self.call_stack.len() + 1)?;
+                self.call_stack.push(name);
+                // Globals are those of the module that defined the function.
+                let caller_globals = std::mem::replace(&mut self.globals, closure.root());
+                let result = self.execute_in(call_env, &body).map_err(|e| e.or_stack(|| self.stack_trace()));
+                self.globals = caller_globals;
+                self.call_stack.pop();
+
+                match result? {
+                    Some(Flow::Return(value)) => Ok(value),
+                    _ => Ok(Value::Nil),
+                }
+            }
+            Value::NativeFunction(native) => {
+                let result = native.call(&args).map_err(|e| {
+                    e.or_stack(|| std::iter::once(native.name.clone()).chain(self.stack_trace()).collect())
+                })?;
+                self.budget.check_native_call(&args, &result)?;
+                Ok(result)
+            }
+            _ => Err(LangError::new(ErrorKind::NotCallable, "Not a function")),
+        }
+    }
+}
+
+// ============= BYTECODE COMPILER =============
+
+#[derive(Debug, Clone)]
+enum OpCode {
+    Constant(usize),
+    Nil,
+    True,
+    False,
+    Pop,
+    GetLocal(usize),
+    DefineLocal(usize),
+    SetLocal(usize),
+    FreshSlots(usize, usize),
+    GetUpvalue(usize),
+    SetUpvalue(usize),
+    GetGlobal(usize),
+    DefineGlobal(usize),
+    SetGlobal(usize),
+    GetIndex,
+    SetIndex,
+    GetProperty(usize),
+    SetProperty(usize),
+    Object(usize),
+    IterSnapshot,
+    ForNext { iter_slot: usize, index_slot: usize, exit: usize },
+    Binary(BinaryOp),
+    Unary(UnaryOp),
+    Jump(usize),
+    JumpIfFalse(usize),
+    Call(usize),
+    Closure(usize),
+    Array(usize),
+    PushHandler(usize),
+    PopHandler,
+    Throw,
+    Return,
+}
+
+#[derive(Debug, Default)]
+struct Chunk {
+    code: Vec<OpCode>,
+    constants: Vec<Value>,
+    names: Vec<String>,
+    functions: Vec<Rc<FunctionProto>>,
+    spans: Vec<Option<Span>>,
+}
+
+impl Chunk {
+    fn emit(&mut self, op: OpCode) -> usize {
+        self.code.push(op);
+        self.spans.push(None);
+        self.code.len() - 1
+    }
+
+    fn emit_at(&mut self, op: OpCode, span: Span) -> usize {
+        let at = self.emit(op);
+        self.spans[at] = Some(span);
+        at
+    }
+
+    fn add_constant(&mut self, value: Value) -> usize {
+        self.constants.push(value);
+        self.constants.len() - 1
+    }
+
+    fn add_function(&mut self, function: Rc<FunctionProto>) -> usize {
+        self.functions.push(function);
+        self.functions.len() - 1
+    }
+
+    fn add_name(&mut self, name: &str) -> usize {
+        if let Some(index) = self.names.iter().position(|n| n == name) {
+            return index;
+        }
+        self.names.push(name.to_string());
+        self.names.len() - 1
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UpvalueRef {
+    Local(usize),
+    Upvalue(usize),
+}
+
+#[derive(Debug)]
+struct FunctionProto {
+    name: String,
+    arity: usize,
+    slot_names: Vec<String>,
+    upvalues: Vec<UpvalueRef>,
+    chunk: Chunk,
+}
+
+#[derive(Default)]
+struct LoopContext {
+    break_jumps: Vec<usize>,
+    continue_jumps: Vec<usize>,
+    // `try` handlers already active when the loop began; jumping out of the
+    // loop pops any installed since.
+    handler_depth: usize,
+}
+
+struct FunctionState {
+    proto: FunctionProto,
+    scopes: Vec<HashMap<String, usize>>,
+    loops: Vec<LoopContext>,
+    handlers: usize,
+}
+
+impl FunctionState {
+    fn script() -> Self {
+        FunctionState {
+            proto: FunctionProto {
+                name: "<script>".to_string(),
+                arity: 0,
+                slot_names: Vec::new(),
+                upvalues: Vec::new(),
+                chunk: Chunk::default(),
+            },
+            scopes: Vec::new(),
+            loops: Vec::new(),
+            handlers: 0,
+        }
+    }
+
+    fn function(name: &str, params: &[String]) -> Self {
+        let params_scope = params.iter()
+            .enumerate()
+            .map(|(slot, param)| (param.clone(), slot))
+            .collect();
+
+        FunctionState {
+            proto: FunctionProto {
+                name: name.to_string(),
+                arity: params.len(),
+                slot_names: params.to_vec(),
+                upvalues: Vec::new(),
+                chunk: Chunk::default(),
+            },
+            scopes: vec![params_scope],
+            loops: Vec::new(),
+            handlers: 0,
+        }
+    }
+}
+
+struct Compiler {
+    states: Vec<FunctionState>,
+}
+
+impl Compiler {
+    fn compile(program: &[Stmt]) -> Result<Rc<FunctionProto>, LangError> {
+        let mut compiler = Compiler {
+            states: vec![FunctionState::script()],
+        };
+
+        for stmt in program {
+            compiler.compile_statement(stmt)?;
+        }
+
+        compiler.chunk().emit(OpCode::Nil);
+        compiler.chunk().emit(OpCode::Return);
+
+        let state = compiler.states.pop().expect("script state");
+        Ok(Rc::new(state.proto))
+    }
+
+    fn state(&mut self) -> &mut FunctionState {
+        self.states.last_mut().expect("compiler state")
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.state().proto.chunk
+    }
+
+    fn patch_jump(&mut self, at: usize, target: usize) {
+        match &mut self.chunk().code[at] {
+            OpCode::Jump(dest) | OpCode::JumpIfFalse(dest) | OpCode::PushHandler(dest) => *dest = target,
+            OpCode::ForNext { exit, .. } => *exit = target,
+            _ => unreachable!(),
+        }
+    }
+
+    fn hidden_slot(&mut self, label: &str) -> usize {
+        let names = &mut self.state().proto.slot_names;
+        names.push(label.to_string());
+        names.len() - 1
+    }
+
+    fn begin_scope(&mut self) -> usize {
+        let first_slot = self.state().proto.slot_names.len();
+        self.state().scopes.push(HashMap::new());
+        self.chunk().emit(OpCode::FreshSlots(first_slot, first_slot))
+    }
+
+    fn end_scope(&mut self, fresh: usize) {
+        self.state().scopes.pop();
+        let end_slot = self.state().proto.slot_names.len();
+        if let OpCode::FreshSlots(_, end) = &mut self.chunk().code[fresh] {
+            *end = end_slot;
+        }
+    }
+
+    fn compile_loop_body(&mut self, body: &[Stmt]) -> Result<LoopContext, LangError> {
+        let handler_depth = self.state().handlers;
+        self.state().loops.push(LoopContext { handler_depth, ..LoopContext::default() });
+        let result = self.compile_block(body);
+        let context = self.state().loops.pop().expect("loop context");
+        result.map(|_| context)
+    }
+
+    fn finish_loop(&mut self, context: LoopContext, continue_target: usize, end: usize) {
+        for jump in context.continue_jumps {
+            self.patch_jump(jump, continue_target);
+        }
+        for jump in context.break_jumps {
+            self.patch_jump(jump, end);
+        }
+    }
+
+    fn declare_variable(&mut self, name: &str) -> Option<usize> {
+        let state = self.state();
+        let scope = state.scopes.last_mut()?;
+
+        if let Some(&slot) = scope.get(name) {
+            return Some(slot);
+        }
+
+        let slot = state.proto.slot_names.len();
+        state.proto.slot_names.push(name.to_string());
+        scope.insert(name.to_string(), slot);
+        Some(slot)
+    }
+
+    fn define_variable(&mut self, name: &str) {
+        let slot = self.declare_variable(name);
+        self.emit_variable_definition(name, slot);
+    }
+
+    fn resolve_local(&self, depth: usize, name: &str) -> Option<usize> {
+        self.states[depth].scopes.iter().rev().find_map(|scope| scope.get(name).copied())
+    }
+
+    fn resolve_upvalue(&mut self, depth: usize, name: &str) -> Option<usize> {
+        if depth == 0 {
+            return None;
+        }
+
+        let upvalue = if let Some(slot) = self.resolve_local(depth - 1, name) {
+            UpvalueRef::Local(slot)
+        } else {
+            UpvalueRef::Upvalue(self.resolve_upvalue(depth - 1, name)?)
+        };
+
+        let upvalues = &mut self.states[depth].proto.upvalues;
+        if let Some(index) = upvalues.iter().position(|u| *u == upvalue) {
+            return Some(index);
+        }
+        upvalues.push(upvalue);
+        Some(upvalues.len() - 1)
+    }
+
+    fn emit_variable_definition(&mut self, name: &str, slot: Option<usize>) {
+        let op = match slot {
+            Some(slot) => OpCode::DefineLocal(slot),
+            None => OpCode::DefineGlobal(self.chunk().add_name(name)),
+        };
+        self.chunk().emit(op);
+    }
+
+    fn compile_block(&mut self, stmts: &[Stmt]) -> Result<(), LangError> {
+        // Slots for the whole block up front, so closures can capture names
+        // declared after them (mutually recursive local functions).
+        for stmt in stmts {
+            if let Stmt::Let { name, .. } | Stmt::Function { name, .. } = stmt {
+                self.declare_variable(name);
+            }
+        }
+
+        for stmt in stmts {
+            self.compile_statement(stmt)?;
+        }
+        Ok(())
+    }
+
+    fn compile_scoped_block(&mut self, stmts: &[Stmt]) -> Result<(), LangError> {
+        let fresh = self.begin_scope();
+        let result = self.compile_block(stmts);
+        self.end_scope(fresh);
+        result
+    }
+
+    fn compile_statement(&mut self, stmt: &Stmt) -> Result<(), LangError> {
+        match stmt {
+            Stmt::Expression(expr) => {
+                self.compile_expression(expr)?;
+                self.chunk().emit(OpCode::Pop);
+            }
+            Stmt::Let { name, value, .. } => {
+                self.compile_expression(value)?;
+                self.define_variable(name);
+            }
+            Stmt::Function { name, params, body, .. } => {
+                // Declared before the body is compiled so nested functions can recurse.
+                let slot = self.declare_variable(name);
+                self.compile_function(name, params, body)?;
+                self.emit_variable_definition(name, slot);
+            }
+            Stmt::Return(expr, _) => {
+                match expr {
+                    Some(e) => self.compile_expression(e)?,
+                    None => {
+                        self.chunk().emit(OpCode::Nil);
+                    }
+                }
+                self.chunk().emit(OpCode::Return);
+            }
+            Stmt::If { condition, then_branch, else_branch } => {
+                self.compile_expression(condition)?;
+                let else_jump = self.chunk().emit(OpCode::JumpIfFalse(0));
+                self.compile_block(then_branch)?;
+
+                if let Some(else_stmts) = else_branch {
+                    let end_jump = self.chunk().emit(OpCode::Jump(0));
+                    let else_start = self.chunk().code.len();
+                    self.patch_jump(else_jump, else_start);
+                    self.compile_block(else_stmts)?;
+                    let end = self.chunk().code.len();
+                    self.patch_jump(end_jump, end);
+                } else {
+                    let end = self.chunk().code.len();
+                    self.patch_jump(else_jump, end);
+                }
+            }
+            Stmt::While { condition, body } => {
+                let loop_start = self.chunk().code.len();
+                self.compile_expression(condition)?;
+                let exit_jump = self.chunk().emit(OpCode::JumpIfFalse(0));
+                let context = self.compile_loop_body(body)?;
+                self.chunk().emit(OpCode::Jump(loop_start));
+                let end = self.chunk().code.len();
+                self.patch_jump(exit_jump, end);
+                self.finish_loop(context, loop_start, end);
+            }
+            Stmt::For { initializer, condition, increment, body } => {
+                let fresh = self.begin_scope();
+                if let Some(init) = initializer {
+                    self.compile_statement(init)?;
+                }
+
+                let loop_start = self.chunk().code.len();
+                let exit_jump = match condition {
+                    Some(cond) => {
+                        self.compile_expression(cond)?;
+                        Some(self.chunk().emit(OpCode::JumpIfFalse(0)))
+                    }
+                    None => None,
+                };
+
+                let context = self.compile_loop_body(body)?;
+                let continue_target = self.chunk().code.len();
+                if let Some(incr) = increment {
+                    self.compile_expression(incr)?;
+                    self.chunk().emit(OpCode::Pop);
+                }
+                self.chunk().emit(OpCode::Jump(loop_start));
+
+                let end = self.chunk().code.len();
+                if let Some(jump) = exit_jump {
+                    self.patch_jump(jump, end);
+                }
+                self.finish_loop(context, continue_target, end);
+                self.end_scope(fresh);
+            }
+            Stmt::ForIn { variable, iterable, body } => {
+                self.compile_expression(iterable)?;
+                self.chunk().emit(OpCode::IterSnapshot);
+
+                let outer_fresh = self.begin_scope();
+                let iter_slot = self.hidden_slot("<iterable>");
+                self.chunk().emit(OpCode::DefineLocal(iter_slot));
+                let zero = self.chunk().add_constant(Value::Number(0.0));
+                self.chunk().emit(OpCode::Constant(zero));
+                let index_slot = self.hidden_slot("<index>");
+                self.chunk().emit(OpCode::DefineLocal(index_slot));
+
+                let loop_start = self.chunk().code.len();
+                let iteration_fresh = self.begin_scope();
+                let next = self.chunk().emit(OpCode::ForNext { iter_slot, index_slot, exit: 0 });
+                self.define_variable(variable);
+
+                let context = self.compile_loop_body(body)?;
+                self.chunk().emit(OpCode::Jump(loop_start));
+                self.end_scope(iteration_fresh);
+
+                let end = self.chunk().code.len();
+                self.patch_jump(next, end);
+                self.finish_loop(context, loop_start, end);
+                self.end_scope(outer_fresh);
+            }
+            Stmt::Import { span, .. } => {
+                return Err(LangError::at(
+                    ErrorKind::Unsupported,
+                    "import is only supported by the tree-walking interpreter",
+                    *span,
+                ));
+            }
+            Stmt::Break => {
+                let jump = self.emit_loop_exit("Break")?;
+                if let Some(context) = self.state().loops.last_mut() {
+                    context.break_jumps.push(jump);
+                }
+            }
+            Stmt::Continue => {
+                let jump = self.emit_loop_exit("Continue")?;
+                if let Some(context) = self.state().loops.last_mut() {
+                    context.continue_jumps.push(jump);
+                }
+            }
+            Stmt::Block(stmts) => self.compile_scoped_block(stmts)?,
+            Stmt::Throw(value, span) => {
+                self.compile_expression(value)?;
+                self.chunk().emit_at(OpCode::Throw, *span);
+            }
+            Stmt::Try { body, variable, handler } => {
+                let push = self.chunk().emit(OpCode::PushHandler(0));
+                self.state().handlers += 1;
+                let result = self.compile_scoped_block(body);
+                self.state().handlers -= 1;
+                result?;
+                self.chunk().emit(OpCode::PopHandler);
+                let end_jump = self.chunk().emit(OpCode::Jump(0));
+
+                // The VM enters the handler with the caught error on the stack.
+                let catch_start = self.chunk().code.len();
+                self.patch_jump(push, catch_start);
+                let fresh = self.begin_scope();
+                self.define_variable(variable);
+                let result = self.compile_block(handler);
+                self.end_scope(fresh);
+                result?;
+
+                let end = self.chunk().code.len();
+                self.patch_jump(end_jump, end);
+            }
+        }
+        Ok(())
+    }
+
+    /// Emits the jump for a `break` or `continue`, preceded by a `PopHandler`
+    /// for every `try` it leaves.
+    fn emit_loop_exit(&mut self, keyword: &str) -> Result<usize, LangError> {
+        let state = self.state();
+        let Some(handler_depth) = state.loops.last().map(|context| context.handler_depth) else {
+            return Err(LangError::new(ErrorKind::LoopControlOutsideLoop, format!("{} outside of a loop", keyword)));
+        };
+        for _ in handler_depth..state.handlers {
+            self.chunk().emit(OpCode::PopHandler);
+        }
+        Ok(self.chunk().emit(OpCode::Jump(0)))
+    }
+
+    fn compile_function(&mut self, name: &str, params: &[String], body: &[Stmt]) -> Result<(), LangError> {
+        self.states.push(FunctionState::function(name, params));
+
+        let result = self.compile_block(body);
+        self.chunk().emit(OpCode::Nil);
+        self.chunk().emit(OpCode::Return);
+
+        let state = self.states.pop().expect("function state");
+        result?;
+
+        let index = self.chunk().add_function(Rc::new(state.proto));
+        self.chunk().emit(OpCode::Closure(index));
+        Ok(())
+    }
+
+    fn compile_expression(&mut self, expr: &Expr) -> Result<(), LangError> {
+        match expr {
+            Expr::Number(n) => {
+                let index = self.chunk().add_constant(Value::Number(*n));
+                self.chunk().emit(OpCode::Constant(index));
+            }
+            Expr::String(s) => {
+                let index = self.chunk().add_constant(Value::String(s.clone()));
+                self.chunk().emit(OpCode::Constant(index));
+            }
+            Expr::Bool(true) => {
+                self.chunk().emit(OpCode::True);
+            }
+            Expr::Bool(false) => {
+                self.chunk().emit(OpCode::False);
+            }
+            Expr::Nil => {
+                self.chunk().emit(OpCode::Nil);
+            }
+            Expr::Identifier { name, span, .. } => {
+                let depth = self.states.len() - 1;
+                let op = if let Some(slot) = self.resolve_local(depth, name) {
+                    OpCode::GetLocal(slot)
+                } else if let Some(index) = self.resolve_upvalue(depth, name) {
+                    OpCode::GetUpvalue(index)
+                } else {
+                    OpCode::GetGlobal(self.chunk().add_name(name))
+                };
+                self.chunk().emit_at(op, *span);
+            }
+            Expr::Binary { left, op, right, span } => {
+                self.compile_expression(left)?;
+                self.compile_expression(right)?;
+                self.chunk().emit_at(OpCode::Binary(op.clone()), *span);
+            }
+            Expr::Unary { op, expr, span } => {
+                self.compile_expression(expr)?;
+                self.chunk().emit_at(OpCode::Unary(op.clone()), *span);
+            }
+            Expr::Call { callee, args, span } => {
+                self.compile_expression(callee)?;
+                for arg in args {
+                    self.compile_expression(arg)?;
+                }
+                self.chunk().emit_at(OpCode::Call(args.len()), *span);
+            }
+            Expr::Array(elements) => {
+                for element in elements {
+                    self.compile_expression(element)?;
+                }
+                self.chunk().emit(OpCode::Array(elements.len()));
+            }
+            Expr::Index { object, index, span } => {
+                self.compile_expression(object)?;
+                self.compile_expression(index)?;
+                self.chunk().emit_at(OpCode::GetIndex, *span);
+            }
+            Expr::Assign { name, value, span, .. } => {
+                self.compile_expression(value)?;
+                let depth = self.states.len() - 1;
+                let op = if let Some(slot) = self.resolve_local(depth, name) {
+                    OpCode::SetLocal(slot)
+                } else if let Some(index) = self.resolve_upvalue(depth, name) {
+                    OpCode::SetUpvalue(index)
+                } else {
+                    OpCode::SetGlobal(self.chunk().add_name(name))
+                };
+                self.chunk().emit_at(op, *span);
+            }
+            Expr::IndexAssign { object, index, value, span } => {
+                self.compile_expression(object)?;
+                self.compile_expression(index)?;
+                self.compile_expression(value)?;
+                self.chunk().emit_at(OpCode::SetIndex, *span);
+            }
+            Expr::Object(fields) => {
+                for (key, value) in fields {
+                    let index = self.chunk().add_constant(Value::String(key.clone()));
+                    self.chunk().emit(OpCode::Constant(index));
+                    self.compile_expression(value)?;
+                }
+                self.chunk().emit(OpCode::Object(fields.len()));
+            }
+            Expr::Property { object, name, span } => {
+                self.compile_expression(object)?;
+                let index = self.chunk().add_name(name);
+                self.chunk().emit_at(OpCode::GetProperty(index), *span);
+            }
+            Expr::PropertyAssign { object, name, value, span } => {
+                self.compile_expression(object)?;
+                self.compile_expression(value)?;
+                let index = self.chunk().add_name(name);
+                self.chunk().emit_at(OpCode::SetProperty(index), *span);
+            }
+            Expr::Function { params, body } => self.compile_function(ANONYMOUS_FUNCTION, params, body)?,
+        }
+        Ok(())
+    }
+}
+
+// ============= STACK VM =============
+
+type Slot = Rc<RefCell<Option<Value>>>;
+
+struct VmClosure {
+    proto: Rc<FunctionProto>,
+    upvalues: Vec<Slot>,
+}
+
+impl fmt::Debug for VmClosure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<fn/{}>", self.proto.arity)
+    }
+}
+
+fn fresh_slots(count: usize) -> Vec<Slot> {
+    (0..count).map(|_| Rc::new(RefCell::new(None))).collect()
+}
+
+struct CallFrame {
+    closure: Rc<VmClosure>,
+    ip: usize,
+    slots: Vec<Slot>,
+}
+
+/// An active `try`: where to resume, and how far to unwind the call frames
+/// and value stack, when an error is caught.
+struct Handler {
+    frame_count: usize,
+    stack_len: usize,
+    catch_ip: usize,
+}
+
+struct Vm {
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    handlers: Vec<Handler>,
+    globals: HashMap<String, Value>,
+    budget: Budget,
+}
+
+impl Vm {
+    fn new() -> Self {
+        Vm {
+            stack: Vec::new(),
+            frames: Vec::new(),
+            handlers: Vec::new(),
+            globals: native_prelude()
+                .into_iter()
+                .map(|(name, native)| (name.to_string(), native))
+                .collect(),
+            budget: Budget::new(Limits::default()),
+        }
+    }
+
+    fn set_limits(&mut self, limits: Limits) {
+        self.budget = Budget::new(limits);
+    }
+
+    #[cfg(test)]
+    fn register_native(
+        &mut self,
+        name: &str,
+        arity: Option<usize>,
+        func: impl Fn(&[Value]) -> Result<Value, String> + 'static,
+    ) {
+        self.globals.insert(name.to_string(), NativeFunction::value(name, arity, func));
+    }
+
+    fn interpret(&mut self, program: &[Stmt]) -> Result<(), LangError> {
+        // Only the static checks matter here; the compiler resolves slots itself.
+        resolve_program(program.to_vec()).map_err(|mut errors| errors.remove(0))?;
+        let script = Compiler::compile(program)?;
+        self.budget.start();
+        self.run(script)
+    }
+
+    #[cfg(test)]
+    fn global(&self, name: &str) -> Option<Value> {
+        self.globals.get(name).cloned()
+    }
+
+    fn run(&mut self, script: Rc<FunctionProto>) -> Result<(), LangError> {
+        self.frames.push(CallFrame {
+            slots: fresh_slots(script.slot_names.len()),
+            closure: Rc::new(VmClosure {
+                proto: script,
+                upvalues: Vec::new(),
+            }),
+            ip: 0,
+        });
+
+        let result = self.execute();
+        self.stack.clear();
+        self.frames.clear();
+        self.handlers.clear();
+        result
+    }
+
+    fn frame(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("call frame")
+    }
+
+    fn pop(&mut self) -> Result<Value, LangError> {
+        self.stack.pop().ok_or_else(|| LangError::new(ErrorKind::Internal, "Stack underflow"))
+    }
+
+    fn peek_value(&self) -> Result<Value, LangError> {
+        self.stack.last().cloned().ok_or_else(|| LangError::new(ErrorKind::Internal, "Stack underflow"))
+    }
+
+    fn pop_many(&mut self, count: usize) -> Result<Vec<Value>, LangError> {
+        if count > self.stack.len() {
+            return Err(LangError::new(ErrorKind::Internal, "Stack underflow"));
+        }
+        Ok(self.stack.split_off(self.stack.len() - count))
+    }
+
+    fn execute(&mut self) -> Result<(), LangError> {
+        loop {
+            match self.step() {
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(error) => {
+                    let error = match self.current_span() {
+                        Some(span) => error.or_span(span),
+                        None => error,
+                    };
+                    if !error.is_catchable() {
+                        return Err(error);
+                    }
+                    let Some(handler) = self.handlers.pop() else {
+                        return Err(error);
+                    };
+
+                    let error = error.or_stack(|| self.stack_trace());
+                    self.frames.truncate(handler.frame_count);
+                    self.stack.truncate(handler.stack_len);
+                    self.stack.push(Value::caught(error));
+                    self.frame().ip = handler.catch_ip;
+                }
+            }
+        }
+    }
+
+    /// Names of the active functions, innermost first; the script frame is left out.
+    fn stack_trace(&self) -> Vec<String> {
+        self.frames.iter().skip(1).rev().map(|frame| frame.closure.proto.name.clone()).collect()
+    }
+
+    /// Span of the instruction the innermost frame is executing, if the
+    /// compiler recorded one for it.
+    fn current_span(&self) -> Option<Span> {
+        let frame = self.frames.last()?;
+        let chunk = &frame.closure.proto.chunk;
+        chunk.spans.get(frame.ip.checked_sub(1)?).copied().flatten()
+    }
+
+    /// Executes a single instruction, returning `true` once the script frame returns.
+    fn step(&mut self) -> Result<bool, LangError> {
+        self.budget.tick()?;
+        let op = {
+            let frame = self.frame();
+            let op = frame.closure.proto.chunk.code[frame.ip].clone();
+            frame.ip += 1;
+            op
+        };
+
+        match op {
+            OpCode::Constant(index) => {
+                let value = self.frame().closure.proto.chunk.constants[index].clone();
+                self.stack.push(value);
+            }
+            OpCode::Nil => self.stack.push(Value::Nil),
+            OpCode::True => self.stack.push(Value::Bool(true)),
+            OpCode::False => self.stack.push(Value::Bool(false)),
+            OpCode::Pop => {
+                self.pop()?;
+            }
+            OpCode::GetLocal(slot) => {
+                let frame = self.frame();
+                let value = frame.slots[slot].borrow().clone().ok_or_else(|| {
+                    let name = &frame.closure.proto.slot_names[slot];
+                    LangError::new(ErrorKind::UndefinedVariable, format!("Undefined variable: {}", name))
+                })?;
+                self.stack.push(value);
+            }
+            OpCode::DefineLocal(slot) => {
+                let value = self.pop()?;
+                *self.frame().slots[slot].borrow_mut() = Some(value);
+            }
+            OpCode::SetLocal(slot) => {
+                let value = self.peek_value()?;
+                *self.frame().slots[slot].borrow_mut() = Some(value);
+            }
+            OpCode::FreshSlots(start, end) => {
+                let frame = self.frame();
+                for slot in &mut frame.slots[start..end] {
+                    *slot = Rc::new(RefCell::new(None));
+                }
+            }
+            OpCode::GetUpvalue(index) => {
+                let value = self.frame().closure.upvalues[index].borrow().clone();
+                let value = value.ok_or_else(|| {
+                    LangError::new(ErrorKind::UndefinedVariable, "Undefined variable in closure")
+                })?;
+                self.stack.push(value);
+            }
+            OpCode::SetUpvalue(index) => {
+                let value = self.peek_value()?;
+                *self.frame().closure.upvalues[index].borrow_mut() = Some(value);
+            }
+            OpCode::GetGlobal(index) => {
+                let name = &self.frames.last().expect("call frame").closure.proto.chunk.names[index];
+                let value = self.globals.get(name)
+                    .cloned()
+                    .ok_or_else(|| {
+                        LangError::new(ErrorKind::UndefinedVariable, format!("Undefined variable: {}", name))
+                    })?;
+                self.stack.push(value);
+            }
+            OpCode::DefineGlobal(index) => {
+                let value = self.pop()?;
+                let name = self.frame().closure.proto.chunk.names[index].clone();
+                self.globals.insert(name, value);
+            }
+            OpCode::SetGlobal(index) => {
+                let value = self.peek_value()?;
+                let name = &self.frames.last().expect("call frame").closure.proto.chunk.names[index];
+                match self.globals.get_mut(name) {
+                    Some(slot) => *slot = value,
+                    None => {
+                        return Err(LangError::new(
+                            ErrorKind::UndefinedVariable,
+                            format!("Undefined variable: {}", name),
+                        ));
+                    }
+                }
+            }
+            OpCode::GetIndex => {
+                let index = self.pop()?;
+                let object = self.pop()?;
+                self.stack.push(object.get_index(&index)?);
+            }
+            OpCode::SetIndex => {
+                let value = self.pop()?;
+                let index = self.pop()?;
+                let object = self.pop()?;
+                object.set_index(&index, value.clone())?;
+                self.stack.push(value);
+            }
+            OpCode::GetProperty(index) => {
+                let object = self.pop()?;
+                let name = &self.frame().closure.proto.chunk.names[index];
+                let value = object.get_property(name)?;
+                self.stack.push(value);
+            }
+            OpCode::SetProperty(index) => {
+                let value = self.pop()?;
+                let object = self.pop()?;
+                let name = &self.frame().closure.proto.chunk.names[index];
+                object.set_property(name, value.clone())?;
+                self.stack.push(value);
+            }
+            OpCode::Object(count) => {
+                let entries = self.pop_many(count * 2)?;
+                let mut fields = BTreeMap::new();
+                let mut entries = entries.into_iter();
+                while let (Some(key), Some(value)) = (entries.next(), entries.next()) {
+                    if let Value::String(key) = key {
+                        fields.insert(key, value);
+                    }
+                }
+                self.stack.push(Value::object(fields));
+            }
+            OpCode::IterSnapshot => {
+                let iterable = self.pop()?;
+                self.stack.push(Value::array(iterable.iter_values()?));
+            }
+            OpCode::ForNext { iter_slot, index_slot, exit } => {
+                let frame = self.frame();
+                let iterable = frame.slots[iter_slot].borrow().clone();
+                let index = match frame.slots[index_slot].borrow().clone() {
+                    Some(Value::Number(n)) => n as usize,
+                    _ => 0,
+                };
+
+                let item = match iterable {
+                    Some(Value::Array(items)) => items.borrow().get(index).cloned(),
+                    _ => None,
+                };
+
+                match item {
+                    Some(item) => {
+                        *frame.slots[index_slot].borrow_mut() = Some(Value::Number((index + 1) as f64));
+                        self.stack.push(item);
+                    }
+                    None => frame.ip = exit,
+                }
+            }
+            OpCode::Binary(op) => {
+                let right = self.pop()?;
+                let left = self.pop()?;
+                self.stack.push(Value::binary_op(left, op, right)?);
+            }
+            OpCode::Unary(op) => {
+                let value = self.pop()?;
+                self.stack.push(Value::unary_op(op, value)?);
+            }
+            OpCode::Jump(target) => self.frame().ip = target,
+            OpCode::JumpIfFalse(target) => {
+                if !self.pop()?.is_truthy() {
+                    self.frame().ip = target;
+                }
+            }
+            OpCode::Call(arg_count) => {
+                let args = self.pop_many(arg_count)?;
+                let callee = self.pop()?;
+                self.call_value(callee, args)?;
+            }
+            OpCode::Closure(index) => {
+                let frame = self.frame();
+                let proto = frame.closure.proto.chunk.functions[index].clone();
+                let upvalues = proto.upvalues.iter()
+                    .map(|upvalue| match upvalue {
+                        UpvalueRef::Local(slot) => frame.slots[*slot].clone(),
+                        UpvalueRef::Upvalue(index) => frame.closure.upvalues[*index].clone(),
+                    })
+                    .collect();
+                self.stack.push(Value::CompiledFunction(Rc::new(VmClosure { proto, upvalues })));
+            }
+            OpCode::Array(count) => {
+                let array = Value::array(self.pop_many(count)?);
+                self.budget.check_size(&array)?;
+                self.stack.push(array);
+            }
+            OpCode::PushHandler(catch_ip) => {
+                self.handlers.push(Handler {
+                    frame_count: self.frames.len(),
+                    stack_len: self.stack.len(),
+                    catch_ip,
+                });
+            }
+            OpCode::PopHandler => {
+                self.handlers.pop();
+            }
+            OpCode::Throw => return Err(self.pop()?.thrown()),
+            OpCode::Return => {
+                let result = self.pop()?;
+                self.frames.pop();
+                // Returning from inside a `try` abandons its handler.
+                let frame_count = self.frames.len();
+                while self.handlers.last().is_some_and(|handler| handler.frame_count > frame_count) {
+                    self.handlers.pop();
+                }
+
+                if self.frames.is_empty() {
+                    return Ok(true);
+                }
+                self.stack.push(result);
+            }
+        }
+        Ok(false)
+    }
+
+    fn call_value(&mut self, callee: Value, args: Vec<Value>) -> Result<(), LangError> {
+        match callee {
+            Value::CompiledFunction(closure) => {
+                if closure.proto.arity != args.len() {
+                    return Err(LangError::new(ErrorKind::ArgumentCount, "Argument count mismatch"));
+                }
+                // The script itself occupies the first frame.
+                self.budget.check_call_depth(self.frames.len())?;
+
+                let slots = fresh_slots(closure.proto.slot_names.len());
+                for (slot, arg) in slots.iter().zip(args) {
+                    *slot.borrow_mut() = Some(arg);
+                }
+
+                self.frames.push(CallFrame {
+                    closure,
+                    ip: 0,
+                    slots,
+                });
+                Ok(())
+            }
+            Value::NativeFunction(native) => {
+                let result = native.call(&args).map_err(|e| {
+                    e.or_stack(|| std::iter::once(native.name.clone()).chain(self.stack_trace()).collect())
+                })?;
+                self.budget.check_native_call(&args, &result)?;
+                self.stack.push(result);
+                Ok(())
+            }
+            _ => Err(LangError::new(ErrorKind::NotCallable, "Not a function")),
+        }
+    }
+}
+
+// ============= FORMATTER =============
+
+const INDENT: &str = "    ";
+
+// Binding strength of each expression form, mirroring the parser's descent
+// from `parse_assignment` down to `parse_primary`.
+const PREC_ASSIGNMENT: u8 = 1;
+const PREC_UNARY: u8 = 8;
+const PREC_POSTFIX: u8 = 9;
+const PREC_PRIMARY: u8 = 10;
+
+fn binary_precedence(op: &BinaryOp) -> u8 {
+    match op {
+        BinaryOp::Or => 2,
+        BinaryOp::And => 3,
+        BinaryOp::Equal | BinaryOp::NotEqual => 4,
+        BinaryOp::Less | BinaryOp::LessEqual | BinaryOp::Greater | BinaryOp::GreaterEqual => 5,
+        BinaryOp::Add | BinaryOp::Sub => 6,
+        BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => 7,
+    }
+}
+
+fn binary_symbol(op: &BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Add => "+",
+        BinaryOp::Sub => "-",
+        BinaryOp::Mul => "*",
+        BinaryOp::Div => "/",
+        BinaryOp::Mod => "%",
+        BinaryOp::Equal => "==",
+        BinaryOp::NotEqual => "!=",
+        BinaryOp::Less => "<",
+        BinaryOp::LessEqual => "<=",
+        BinaryOp::Greater => ">",
+        BinaryOp::GreaterEqual => ">=",
+        BinaryOp::And => "and",
+        BinaryOp::Or => "or",
+    }
+}
+
+fn precedence(expr: &Expr) -> u8 {
+    match expr {
+        Expr::Assign { .. } | Expr::IndexAssign { .. } | Expr::PropertyAssign { .. } => PREC_ASSIGNMENT,
+        Expr::Binary { op, .. } => binary_precedence(op),
+        // Numbers rank with unary minus: a negative literal prints as one,
+        // and a postfix `.p` straight after `1.5` would lex as part of it.
+        Expr::Unary { .. } | Expr::Number(_) => PREC_UNARY,
+        Expr::Call { .. } | Expr::Index { .. } | Expr::Property { .. } => PREC_POSTFIX,
+        _ => PREC_PRIMARY,
+    }
+}
+
+/// Whether the formatted expression would start with `{`, which the parser
+/// reads as a block when it opens a statement.
+fn starts_with_brace(expr: &Expr) -> bool {
+    let (leftmost, min_precedence) = match expr {
+        Expr::Object(_) => return true,
+        Expr::Binary { left, op, .. } => (left, binary_precedence(op)),
+        Expr::Call { callee: leftmost, .. }
+        | Expr::Index { object: leftmost, .. }
+        | Expr::Property { object: leftmost, .. }
+        | Expr::IndexAssign { object: leftmost, .. }
+        | Expr::PropertyAssign { object: leftmost, .. } => (leftmost, PREC_POSTFIX),
+        _ => return false,
+    };
+    // A parenthesized operand starts with `(` instead.
+    precedence(leftmost) >= min_precedence && starts_with_brace(leftmost)
+}
+
+fn is_identifier(key: &str) -> bool {
+    let mut chars = key.chars();
+    let starts_well = chars.next().is_some_and(|ch| ch.is_alphabetic() || ch == '_');
+    let is_keyword = matches!(
+        key,
+        "let" | "fn" | "if" | "else" | "while" | "for" | "in" | "break" | "continue" | "return"
+            | "true" | "false" | "nil" | "and" | "or" | "not" | "import" | "throw" | "try" | "catch"
+    );
+    starts_well && chars.all(|ch| ch.is_alphanumeric() || ch == '_') && !is_keyword
+}
+
+/// Name an `import` binds when no `as` is given: the file stem, if it is a
+/// valid identifier.
+fn module_name(path: &str) -> Option<String> {
+    let stem = Path::new(path).file_stem()?.to_str()?;
+    is_identifier(stem).then(|| stem.to_string())
+}
+
+fn quote_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for ch in s.chars() {
+        match ch {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            _ => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Turns an AST back into canonical source: four-space indentation, one
+/// statement per line and only the parentheses the precedence rules need,
+/// so that parsing the output yields the same tree.
+struct Formatter {
+    output: String,
+    indent: usize,
+}
+
+fn format_program(program: &[Stmt]) -> String {
+    let mut formatter = Formatter {
+        output: String::new(),
+        indent: 0,
+    };
+    for stmt in program {
+        formatter.write_statement(stmt);
+    }
+    formatter.output
+}
+
+impl Formatter {
+    fn line(&mut self, text: &str) {
+        self.output.push_str(&INDENT.repeat(self.indent));
+        self.output.push_str(text);
+        self.output.push('\n');
+    }
+
+    fn expression(&mut self, expr: &Expr) -> String {
+        let mut text = String::new();
+        self.write_expression(&mut text, expr);
+        text
+    }
+
+    /// Writes `{`, the indented statements and the closing `}` onto the
+    /// current line, leaving the caller to finish that line.
+    fn body(&mut self, out: &mut String, stmts: &[Stmt]) {
+        if stmts.is_empty() {
+            out.push_str("{}");
+            return;
+        }
+
+        out.push_str("{\n");
+        let outer = std::mem::take(&mut self.output);
+        self.indent += 1;
+        for stmt in stmts {
+            self.write_statement(stmt);
+        }
+        self.indent -= 1;
+        out.push_str(&std::mem::replace(&mut self.output, outer));
+        out.push_str(&INDENT.repeat(self.indent));
+        out.push('}');
+    }
+
+    fn write_statement(&mut self, stmt: &Stmt) {
+        let text = self.statement(stmt);
+        self.line(&text);
+    }
+
+    fn statement(&mut self, stmt: &Stmt) -> String {
+        match stmt {
+            Stmt::Expression(expr) => {
+                let text = self.expression(expr);
+                if starts_with_brace(expr) {
+                    format!("({});", text)
+                } else {
+                    format!("{};", text)
+                }
+            }
+            Stmt::Let { name, value, .. } => format!("let {} = {};", name, self.expression(value)),
+            Stmt::Function { name, params, body, .. } => {
+                let mut text = format!("fn {}({}) ", name, params.join(", "));
+                self.body(&mut text, body);
+                text
+            }
+            Stmt::Return(Some(value), _) => format!("return {};", self.expression(value)),
+            Stmt::Return(None, _) => "return;".to_string(),
+            Stmt::If { condition, then_branch, else_branch } => {
+                let mut text = format!("if ({}) ", self.expression(condition));
+                self.body(&mut text, then_branch);
+                if let Some(else_branch) = else_branch {
+                    text.push_str(" else ");
+                    self.body(&mut text, else_branch);
+                }
+                text
+            }
+            Stmt::While { condition, body } => {
+                let mut text = format!("while ({}) ", self.expression(condition));
+                self.body(&mut text, body);
+                text
+            }
+            Stmt::For { initializer, condition, increment, body } => {
+                let mut text = String::from("for (");
+                match initializer {
+                    Some(init) => text.push_str(&self.statement(init)),
+                    None => text.push(';'),
+                }
+                if let Some(condition) = condition {
+                    text.push(' ');
+                    text.push_str(&self.expression(condition));
+                }
+                text.push(';');
+                if let Some(increment) = increment {
+                    text.push(' ');
+                    text.push_str(&self.expression(increment));
+                }
+                text.push_str(") ");
+                self.body(&mut text, body);
+                text
+            }
+            Stmt::ForIn { variable, iterable, body } => {
+                let mut text = format!("for ({} in {}) ", variable, self.expression(iterable));
+                self.body(&mut text, body);
+                text
+            }
+            Stmt::Import { path, alias, .. } if module_name(path).as_ref() == Some(alias) => {
+                format!("import {};", quote_string(path))
+            }
+            Stmt::Import { path, alias, .. } => format!("import {} as {};", quote_string(path), alias),
+            Stmt::Break => "break;".to_string(),
+            Stmt::Continue => "continue;".to_string(),
+            Stmt::Block(stmts) => {
+                let mut text = String::new();
+                self.body(&mut text, stmts);
+                text
+            }
+            Stmt::Throw(value, _) => format!("throw {};", self.expression(value)),
+            Stmt::Try { body, variable, handler } => {
+                let mut text = String::from("try ");
+                self.body(&mut text, body);
+                text.push_str(&format!(" catch ({}) ", variable));
+                self.body(&mut text, handler);
+                text
+            }
+        }
+    }
+
+    /// Writes `expr`, parenthesized when it binds looser than `min_precedence`.
+    fn write_operand(&mut self, out: &mut String, expr: &Expr, min_precedence: u8) {
+        if precedence(expr) < min_precedence {
+            out.push('(');
+            self.write_expression(out, expr);
+            out.push(')');
+        } else {
+            self.write_expression(out, expr);
+        }
+    }
+
+    fn write_list(&mut self, out: &mut String, exprs: &[Expr]) {
+        for (i, expr) in exprs.iter().enumerate() {
+            if i > 0 {
+                out.push_str(", ");
+            }
+            self.write_expression(out, expr);
+        }
+    }
+
+    fn write_expression(&mut self, out: &mut String, expr: &Expr) {
+        match expr {
+            // Only a literal too large for an f64 parses to infinity.
+            Expr::Number(n) if n.is_infinite() => out.push_str(&format!("1{}", "0".repeat(309))),
+            Expr::Number(n) => out.push_str(&n.to_string()),
+            Expr::String(s) => out.push_str(&quote_string(s)),
+            Expr::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            Expr::Nil => out.push_str("nil"),
+            Expr::Identifier { name, .. } => out.push_str(name),
+            Expr::Binary { left, op, right, .. } => {
+                let precedence = binary_precedence(op);
+                // Operators are left-associative, so an equal-precedence right operand needs parentheses.
+                self.write_operand(out, left, precedence);
+                out.push_str(&format!(" {} ", binary_symbol(op)));
+                self.write_operand(out, right, precedence + 1);
+            }
+            Expr::Unary { op, expr, .. } => {
+                out.push_str(match op {
+                    UnaryOp::Neg => "-",
+                    UnaryOp::Not => "not ",
+                });
+                self.write_operand(out, expr, PREC_UNARY);
+            }
+            Expr::Call { callee, args, .. } => {
+                self.write_operand(out, callee, PREC_POSTFIX);
+                out.push('(');
+                self.write_list(out, args);
+                out.push(')');
+            }
+            Expr::Index { object, index, .. } => {
+                self.write_operand(out, object, PREC_POSTFIX);
+                out.push('[');
+                self.write_expression(out, index);
+                out.push(']');
+            }
+            Expr::Property { object, name, .. } => {
+                self.write_operand(out, object, PREC_POSTFIX);
+                out.push('.');
+                out.push_str(name);
+            }
+            Expr::Array(elements) => {
+                out.push('[');
+                self.write_list(out, elements);
+                out.push(']');
+            }
+            Expr::Object(fields) if fields.is_empty() => out.push_str("{}"),
+            Expr::Object(fields) => {
+                out.push_str("{ ");
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    if is_identifier(key) {
+                        out.push_str(key);
+                    } else {
+                        out.push_str(&quote_string(key));
+                    }
+                    out.push_str(": ");
+                    self.write_expression(out, value);
+                }
+                out.push_str(" }");
+            }
+            Expr::Assign { name, value, .. } => {
+                out.push_str(name);
+                out.push_str(" = ");
+                self.write_expression(out, value);
+            }
+            Expr::IndexAssign { object, index, value, .. } => {
+                self.write_operand(out, object, PREC_POSTFIX);
+                out.push('[');
+                self.write_expression(out, index);
+                out.push_str("] = ");
+                self.write_expression(out, value);
+            }
+            Expr::PropertyAssign { object, name, value, .. } => {
+                self.write_operand(out, object, PREC_POSTFIX);
+                out.push('.');
+                out.push_str(name);
+                out.push_str(" = ");
+                self.write_expression(out, value);
+            }
+            Expr::Function { params, body } => {
+                out.push_str(&format!("fn ({}) ", params.join(", ")));
+                self.body(out, body);
+            }
+        }
+    }
+}
+
+// ============= REPL =============
+
+const REPL_HELP: &str = "\
+:env            list global bindings
+:ast <code>     show the parsed statements for <code>
+:tokens <code>  show the tokens for <code>
+:fmt <code>     show <code> in canonical formatting
+:help           show this message
+:quit           exit the REPL";
+
+/// Whether `source` still has unclosed brackets or an unterminated string,
+/// meaning the REPL should keep reading lines before parsing.
+fn is_incomplete(source: &str) -> bool {
+    let mut depth = 0i32;
+    let mut in_string = false;
+
+    for ch in source.chars() {
+        match ch {
+            '"' => in_string = !in_string,
+            _ if in_string => {}
+            '{' | '(' | '[' => depth += 1,
+            '}' | ')' | ']' => depth -= 1,
+            _ => {}
+        }
+    }
+    in_string || depth > 0
+}
+
+/// Parses REPL input, letting a trailing expression omit its semicolon.
+fn parse_repl_input(source: &str) -> Result<Vec<Stmt>, Vec<LangError>> {
+    match parse_source(source) {
+        Ok(program) => Ok(program),
+        Err(errors) => {
+            let trimmed = source.trim_end();
+            if trimmed.ends_with(';') || trimmed.ends_with('}') {
+                return Err(errors);
+            }
+            parse_source(&format!("{};", trimmed)).map_err(|_| errors)
+        }
+    }
+}
+
+struct Repl {
+    interpreter: Interpreter,
+    buffer: String,
+}
+
+impl Repl {
+    fn new() -> Self {
+        Repl {
+            interpreter: Interpreter::new(),
+            buffer: String::new(),
+        }
+    }
+
+    fn prompt(&self) -> &'static str {
+        if self.buffer.is_empty() { "> " } else { "... " }
+    }
+
+    /// Feeds one line of input, returning whatever should be printed. Lines
+    /// are buffered until brackets balance.
+    fn handle_line(&mut self, line: &str) -> Option<String> {
+        if self.buffer.is_empty()
+            && let Some(command) = line.trim().strip_prefix(':')
+        {
+            return Some(self.run_command(command));
+        }
+
+        self.buffer.push_str(line);
+        self.buffer.push('\n');
+        if is_incomplete(&self.buffer) {
+            return None;
+        }
+
+        let source = std::mem::take(&mut self.buffer);
+        if source.trim().is_empty() {
+            return None;
+        }
+
+        let program = match parse_repl_input(&source).and_then(resolve_program) {
+            Ok(program) => program,
+            Err(errors) => return Some(render_errors(&errors, &source)),
+        };
+        match self.interpreter.run_resolved(program) {
+            Ok(Some(Value::Nil)) | Ok(None) => None,
+            Ok(Some(Value::String(s))) => Some(format!("{:?}", s)),
+            Ok(Some(value)) => Some(value.to_string()),
+            Err(error) => Some(error.render(&source)),
+        }
+    }
+
+    fn run_command(&mut self, command: &str) -> String {
+        let (name, argument) = command.split_once(' ').unwrap_or((command, ""));
+        let argument = argument.trim();
+
+        match name {
+            "env" => self.interpreter.bindings().iter()
+                .map(|(name, value)| format!("{} = {}", name, value))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            "ast" if !argument.is_empty() => match parse_repl_input(argument) {
+                Ok(program) => program.iter()
+                    .map(|stmt| format!("{:#?}", stmt))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                Err(errors) => render_errors(&errors, argument),
+            },
+            "tokens" if !argument.is_empty() => match Lexer::new(argument.to_string()).tokenize() {
+                Ok(tokens) => tokens.iter()
+                    .map(|token| format!("{}:{} {:?}", token.span.line, token.span.column, token.kind))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                Err(errors) => render_errors(&errors, argument),
+            },
+            "fmt" if !argument.is_empty() => match parse_repl_input(argument) {
+                Ok(program) => format_program(&program).trim_end().to_string(),
+                Err(errors) => render_errors(&errors, argument),
+            },
+            "ast" | "tokens" | "fmt" => format!("usage: :{} <code>", name),
+            "help" => REPL_HELP.to_string(),
+            _ => format!("unknown command ':{}' (try :help)", name),
+        }
+    }
+}
+
+fn run_repl() {
+    let mut repl = Repl::new();
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+
+    loop {
+        print!("{}", repl.prompt());
+        io::stdout().flush().ok();
+
+        let Some(Ok(line)) = lines.next() else {
+            break;
+        };
+        if repl.buffer.is_empty() && matches!(line.trim(), ":quit" | ":q") {
+            break;
+        }
+        if let Some(output) = repl.handle_line(&line)
+            && !output.is_empty()
+        {
+            println!("{}", output);
+        }
+    }
+}
+
+/// Prints a script file in canonical formatting, exiting with 65 if it does
+/// not parse.
+fn format_file(path: &str) -> ExitCode {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("error: could not read {}: {}", path, error);
+            return ExitCode::from(74);
+        }
+    };
+
+    match parse_source(&source) {
+        Ok(program) => {
+            print!("{}", format_program(&program));
+            ExitCode::SUCCESS
+        }
+        Err(errors) => {
+            eprintln!("{}", render_errors(&errors, &source));
+            ExitCode::from(65)
+        }
+    }
+}
+
+/// Runs a script file, exiting with 65 on syntax or resolver errors and 70
+/// on runtime errors (the sysexits `DATAERR` / `SOFTWARE` codes). `use_vm`
+/// selects the bytecode backend instead of the tree-walker and `optimized`
+/// runs the AST optimizer first.
+fn run_file(path: &str, use_vm: bool, optimized: bool, limits: Limits) -> ExitCode {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("error: could not read {}: {}", path, error);
+            return ExitCode::from(74);
+        }
+    };
+
+    let program = match parse_source(&source).and_then(resolve_program) {
+        Ok(program) => program,
+        Err(errors) => {
+            eprintln!("{}", render_errors(&errors, &source));
+            return ExitCode::from(65);
+        }
+    };
+    let program = if optimized { optimize(program) } else { program };
+    let result = if use_vm {
+        let mut vm = Vm::new();
+        vm.set_limits(limits);
+        vm.interpret(&program)
+    } else {
+        let mut interpreter = Interpreter::for_script(Path::new(path));
+        interpreter.set_limits(limits);
+        interpreter.run_resolved(program).map(|_| ())
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("{}", error.render(&source));
+            ExitCode::from(70)
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let mut use_vm = false;
+    let mut optimized = false;
+    let mut formatting = false;
+    let mut limits = Limits::default();
+    let mut path = None;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--vm" => use_vm = true,
+            "-O" => optimized = true,
+            "--fmt" => formatting = true,
+            "--sandbox" => limits = Limits::sandboxed(),
+            _ => path = Some(arg),
+        }
+    }
+
+    on_script_stack(move || match path {
+        Some(path) if formatting => format_file(&path),
+        Some(path) => run_file(&path, use_vm, optimized, limits),
+        None => {
+            run_repl();
+            ExitCode::SUCCESS
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_first_error(source: &str) -> Result<Vec<Stmt>, LangError> {
+        parse_source(source).map_err(|mut errors| errors.remove(0))
+    }
+
+    fn run_tree_walker(source: &str) -> Result<Interpreter, LangError> {
+        let mut interpreter = Interpreter::new();
+        interpreter.interpret(parse_first_error(source)?)?;
+        Ok(interpreter)
+    }
+
+    fn run_vm(source: &str) -> Result<Vm, LangError> {
+        let mut vm = Vm::new();
+        vm.interpret(&parse_first_error(source)?)?;
+        Ok(vm)
+    }
+
+    fn error_message<T>(result: Result<T, LangError>) -> Option<String> {
+        result.err().map(|error| error.message)
+    }
+
+    fn assert_same_globals(source: &str, names: &[&str]) {
+        let interpreter = run_tree_walker(source).unwrap();
+        let vm = run_vm(source).unwrap();
+
+        for name in names {
+            assert_eq!(
+                format!("{:?}", interpreter.global(name)),
+                format!("{:?}", vm.global(name)),
+                "global `{}` differs between backends",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_vm_matches_recursion() {
+        assert_same_globals(
+            "fn fib(n) { if (n < 2) { return n; } return fib(n - 1) + fib(n - 2); }
+             let result = fib(15);",
+            &["result"],
+        );
+    }
+
+    #[test]
+    fn test_vm_matches_loops_and_function_locals() {
+        assert_same_globals(
+            "let i = 0;
+             let total = 0;
+             while (i < 100) { let total = total + i; let i = i + 1; }
+             fn sum_to(n) {
+                 let acc = 0;
+                 let k = 1;
+                 while (k <= n) { let acc = acc + k; let k = k + 1; }
+                 return acc;
+             }
+             let s = sum_to(10);",
+            &["i", "total", "s"],
+        );
+    }
+
+    #[test]
+    fn test_vm_matches_block_scoping() {
+        assert_same_globals(
+            "let x = 1;
+             let seen = nil;
+             { let x = 2; let seen = [x, not x, -x]; }
+             let after = [x, seen];
+             if (x > 0) { let branch = \"then\"; } else { let branch = \"else\"; }",
+            &["x", "seen", "after", "branch"],
+        );
+    }
+
+    #[test]
+    fn test_closures_capture_defining_scope() {
+        assert_same_globals(
+            "fn make_adder(n) { return fn (x) { return x + n; }; }
+             let add5 = make_adder(5);
+             let n = 100;
+             let r = add5(1);
+             fn compose(f, g) { return fn (x) { return f(g(x)); }; }
+             let twice = compose(add5, make_adder(10));
+             let c = twice(0);
+             let apply = fn (f, v) { return f(v); };
+             let d = apply(fn (v) { return v * 2; }, 21);",
+            &["r", "c", "d"],
+        );
+        let interpreter = run_tree_walker("fn make_adder(n) { return fn (x) { return x + n; }; } let n = 100; let r = make_adder(5)(1);").unwrap();
+        assert_eq!(format!("{:?}", interpreter.global("r")), "Some(Number(6.0))");
+    }
+
+    #[test]
+    fn test_nested_functions_recurse_and_capture_blocks() {
+        assert_same_globals(
+            "let fns = nil;
+             {
+                 let base = 10;
+                 fn countdown(n) { if (n < 1) { return base; } return countdown(n - 1); }
+                 let fns = [countdown, fn () { return base + 1; }];
+             }
+             fn outer() {
+                 let k = 3;
+                 fn middle() { return fn () { return k * 2; }; }
+                 return middle()();
+             }
+             let o = outer();",
+            &["o"],
+        );
+
+        let interpreter = run_tree_walker("{ let base = 10; fn countdown(n) { if (n < 1) { return base; } return countdown(n - 1); } let x = countdown(3); let y = [x]; }").unwrap();
+        assert!(interpreter.global("y").is_none());
+    }
+
+    #[test]
+    fn test_functions_do_not_see_caller_locals() {
+        let source = "fn reader() { return secret; }
+                      fn caller() { let secret = 1; return reader(); }
+                      caller();";
+        assert_eq!(error_message(run_tree_walker(source)), Some("Undefined variable: secret".to_string()));
+        assert_eq!(error_message(run_vm(source)), Some("Undefined variable: secret".to_string()));
+    }
+
+    #[test]
+    fn test_assignment_updates_enclosing_scopes() {
+        assert_same_globals(
+            "let i = 0;
+             let total = 0;
+             while (i < 10) { total = total + i; i = i + 1; }
+             fn make_counter() {
+                 let count = 0;
+                 return fn () { count = count + 1; return count; };
+             }
+             let counter = make_counter();
+             counter();
+             counter();
+             let counted = counter();
+             let a = 1;
+             let b = a = 7;
+             { let a = 2; a = 3; }",
+            &["i", "total", "counted", "a", "b"],
+        );
+        let interpreter = run_tree_walker("let i = 0; while (i < 3) { i = i + 1; }").unwrap();
+        assert_eq!(format!("{:?}", interpreter.global("i")), "Some(Number(3.0))");
+    }
+
+    #[test]
+    fn test_index_assignment() {
+        assert_same_globals(
+            "let grid = [[0, 0], [0, 0]];
+             grid[1][0] = 5;
+             let row = grid[0];
+             row[1] = 9;
+             let first = grid[0][1];
+             let letter = \"abc\"[1];",
+            &["grid", "first", "letter"],
+        );
+        assert_eq!(
+            error_message(run_tree_walker("let xs = [1]; xs[3] = 2;")),
+            Some("Index 3 out of bounds for length 1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_for_loops_with_break_and_continue() {
+        assert_same_globals(
+            "let sum = 0;
+             for (x in [1, 2, 3, 4, 5, 6]) {
+                 if (x == 5) { break; }
+                 if (x % 2 == 0) { continue; }
+                 sum = sum + x;
+             }
+             let evens = 0;
+             for (let i = 0; i < 10; i = i + 1) {
+                 if (i % 2 == 1) { continue; }
+                 evens = evens + 1;
+             }
+             let pairs = 0;
+             for (let i = 0; i < 3; i = i + 1) {
+                 for (let j = 0; ; j = j + 1) {
+                     if (j > i) { break; }
+                     pairs = pairs + 1;
+                 }
+             }
+             let letters = 0;
+             for (c in \"hello\") { letters = letters + 1; }",
+            &["sum", "evens", "pairs", "letters"],
+        );
+        let interpreter = run_tree_walker("let sum = 0; for (x in [1, 2, 3]) { sum = sum + x; }").unwrap();
+        assert_eq!(format!("{:?}", interpreter.global("sum")), "Some(Number(6.0))");
+        assert!(interpreter.global("x").is_none());
+    }
+
+    #[test]
+    fn test_for_in_binds_fresh_variable_per_iteration() {
+        assert_same_globals(
+            "let fns = [nil, nil, nil];
+             let n = 0;
+             for (x in [10, 20, 30]) { fns[n] = fn () { return x; }; n = n + 1; }
+             let seen = [fns[0](), fns[1](), fns[2]()];
+             fn find(xs, target) {
+                 for (x in xs) { if (x == target) { return true; } }
+                 return false;
+             }
+             let found = [find([1, 2], 2), find([1, 2], 3)];",
+            &["seen", "found"],
+        );
+        let vm = run_vm("let fns = [nil, nil]; let n = 0; for (x in [10, 20]) { fns[n] = fn () { return x; }; n = n + 1; } let seen = fns[0]() + fns[1]();").unwrap();
+        assert_eq!(format!("{:?}", vm.global("seen")), "Some(Number(30.0))");
+    }
+
+    #[test]
+    fn test_loop_control_outside_loop_is_parse_error() {
+        assert!(parse_source("break;").is_err());
+        assert!(parse_source("while (true) { fn f() { continue; } }").is_err());
+        assert!(parse_source("1 = 2;").is_err());
+        assert_eq!(error_message(run_tree_walker("missing = 1;")), Some("Undefined variable: missing".to_string()));
+        assert_eq!(error_message(run_vm("missing = 1;")), Some("Undefined variable: missing".to_string()));
+    }
+
+    #[test]
+    fn test_objects_and_property_access() {
+        assert_same_globals(
+            "let config = { name: \"svc\", \"port\": 80, tls: { enabled: false } };
+             config.port = config.port + 1;
+             config[\"region\"] = \"eu\";
+             config.tls.enabled = true;
+             let alias = config;
+             alias.name = \"renamed\";
+             let name = config.name;
+             let missing = config.nothing;
+             let count = 0;
+             for (key in config) { count = count + 1; }
+             let nested = config[\"tls\"].enabled;",
+            &["config", "name", "missing", "count", "nested"],
+        );
+
+        let interpreter = run_tree_walker(
+            "let o = { b: [1, \"two\"], a: { inner: nil } }; o.c = true;"
+        ).unwrap();
+        assert_eq!(
+            interpreter.global("o").unwrap().to_string(),
+            "{ a: { inner: nil }, b: [1, \"two\"], c: true }"
+        );
+        assert_eq!(error_message(run_tree_walker("let n = 1; n.x = 2;")), Some("Cannot set property 'x' on a non-object".to_string()));
+    }
+
+    #[test]
+    fn test_equality_semantics() {
+        assert_same_globals(
+            "let same = [{ a: [1, 2] } == { a: [1, 2] }, [1, 2] == [1, 2], \"a\" == \"a\", nil == nil];
+             let different = [{ a: 1 } == { a: 2 }, { a: 1 } == { b: 1 }, [1] == [1, 2], 1 == \"1\", nil != false];
+             fn f() { return 1; }
+             let g = f;
+             let functions = [f == g, f == fn () { return 1; }];",
+            &["same", "different", "functions"],
+        );
+        let interpreter = run_tree_walker("let r = [{ a: 1 } == { a: 1 }, 1 == \"1\", nil != false];").unwrap();
+        assert_eq!(interpreter.global("r").unwrap().to_string(), "[true, false, true]");
+    }
+
+    #[test]
+    fn test_cyclic_values_compare_and_print() {
+        let source = "let o = { a: 1 }; o.self = o;
+             let p = { a: 1 }; p.self = p;
+             let q = { a: 2 }; q.self = q;
+             let xs = [1]; push(xs, xs);
+             let ys = [1]; push(ys, ys);
+             let checks = [o == p, o == q, xs == ys, o != p];
+             let text = str(o);";
+        let interpreter = run_tree_walker(source).unwrap();
+        let vm = run_vm(source).unwrap();
+
+        for global in [interpreter.global("checks"), vm.global("checks")] {
+            assert_eq!(global.unwrap().to_string(), "[true, false, true, false]");
+        }
+        for global in [interpreter.global("text"), vm.global("text")] {
+            assert_eq!(global.unwrap().to_string(), "{ a: 1, self: [cycle] }");
+        }
+        assert_eq!(vm.global("xs").unwrap().to_string(), "[1, [cycle]]");
+        assert_eq!(interpreter.global("o").unwrap().to_string(), "{ a: 1, self: [cycle] }");
+    }
+
+    #[test]
+    fn test_native_prelude() {
+        assert_same_globals(
+            "let xs = [3, 1];
+             let new_len = push(xs, 2);
+             let popped = pop(xs);
+             let sizes = [len(xs), len(\"héllo\"), len({ a: 1, b: 2 })];
+             let names = keys({ b: 1, a: 2 });
+             let text = str([1.5, \"x\", nil]);
+             let parsed = num(\" 42 \") + 1;
+             let types = [type_of(1), type_of(\"s\"), type_of(nil), type_of(len), type_of([]), type_of({}), type_of(fn () {})];
+             let words = split(\"a,b,c\", \",\");
+             let joined = join(words, \"-\");
+             let middle = substring(\"toy language\", 4, 12);
+             let math = [abs(-2), floor(2.7), ceil(2.1), round(2.5), sqrt(16), pow(2, 10), min(3, 4), max(3, 4)];
+             let ticking = clock() > 0;",
+            &["xs", "new_len", "popped", "sizes", "names", "text", "parsed", "types", "words", "joined", "middle", "math", "ticking"],
+        );
+
+        let interpreter = run_tree_walker("let parts = split(\"k=v\", \"=\"); let t = [type_of(print), join(parts, \":\")];").unwrap();
+        assert_eq!(interpreter.global("t").unwrap().to_string(), "[\"function\", \"k:v\"]");
+        assert_eq!(error_message(run_tree_walker("len(1, 2);")), Some("len() expects 1 arguments but got 2".to_string()));
+        assert_eq!(error_message(run_vm("num(\"abc\");")), Some("num() cannot convert \"abc\" to a number".to_string()));
+    }
+
+    #[test]
+    fn test_register_native() {
+        let mut interpreter = Interpreter::new();
+        interpreter.register_native("double", Some(1), |args| match &args[0] {
+            Value::Number(n) => Ok(Value::Number(n * 2.0)),
+            _ => Err("double() expects a number".to_string()),
+        });
+        interpreter.interpret(parse_source("let d = double(21);").unwrap()).unwrap();
+        assert_eq!(interpreter.global("d").unwrap().to_string(), "42");
+
+        let mut vm = Vm::new();
+        vm.register_native("greet", Some(1), |args| Ok(Value::String(format!("hi {}", args[0]))));
+        vm.interpret(&parse_source("let g = greet(\"there\");").unwrap()).unwrap();
+        assert_eq!(vm.global("g").unwrap().to_string(), "hi there");
+    }
+
+    #[test]
+    fn test_vm_matches_errors() {
+        for source in ["let a = missing;", "let b = 1; b();", "fn f(x) { return x; } f(1, 2);", "let c = 1 + \"s\";"] {
+            let tree_error = run_tree_walker(source).err();
+            let vm_error = run_vm(source).err();
+            assert!(tree_error.as_ref().is_some_and(|error| error.span.is_some()));
+            assert_eq!(tree_error, vm_error, "error differs for `{}`", source);
+        }
+    }
+
+    #[test]
+    fn test_runtime_error_spans() {
+        let source = "let x = 1;\nlet y = x + \"s\";";
+        let error = run_tree_walker(source).err().unwrap();
+        assert_eq!(error.kind, ErrorKind::TypeError);
+        let span = error.span.unwrap();
+        assert_eq!((span.line, span.column), (2, 9));
+        assert_eq!(&source[span.start..span.end], "x + \"s\"");
+
+        let error = run_vm("fn f() {\n  return g();\n}\nf();").err().unwrap();
+        assert_eq!(error.kind, ErrorKind::UndefinedVariable);
+        assert_eq!(error.span.map(|span| (span.line, span.column)), Some((2, 10)));
+    }
+
+    #[test]
+    fn test_render_points_at_source() {
+        let source = "let a = 1;\nlet b = a();";
+        let error = run_vm(source).err().unwrap();
+        assert_eq!(
+            error.render(source),
+            "error[NotCallable]: Not a function\n --> line 2, column 9\n  |\n2 | let b = a();\n  |         ^^^"
+        );
+    }
+
+    #[test]
+    fn test_parser_recovers_and_reports_every_error() {
+        let errors = parse_source("let = 1;\nlet ok = 2;\nprint(1 +);\nif (true) { let = 3; }").unwrap_err();
+        assert!(errors.iter().all(|error| error.kind == ErrorKind::UnexpectedToken));
+        let lines: Vec<_> = errors.iter().map(|error| error.span.unwrap().line).collect();
+        assert_eq!(lines, vec![1, 3, 4]);
+
+        let source = "let a = 1 $ 2;\nlet b = #;";
+        let errors = parse_source(source).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().all(|error| error.kind == ErrorKind::UnexpectedCharacter));
+        let report = render_errors(&errors, source);
+        assert!(report.contains("1 | let a = 1 $ 2;\n  |           ^"));
+        assert!(report.contains("2 | let b = #;\n  |         ^"));
+
+        let errors = parse_source("let s = \"open;").unwrap_err();
+        assert_eq!(errors[0].kind, ErrorKind::UnterminatedString);
+    }
+
+    #[test]
+    fn test_repl_keeps_state_and_buffers_multiline_input() {
+        let mut repl = Repl::new();
+        assert_eq!(repl.handle_line("let x = 40;"), None);
+        assert_eq!(repl.handle_line("fn add(a, b) {"), None);
+        assert_eq!(repl.prompt(), "... ");
+        assert_eq!(repl.handle_line("  return a + b;"), None);
+        assert_eq!(repl.handle_line("}"), None);
+        assert_eq!(repl.prompt(), "> ");
+        assert_eq!(repl.handle_line("add(x, 2)"), Some("42".to_string()));
+        assert_eq!(repl.handle_line("str(x);"), Some("\"40\"".to_string()));
+        assert_eq!(repl.handle_line(":env"), Some("add = <fn(a, b)>\nx = 40".to_string()));
+        assert!(repl.handle_line("missing").unwrap().starts_with("error[UndefinedVariable]"));
+        assert!(repl.handle_line(":tokens let").unwrap().starts_with("1:1 Let"));
+    }
+
+    #[test]
+    fn test_is_incomplete() {
+        assert!(is_incomplete("if (x) {"));
+        assert!(is_incomplete("let s = \"{"));
+        assert!(!is_incomplete("let s = \"{\";"));
+        assert!(!is_incomplete("fn f() { return [1, 2]; }"));
+    }
+
+    fn resolve_errors(source: &str) -> Vec<(ErrorKind, usize)> {
+        match resolve_program(parse_source(source).unwrap()) {
+            Ok(_) => Vec::new(),
+            Err(errors) => errors.iter().map(|error| (error.kind, error.span.unwrap().line)).collect(),
+        }
+    }
+
+    #[test]
+    fn test_resolver_reports_static_errors() {
+        assert_eq!(
+            resolve_errors("fn f() {\n  print(a);\n  let a = 1;\n  let a = 2;\n}"),
+            vec![(ErrorKind::DuplicateDeclaration, 4), (ErrorKind::UseBeforeDefine, 2)]
+        );
+        assert_eq!(resolve_errors("{ let b = b; }"), vec![(ErrorKind::UseBeforeDefine, 1)]);
+        assert_eq!(resolve_errors("fn g(x) { let x = 1; }"), vec![(ErrorKind::DuplicateDeclaration, 1)]);
+        assert_eq!(resolve_errors("let r = 1;\nreturn r;"), vec![(ErrorKind::ReturnOutsideFunction, 2)]);
+        assert_eq!(
+            resolve_errors("fn h() {\n  return 1;\n  print(2);\n}"),
+            vec![(ErrorKind::UnreachableCode, 2)]
+        );
+        assert_eq!(
+            error_message(run_vm("fn h() { return 1; print(2); }")),
+            Some("Unreachable code after return".to_string())
+        );
+    }
+
+    #[test]
+    fn test_resolver_allows_late_bound_names() {
+        // Globals may be redefined, and nested functions may capture locals declared after them.
+        assert!(resolve_errors("let a = 1; let a = a + 1; print(missing);").is_empty());
+        assert_same_globals(
+            "fn outer() {
+                 fn is_even(n) { if (n == 0) { return true; } return is_odd(n - 1); }
+                 fn is_odd(n) { if (n == 0) { return false; } return is_even(n - 1); }
+                 return is_even(10);
+             }
+             let even = outer();",
+            &["even"],
+        );
+    }
+
+    #[test]
+    fn test_resolver_records_scope_depths() {
+        let program = resolve_program(parse_source("fn f(a) { { let b = a; return fn () { return b; }; } }").unwrap()).unwrap();
+        let Stmt::Function { body, .. } = &program[0] else { panic!("expected function") };
+        let Stmt::Block(block) = &body[0] else { panic!("expected block") };
+        let Stmt::Let { value: Expr::Identifier { depth, .. }, .. } = &block[0] else { panic!("expected let") };
+        assert_eq!(*depth, Some(1));
+        let Stmt::Return(Some(Expr::Function { body: inner, .. }), _) = &block[1] else { panic!("expected return") };
+        let Stmt::Return(Some(Expr::Identifier { depth, .. }), _) = &inner[0] else { panic!("expected return") };
+        assert_eq!(*depth, Some(1));
+    }
+
+    fn optimized_source(source: &str) -> Vec<Stmt> {
+        optimize(resolve_program(parse_source(source).unwrap()).unwrap())
+    }
+
+    fn assert_optimization_preserves(source: &str, names: &[&str]) {
+        let mut plain = Interpreter::new();
+        plain.interpret(parse_source(source).unwrap()).unwrap();
+        let mut optimized = Interpreter::new();
+        optimized.run_resolved(optimized_source(source)).unwrap();
+        let mut vm = Vm::new();
+        vm.interpret(&optimized_source(source)).unwrap();
+
+        for name in names {
+            let expected = format!("{:?}", plain.global(name));
+            assert_eq!(expected, format!("{:?}", optimized.global(name)), "global `{}` changed", name);
+            assert_eq!(expected, format!("{:?}", vm.global(name)), "global `{}` changed on the VM", name);
+        }
+    }
+
+    #[test]
+    fn test_optimizer_folds_constants() {
+        let program = optimized_source("let a = 2 * (3 + 4) - -1; let b = not (1 < 2); let c = a + 1; let d = \"s\" == \"s\";");
+        let values: Vec<String> = program.iter()
+            .map(|stmt| match stmt {
+                Stmt::Let { value, .. } => format!("{:?}", value),
+                other => panic!("unexpected statement {:?}", other),
+            })
+            .collect();
+        assert_eq!(values, vec!["Number(15.0)", "Bool(false)", "Number(16.0)", "Bool(true)"]);
+
+        // Failing operations stay in place so the runtime still reports them.
+        let program = optimized_source("let e = 1 + \"x\";");
+        assert!(matches!(&program[0], Stmt::Let { value: Expr::Binary { .. }, .. }));
+    }
+
+    #[test]
+    fn test_optimizer_removes_dead_branches() {
+        let program = optimized_source("let debug = false; if (debug) { print(1); } while (debug) { print(2); } if (not debug) { print(3); } else { print(4); }");
+        assert_eq!(program.len(), 2);
+        assert!(matches!(&program[1], Stmt::Expression(Expr::Call { args, .. }) if matches!(args[0], Expr::Number(n) if n == 3.0)));
+    }
+
+    #[test]
+    fn test_optimizer_preserves_behaviour() {
+        assert_optimization_preserves(
+            "let limit = 3 * 2;
+             let total = 0;
+             for (let i = 0; i < limit; i = i + 1) { total = total + i; }
+             let name = \"x\";
+             let renamed = nil;
+             { let name = \"y\"; renamed = name; }
+             fn scale(n) { let factor = 10; if (true) { return n * factor; } }
+             let scaled = scale(limit);
+             let counter = 0;
+             fn bump() { counter = counter + 1; return counter; }
+             bump();
+             let shadow = 1;
+             let later = nil;
+             { fn read() { return shadow; } let shadow = 2; later = read(); }
+             let fold = [1 + 1, \"a\" == \"b\", -(2 * 3)];",
+            &["limit", "total", "renamed", "scaled", "counter", "later", "fold"],
+        );
+    }
+
+    /// Debug form of a program with spans blanked out, so trees parsed from
+    /// differently laid out source compare equal.
+    fn shape(program: &[Stmt]) -> String {
+        let debug = format!("{:?}", program);
+        let mut shape = String::new();
+        let mut rest = debug.as_str();
+        while let Some(start) = rest.find("Span {") {
+            shape.push_str(&rest[..start]);
+            shape.push_str("Span");
+            let end = rest[start..].find('}').unwrap();
+            rest = &rest[start + end + 1..];
+        }
+        shape.push_str(rest);
+        shape
+    }
+
+    fn assert_round_trips(source: &str) {
+        let program = parse_source(source).unwrap();
+        let formatted = format_program(&program);
+        let reparsed = parse_source(&formatted).unwrap_or_else(|errors| {
+            panic!("formatted source does not parse: {:?}\n{}", errors, formatted)
+        });
+        assert_eq!(shape(&program), shape(&reparsed), "round trip changed the tree:\n{}", formatted);
+        assert_eq!(format_program(&reparsed), formatted);
+    }
+
+    #[test]
+    fn test_formatter_output() {
+        let source = "fn f(a,b){if(a<b){return (a+b)*2;}else{return a-(b-1);}}
+                      let o={name:\"x\",\"two words\":[1,2.5],nested:{}};
+                      for(let i=0;i<3;i=i+1){o.n=-(-i);}
+                      for(;;){break;}
+                      ({a:1}).a;
+                      let s=\"quote\\\" and \\n\";";
+        assert_eq!(
+            format_program(&parse_source(source).unwrap()),
+            "fn f(a, b) {
+    if (a < b) {
+        return (a + b) * 2;
+    } else {
+        return a - (b - 1);
+    }
+}
+let o = { name: \"x\", \"two words\": [1, 2.5], nested: {} };
+for (let i = 0; i < 3; i = i + 1) {
+    o.n = --i;
+}
+for (;;) {
+    break;
+}
+({ a: 1 }.a);
+let s = \"quote\\\" and \\n\";
+"
+        );
+    }
+
+    #[test]
+    fn test_formatter_round_trips_programs() {
+        assert_round_trips(
+            "import \"lib/a.toy\"; import \"x-y.toy\" as xy; import \"b.toy\" as c;
+             fn make(n) { let f = fn (x) { return fn () { return x + n; }; }; return f; }
+             let xs = [1, [2, 3], { k: fn () {} }];
+             xs[1][0] = xs[1][1] = (1 + 2) * 3 - 4 / (5 % 6);
+             while (not (1 < 2 and 3 >= 4) or -xs[0] != 1) { continue; }
+             for (x in \"abc\") { if (x == \"b\") { break; } }
+             { let y = nil; y = true; }
+             fn () { return 1; }();
+             (fn (a) { return a; })(2);
+             ({ k: 1 }.k + 1).toString;",
+        );
+    }
+
+    /// Small deterministic generator so the property test needs no dependencies.
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self, bound: u64) -> u64 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (self.0 >> 33) % bound
+        }
+    }
+
+    fn random_expr(rng: &mut Lcg, depth: u32) -> Expr {
+        let span = Span::default();
+        let leaf = depth == 0 || rng.next(4) == 0;
+        if leaf {
+            return match rng.next(5) {
+                0 => Expr::Number(rng.next(100) as f64 / 4.0),
+                1 => Expr::String(["a", "b\"c", "line\n"][rng.next(3) as usize].to_string()),
+                2 => Expr::Bool(rng.next(2) == 0),
+                3 => Expr::Nil,
+                _ => Expr::Identifier { name: ["x", "y", "z"][rng.next(3) as usize].to_string(), depth: None, span },
+            };
+        }
+
+        let ops = [
+            BinaryOp::Add, BinaryOp::Sub, BinaryOp::Mul, BinaryOp::Div, BinaryOp::Mod, BinaryOp::Equal,
+            BinaryOp::NotEqual, BinaryOp::Less, BinaryOp::LessEqual, BinaryOp::Greater,
+            BinaryOp::GreaterEqual, BinaryOp::And, BinaryOp::Or,
+        ];
+        let op = ops[rng.next(ops.len() as u64) as usize].clone();
+        let unary = if rng.next(2) == 0 { UnaryOp::Neg } else { UnaryOp::Not };
+        let sub = |rng: &mut Lcg| Box::new(random_expr(rng, depth - 1));
+        match rng.next(10) {
+            0..=3 => Expr::Binary { left: sub(rng), op, right: sub(rng), span },
+            4 => Expr::Unary { op: unary, expr: sub(rng), span },
+            5 => Expr::Call { callee: sub(rng), args: vec![*sub(rng), *sub(rng)], span },
+            6 => Expr::Index { object: sub(rng), index: sub(rng), span },
+            7 => Expr::Property { object: sub(rng), name: "p".to_string(), span },
+            8 => Expr::Assign { name: "x".to_string(), value: sub(rng), depth: None, span },
+            _ => Expr::Object(vec![("k".to_string(), *sub(rng))]),
+        }
+    }
+
+    #[test]
+    fn test_formatter_round_trips_random_expressions() {
+        let mut rng = Lcg(0x5eed);
+        for _ in 0..500 {
+            let program = vec![
+                Stmt::Expression(random_expr(&mut rng, 4)),
+                Stmt::Let { name: "v".to_string(), value: random_expr(&mut rng, 4), span: Span::default() },
+            ];
+            let formatted = format_program(&program);
+            let reparsed = parse_source(&formatted).unwrap_or_else(|errors| {
+                panic!("formatted source does not parse: {:?}\n{}", errors, formatted)
+            });
+            assert_eq!(shape(&program), shape(&reparsed), "round trip changed the tree:\n{}", formatted);
+        }
+    }
+
+    /// Writes `files` into a fresh directory under the system temp dir.
+    fn module_dir(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("toy-modules-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (name, source) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+        dir
+    }
+
+    fn run_script(dir: &Path, main: &str) -> Result<Interpreter, LangError> {
+        let path = dir.join(main);
+        let source = fs::read_to_string(&path).unwrap();
+        let mut interpreter = Interpreter::for_script(&path);
+        interpreter.interpret(parse_first_error(&source)?)?;
+        Ok(interpreter)
+    }
+
+    #[test]
+    fn test_import_exposes_module_namespace() {
+        let dir = module_dir("namespace", &[
+            ("main.toy", "import \"lib/math.toy\"; import \"lib/math.toy\" as again;
+                          let sq = math.square(4); let base = math.base; let same = math == again;
+                          let base_here = nil;"),
+            ("lib/math.toy", "import \"consts.toy\";
+                              let base = consts.ten;
+                              fn helper(x) { return x * x; }
+                              fn square(x) { return helper(x) + base - base; }
+                              if (true) { let hidden = 1; }"),
+            ("lib/consts.toy", "let ten = 10;"),
+        ]);
+
+        let interpreter = run_script(&dir, "main.toy").unwrap();
+        assert_eq!(interpreter.global("sq").unwrap().to_string(), "16");
+        assert_eq!(interpreter.global("base").unwrap().to_string(), "10");
+        assert_eq!(interpreter.global("same").unwrap().to_string(), "true");
+        assert_eq!(interpreter.global("math").unwrap().to_string(), "{ base: 10, helper: <fn(x)>, square: <fn(x)> }");
+
+        // Both imports share the cached module object.
+        let (Some(Value::Object(first)), Some(Value::Object(second))) = (interpreter.global("math"), interpreter.global("again")) else {
+            panic!("expected module objects");
+        };
+        assert!(Rc::ptr_eq(&first, &second));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_import_errors() {
+        let dir = module_dir("errors", &[
+            ("a.toy", "import \"b.toy\";"),
+            ("b.toy", "import \"c.toy\";"),
+            ("c.toy", "import \"a.toy\";"),
+            ("broken.toy", "import \"bad.toy\";"),
+            ("bad.toy", "let x = ;"),
+            ("missing.toy", "import \"nowhere.toy\";"),
+        ]);
+
+        let error = run_script(&dir, "a.toy").err().unwrap();
+        assert_eq!(error.kind, ErrorKind::ImportCycle);
+        assert_eq!(error.message, "Import cycle: a.toy -> b.toy -> c.toy -> a.toy");
+
+        let error = run_script(&dir, "broken.toy").err().unwrap();
+        assert_eq!(error.kind, ErrorKind::ImportError);
+        assert!(error.message.starts_with("In module \"bad.toy\": Unexpected token"));
+        assert_eq!(error.span.map(|span| span.column), Some(1));
+
+        let error = run_script(&dir, "missing.toy").err().unwrap();
+        assert!(error.message.starts_with("Cannot import \"nowhere.toy\""));
+
+        assert!(parse_source("import \"my-lib.toy\";").is_err());
+        assert_eq!(error_message(run_vm("import \"x.toy\";")), Some("import is only supported by the tree-walking interpreter".to_string()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn limit_errors(source: &str, limits: Limits) -> (LangError, LangError) {
+        let program = parse_first_error(source).unwrap();
+
+        let mut interpreter = Interpreter::new();
+        interpreter.set_limits(limits);
+        let tree_error = interpreter.interpret(program.clone()).expect_err("tree-walker should hit a limit");
+
+        let mut vm = Vm::new();
+        vm.set_limits(limits);
+        let vm_error = vm.interpret(&program).expect_err("VM should hit a limit");
+        (tree_error, vm_error)
+    }
+
+    #[test]
+    fn test_step_budget_and_timeout() {
+        let steps = Limits { max_steps: Some(10_000), ..Limits::default() };
+        for error in <[LangError; 2]>::from(limit_errors("while (true) {}", steps)) {
+            assert_eq!(error.kind, ErrorKind::LimitExceeded);
+            assert_eq!(error.message, "Step budget of 10000 exceeded");
+        }
+
+        let timeout = Limits { timeout: Some(Duration::from_millis(20)), ..Limits::default() };
+        for error in <[LangError; 2]>::from(limit_errors("let i = 0; while (true) { i = i + 1; }", timeout)) {
+            assert_eq!(error.kind, ErrorKind::LimitExceeded);
+            assert_eq!(error.message, "Timed out after 20 ms");
+        }
+
+        // The budget is per run, so a REPL session is not starved by earlier input.
+        let mut interpreter = Interpreter::new();
+        interpreter.set_limits(Limits { max_steps: Some(50), ..Limits::default() });
+        for _ in 0..5 {
+            interpreter.interpret(parse_first_error("let x = 1 + 2;").unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_call_depth_limit() {
+        let source = "fn down(n) { return down(n + 1); } down(0);";
+        let shallow = Limits { max_call_depth: Some(30), ..Limits::default() };
+        for error in <[LangError; 2]>::from(limit_errors(source, shallow)) {
+            assert_eq!(error.kind, ErrorKind::LimitExceeded);
+            assert_eq!(error.message, "Maximum call depth of 30 exceeded");
+        }
+
+        // The tree-walker keeps its stack cap even when asked for no limit.
+        let unlimited = Limits { max_call_depth: None, ..Limits::default() };
+        assert_eq!(unlimited.for_tree_walker().max_call_depth, Some(TREE_WALKER_MAX_CALL_DEPTH));
+
+        // Recursion inside the limit still works and unwinds the depth counter.
+        assert_same_globals(
+            "fn sum(n) { if (n == 0) { return 0; } return n + sum(n - 1); } let a = sum(40); let b = sum(40);",
+            &["a", "b"],
+        );
+    }
+
+    #[test]
+    fn test_default_limits_allow_deep_recursion() {
+        let source = "fn f(n) { if (n == 0) { return 0; } return 1 + f(n - 1); } let depth = f(1000);";
+        let (tree, vm) = on_script_stack(move || {
+            let tree = run_tree_walker(source).unwrap().global("depth").unwrap().to_string();
+            let vm = run_vm(source).unwrap().global("depth").unwrap().to_string();
+            (tree, vm)
+        });
+        assert_eq!(tree, "1000");
+        assert_eq!(vm, "1000");
+
+        // VM frames are on the heap, so it goes far deeper than the tree-walker.
+        let deep = "fn f(n) { if (n == 0) { return 0; } return 1 + f(n - 1); } let depth = f(100000);";
+        assert_eq!(run_vm(deep).unwrap().global("depth").unwrap().to_string(), "100000");
+    }
+
+    #[test]
+    fn test_collection_size_limit() {
+        let limits = Limits { max_collection_len: Some(100), ..Limits::default() };
+        let source = "let items = []; while (true) { push(items, 0); }";
+        for error in <[LangError; 2]>::from(limit_errors(source, limits)) {
+            assert_eq!(error.kind, ErrorKind::LimitExceeded);
+            assert_eq!(error.message, "Array of length 101 exceeds the limit of 100");
+        }
+
+        let limits = Limits { max_collection_len: Some(2), ..Limits::default() };
+        let (tree_error, vm_error) = limit_errors("let xs = [1, 2, 3];", limits);
+        assert_eq!(tree_error.message, "Array of length 3 exceeds the limit of 2");
+        assert_eq!(vm_error.message, tree_error.message);
+    }
+
+    #[test]
+    fn test_try_catch_handles_runtime_errors() {
+        let source = r#"
+            fn divide(a, b) { return a / b; }
+            fn wrap(x) { return len(x); }
+            let message = nil;
+            let kind = nil;
+            let stack = nil;
+            try {
+                divide(1, 0);
+                message = "unreachable";
+            } catch (e) {
+                message = e.message;
+                kind = e.kind;
+                stack = join(e.stack, ",");
+            }
+            let index = nil;
+            try { let xs = [1]; xs[5]; } catch (e) { index = e.kind; }
+            let native = nil;
+            try { wrap(1); } catch (e) { native = join(e.stack, ","); }
+            let typed = nil;
+            try { -"a"; } catch (e) { typed = [e.kind, type_of(e), str(e)]; }
+        "#;
+        assert_same_globals(source, &["message", "kind", "stack", "index", "native", "typed"]);
+
+        let interpreter = run_tree_walker(source).unwrap();
+        let global = |name| interpreter.global(name).unwrap().to_string();
+        assert_eq!(global("message"), "Division by zero");
+        assert_eq!(global("kind"), "DivisionByZero");
+        assert_eq!(global("stack"), "divide");
+        assert_eq!(global("index"), "IndexOutOfBounds");
+        assert_eq!(global("native"), "len,wrap");
+        assert_eq!(global("typed"), "[\"TypeError\", \"error\", \"<error: Type error in unary operation>\"]");
+    }
+
+    #[test]
+    fn test_throw_and_rethrow() {
+        let source = r#"
+            fn check(n) {
+                if (n < 0) { throw "negative"; }
+                return n;
+            }
+            fn outer(n) { return check(n); }
+            let log = [];
+            for (let i = 1; i > -2; i = i - 1) {
+                try { push(log, outer(i)); } catch (e) { push(log, e.message); push(log, join(e.stack, "<")); }
+            }
+            let rethrown = nil;
+            try {
+                try { outer(-1); } catch (e) { throw e; }
+            } catch (e) {
+                rethrown = [e.kind, e.message, len(e.stack)];
+            }
+            let thrown_value = nil;
+            try { throw [1, 2]; } catch (e) { thrown_value = e.message; }
+        "#;
+        assert_same_globals(source, &["log", "rethrown", "thrown_value"]);
+        let interpreter = run_tree_walker(source).unwrap();
+        assert_eq!(interpreter.global("log").unwrap().to_string(), "[1, 0, \"negative\", \"check<outer\"]");
+        assert_eq!(interpreter.global("rethrown").unwrap().to_string(), "[\"Thrown\", \"negative\", 2]");
+        assert_eq!(interpreter.global("thrown_value").unwrap().to_string(), "[1, 2]");
+
+        for result in [error_message(run_tree_walker("throw \"boom\";")), error_message(run_vm("throw \"boom\";"))] {
+            assert_eq!(result, Some("boom".to_string()));
+        }
+    }
+
+    #[test]
+    fn test_try_interacts_with_control_flow() {
+        let source = r#"
+            let total = 0;
+            for (x in [1, 2, 3, 4]) {
+                try {
+                    if (x == 2) { continue; }
+                    if (x == 4) { break; }
+                    total = total + x;
+                } catch (e) {}
+            }
+            fn early() {
+                try { return 1; } catch (e) { return 2; }
+            }
+            let after = nil;
+            try { early(); throw "after return"; } catch (e) { after = e.message; }
+        "#;
+        assert_same_globals(source, &["total", "after"]);
+
+        // A handler left by `break` must not catch errors raised after the loop.
+        let source = "for (x in [1]) { try { break; } catch (e) { print(\"caught\"); } } throw \"late\";";
+        assert_eq!(error_message(run_vm(source)), Some("late".to_string()));
+        assert_eq!(error_message(run_tree_walker(source)), Some("late".to_string()));
+
+        // Exceeded limits are not catchable.
+        let limits = Limits { max_steps: Some(1_000), ..Limits::default() };
+        for error in <[LangError; 2]>::from(limit_errors("try { while (true) {} } catch (e) {}", limits)) {
+            assert_eq!(error.kind, ErrorKind::LimitExceeded);
+        }
+    }
+
+    #[test]
+    fn test_try_catch_static_checks_and_formatting() {
+        assert_eq!(
+            resolve_errors("fn f() {\n  throw 1;\n  print(2);\n}\ntry {} catch (e) {\n  let e = 1;\n}"),
+            vec![(ErrorKind::UnreachableCode, 2), (ErrorKind::DuplicateDeclaration, 6)]
+        );
+        assert!(parse_source("try { } catch { }").is_err());
+
+        let source = "try {\n    risky();\n} catch (error) {\n    throw error;\n}\n";
+        assert_eq!(format_program(&parse_source(source).unwrap()), source);
+        assert_round_trips("fn f() { try { return 1 / 0; } catch (e) { print(e.message); } }");
+    }
+}